@@ -0,0 +1,270 @@
+use crate::ExpansionAudio;
+
+// ディスクシステムの拡張音源
+// https://www.nesdev.org/wiki/FDS_audio
+// 64段の波形メモリと、周波数を揺らすモジュレータ
+
+// 最大音量(波形63 * ゲイン32)の時の出力。2A03の矩形波の最大音量の約2.4倍
+const FDS_VOLUME : f32 = 0.36;
+
+// $4089 の下位2bitによるマスター音量 (2/2, 2/3, 2/4, 2/5)
+static MASTER_VOLUME_TABLE : [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+// モジュレーションテーブルの値ごとのカウンタの増分。4はカウンタを0に戻す
+static MOD_TABLE : [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// $4080, $4084 のエンベロープ
+#[derive(Debug)]
+struct FdsEnvelope {
+    is_disable : bool,
+    is_increase : bool,
+    speed : u8,
+    gain : u8,
+    divider : u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> Self {
+        Self {
+            is_disable: true,
+            is_increase: false,
+            speed: 0,
+            gain: 0,
+            divider: 0,
+        }
+    }
+
+    // MDSS SSSS  エンベロープ無効 (M)、増加 (D)、速度/ゲイン (S)
+    fn write(&mut self, v : u8) {
+        self.is_disable = v & 0x80 != 0;
+        self.is_increase = v & 0x40 != 0;
+        self.speed = v & 0x3f;
+        if self.is_disable {
+            self.gain = self.speed;
+        }
+        self.divider = 0;
+    }
+
+    fn step(&mut self, master_speed : u8) {
+        if self.is_disable {
+            return;
+        }
+        // 8 * (master_speed + 1) * (speed + 1) サイクルごとに1段進む
+        self.divider += 1;
+        if self.divider < 8 * (master_speed as u32 + 1) * (self.speed as u32 + 1) {
+            return;
+        }
+        self.divider = 0;
+        if self.is_increase {
+            if self.gain < 32 {
+                self.gain += 1;
+            }
+        } else if self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FdsAudio {
+    wave_table : [u8; 64],
+    is_wave_write : bool,
+    master_volume : u8,
+
+    volume_envelope : FdsEnvelope,
+    wave_frequency : u16,
+    is_wave_halt : bool,
+    is_envelope_halt : bool,
+    wave_accumulator : u32,
+    wave_position : u8,
+
+    mod_envelope : FdsEnvelope,
+    mod_frequency : u16,
+    is_mod_halt : bool,
+    mod_table : [u8; 64],
+    mod_accumulator : u32,
+    mod_position : u8,
+    // 7bitの符号付き
+    mod_counter : i8,
+
+    envelope_speed : u8,
+    output : f32,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        Self {
+            wave_table: [0; 64],
+            is_wave_write: false,
+            master_volume: 0,
+            volume_envelope: FdsEnvelope::new(),
+            wave_frequency: 0,
+            is_wave_halt: true,
+            is_envelope_halt: false,
+            wave_accumulator: 0,
+            wave_position: 0,
+            mod_envelope: FdsEnvelope::new(),
+            mod_frequency: 0,
+            is_mod_halt: true,
+            mod_table: [0; 64],
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+            envelope_speed: 0xe8,
+            output: 0.0,
+        }
+    }
+
+    // $4040-$4092
+    pub fn read(&self, addr : u16) -> Option<u8> {
+        match addr {
+            // 上位2bitはオープンバス
+            0x4040 ..= 0x407f => Some(self.wave_table[(addr & 0x3f) as usize] | 0x40),
+            0x4090 => Some(self.volume_envelope.gain | 0x40),
+            0x4092 => Some(self.mod_envelope.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    // $4040-$408A
+    pub fn write(&mut self, addr : u16, v : u8) {
+        match addr {
+            0x4040 ..= 0x407f if self.is_wave_write => {
+                self.wave_table[(addr & 0x3f) as usize] = v & 0x3f;
+            }
+            0x4080 => self.volume_envelope.write(v),
+            0x4082 => self.wave_frequency = self.wave_frequency & 0x0f00 | v as u16,
+            0x4083 => {
+                // HE-- FFFF  波形停止 (H)、エンベロープ停止 (E)、周波数上位 (F)
+                self.wave_frequency = self.wave_frequency & 0x00ff | ((v & 0x0f) as u16) << 8;
+                self.is_wave_halt = v & 0x80 != 0;
+                self.is_envelope_halt = v & 0x40 != 0;
+                if self.is_wave_halt {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(v),
+            0x4085 => self.mod_counter = ((v << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = self.mod_frequency & 0x0f00 | v as u16,
+            0x4087 => {
+                // H--- FFFF  モジュレータ停止 (H)、周波数上位 (F)
+                self.mod_frequency = self.mod_frequency & 0x00ff | ((v & 0x0f) as u16) << 8;
+                self.is_mod_halt = v & 0x80 != 0;
+                if self.is_mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // 停止中のみ書き込める。テーブルは実際には32段で、1回の書き込みで2段分埋まる
+            // 奇数の位置で止まっている場合も偶数の位置から書き込む
+            0x4088 if self.is_mod_halt => {
+                let p = (self.mod_position & 0x3e) as usize;
+                self.mod_table[p] = v & 0x07;
+                self.mod_table[p + 1] = v & 0x07;
+                self.mod_position = (p as u8 + 2) & 0x3f;
+            }
+            0x4089 => {
+                // W--- --VV  波形メモリ書き込み有効 (W)、マスター音量 (V)
+                self.is_wave_write = v & 0x80 != 0;
+                self.master_volume = v & 0x03;
+            }
+            0x408a => self.envelope_speed = v,
+            _ => {}
+        }
+    }
+
+    // https://www.nesdev.org/wiki/FDS_audio#Frequency_calculation
+    fn modulated_frequency(&self) -> u32 {
+        let pitch = self.wave_frequency as i32;
+        if self.is_mod_halt || self.mod_frequency == 0 {
+            return pitch as u32;
+        }
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= pitch;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (pitch + temp).max(0) as u32
+    }
+
+    fn step_mod(&mut self) {
+        if self.is_mod_halt || self.mod_frequency == 0 {
+            return;
+        }
+        self.mod_accumulator += self.mod_frequency as u32;
+        if self.mod_accumulator < 0x10000 {
+            return;
+        }
+        self.mod_accumulator &= 0xffff;
+        let m = self.mod_table[self.mod_position as usize];
+        self.mod_counter = if m == 4 {
+            0
+        } else {
+            // 7bitで折り返す
+            (((self.mod_counter as i16 + MOD_TABLE[m as usize] as i16) << 9) >> 9) as i8
+        };
+        self.mod_position = (self.mod_position + 1) & 0x3f;
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn step_cycle(&mut self) {
+        if !self.is_wave_halt && !self.is_envelope_halt && self.envelope_speed != 0 {
+            self.volume_envelope.step(self.envelope_speed);
+            self.mod_envelope.step(self.envelope_speed);
+        }
+
+        self.step_mod();
+
+        // 波形メモリの書き込み中は最後の出力を保つ
+        if self.is_wave_halt || self.is_wave_write {
+            return;
+        }
+        self.wave_accumulator += self.modulated_frequency();
+        if self.wave_accumulator >= 0x10000 {
+            self.wave_accumulator &= 0xffff;
+            self.wave_position = (self.wave_position + 1) & 0x3f;
+        }
+        let gain = self.volume_envelope.gain.min(32) as f32;
+        let wave = self.wave_table[self.wave_position as usize] as f32;
+        self.output = wave * gain / (63.0 * 32.0) * MASTER_VOLUME_TABLE[self.master_volume as usize] * FDS_VOLUME;
+    }
+
+    fn value(&self) -> f32 {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 奇数の位置で止めてもモジュレータのテーブルに書き込める() {
+        let mut a = FdsAudio::new();
+        a.write(0x4087, 0x80);
+        a.mod_position = 63;
+        a.write(0x4088, 0x03);
+        assert_eq!(&a.mod_table[62..64], &[0x03, 0x03]);
+        assert_eq!(a.mod_position, 0);
+    }
+}
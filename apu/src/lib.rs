@@ -1,5 +1,22 @@
 use once_cell::sync::Lazy;
 
+pub mod fds;
+pub mod mmc5;
+pub mod namco163;
+pub mod sunsoft5b;
+pub mod vrc6;
+pub mod vrc7;
+
+// カートリッジ側の拡張音源
+// https://www.nesdev.org/wiki/Expansion_audio
+pub trait ExpansionAudio {
+    // CPUの1クロックごとに呼ばれる
+    fn step_cycle(&mut self);
+
+    // 2A03のミキサー出力と同じスケールの出力値
+    fn value(&self) -> f32;
+}
+
 static DUTY_TABLE : [[u8;8];4] = [
     [0,1,0,0,0,0,0,0],
     [0,1,1,0,0,0,0,0],
@@ -506,6 +523,7 @@ pub struct Apu {
     pub noise : Noise,
     pub frames : Vec<f32>,
 
+    expansion_value : f32,
     time : f32,
     frame_cycle : f32, 
     time_per_cycle : f32,
@@ -520,6 +538,7 @@ impl Apu {
             triangle : Triangle::new(),
             noise : Noise::new(),
             frames: vec![],
+            expansion_value : 0.0,
             time : 0.0,
             frame_cycle : 1.0 / 44_100.0,
             time_per_cycle : 1.0 / 1_789_773.0,
@@ -574,8 +593,17 @@ impl Apu {
     }
 
     pub fn step(&mut self, count : usize) -> bool {
+        self.step_with_expansion(count, None)
+    }
+
+    // 拡張音源を同じクロックで進めて、出力に加える
+    pub fn step_with_expansion(&mut self, count : usize, mut expansion : Option<&mut dyn ExpansionAudio>) -> bool {
         let mut is_irq = false;
         for _ in 0..count {
+            if let Some(e) = expansion.as_deref_mut() {
+                e.step_cycle();
+                self.expansion_value = e.value();
+            }
             is_irq |= self.step_cycle()
         }
         is_irq
@@ -606,7 +634,7 @@ impl Apu {
         let noise = self.noise.value();
         let dmc = 0;
 
-        mixer(pulse1, pulse2, triangle, noise, dmc)
+        mixer(pulse1, pulse2, triangle, noise, dmc) + self.expansion_value
     }
 
 }
@@ -833,6 +861,78 @@ mod tests {
         m.write_wav_file(&file); 
     }
 
+    #[test]
+    #[ignore]
+    fn vrc6_矩形波とノコギリ波ファイル出力() {
+        use crate::vrc6::Vrc6Audio;
+
+        let mut m = Apu::new();
+        let mut vrc6 = Vrc6Audio::new();
+        // 440hz => 1789773 / 440 / 16 - 1 = 253 = 0xfd
+        vrc6.write(0x9000, 0x3f);
+        vrc6.write(0x9001, 0xfd);
+        vrc6.write(0x9002, 0x80);
+        m.step_with_expansion(40*44100/2, Some(&mut vrc6));
+
+        // 220hz => 1789773 / 220 / 14 - 1 = 580 = 0x244
+        vrc6.write(0x9002, 0x00);
+        vrc6.write(0xb000, 0x20);
+        vrc6.write(0xb001, 0x44);
+        vrc6.write(0xb002, 0x82);
+        m.step_with_expansion(40*44100/2, Some(&mut vrc6));
+
+        let file = TEST_OUTPUT.to_string() + "vrc6_1_pulse_saw.wav";
+        m.write_wav_file(&file);
+    }
+
+    #[test]
+    #[ignore]
+    fn vrc7_内蔵音色ファイル出力() {
+        use crate::vrc7::Vrc7Audio;
+
+        let mut m = Apu::new();
+        let mut vrc7 = Vrc7Audio::new();
+        // 440hz => fnum 288, block 4
+        for (reg, v) in [(0x30, 0x10), (0x10, 0x20), (0x20, 0x39)] {
+            vrc7.write_addr(reg);
+            vrc7.write_data(v);
+        }
+        m.step_with_expansion(40*44100/2, Some(&mut vrc7));
+
+        // キーオフしてリリースを聞く
+        vrc7.write_addr(0x20);
+        vrc7.write_data(0x09);
+        m.step_with_expansion(40*44100/2, Some(&mut vrc7));
+
+        let file = TEST_OUTPUT.to_string() + "vrc7_1_patch1.wav";
+        m.write_wav_file(&file);
+    }
+
+    #[test]
+    #[ignore]
+    fn sunsoft5b_矩形波とエンベロープファイル出力() {
+        use crate::sunsoft5b::Sunsoft5bAudio;
+
+        let mut m = Apu::new();
+        let mut s5b = Sunsoft5bAudio::new();
+        // 440hz => 1789773 / 440 / 32 = 127 = 0x7f
+        for (reg, v) in [(0x00, 0x7f), (0x01, 0x00), (0x07, 0x3e), (0x08, 0x0f)] {
+            s5b.write_addr(reg);
+            s5b.write_data(v);
+        }
+        m.step_with_expansion(40*44100/2, Some(&mut s5b));
+
+        // ノコギリ波状のエンベロープ
+        for (reg, v) in [(0x08, 0x10), (0x0b, 0x00), (0x0c, 0x01), (0x0d, 0x08)] {
+            s5b.write_addr(reg);
+            s5b.write_data(v);
+        }
+        m.step_with_expansion(40*44100/2, Some(&mut s5b));
+
+        let file = TEST_OUTPUT.to_string() + "sunsoft5b_1_tone_envelope.wav";
+        m.write_wav_file(&file);
+    }
+
     #[test]
     #[ignore]
     fn noise_lengthファイル出力() {
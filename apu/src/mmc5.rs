@@ -0,0 +1,80 @@
+use crate::{ExpansionAudio, Pulse, PULSE_TABLE, TND_TABLE};
+
+// MMC5の拡張音源
+// https://www.nesdev.org/wiki/MMC5_audio
+// 矩形波2チャンネル(スイープなし)とPCM
+#[derive(Debug)]
+pub struct Mmc5Audio {
+    pub pulse1 : Pulse,
+    pub pulse2 : Pulse,
+
+    pcm_read_mode : bool,
+    pcm : u8,
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Self {
+            pulse1: Pulse::new(1),
+            pulse2: Pulse::new(2),
+            pcm_read_mode: false,
+            pcm: 0,
+        }
+    }
+
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            // PCMの読み出しモードは未対応なので、IRQは発生しない
+            0x5010 => 0,
+            0x5015 => {
+                (self.pulse1.reg_is_enable as u8) |
+                (self.pulse2.reg_is_enable as u8) << 1
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr : u16, v : u8) {
+        match addr {
+            0x5000 => self.pulse1.write_reg1(v),
+            0x5002 => self.pulse1.write_reg3(v),
+            0x5003 => self.pulse1.write_reg4(v),
+            0x5004 => self.pulse2.write_reg1(v),
+            0x5006 => self.pulse2.write_reg3(v),
+            0x5007 => self.pulse2.write_reg4(v),
+            0x5010 => {
+                // l--- ---m  PCM IRQ enable (l), read mode (m)
+                self.pcm_read_mode = v & 1 != 0;
+            }
+            // 書き込みモードのみ、0の書き込みは無視される
+            0x5011 if !self.pcm_read_mode && v != 0 => {
+                self.pcm = v;
+            }
+            0x5015 => {
+                self.pulse1.reg_is_enable = v & (1 << 0) != 0;
+                self.pulse2.reg_is_enable = v & (1 << 1) != 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn step_cycle(&mut self) {
+        self.pulse1.step(1);
+        self.pulse2.step(1);
+    }
+
+    // 矩形波は2A03の矩形波と同じ音量、PCMはDMCの7bitに合わせる
+    fn value(&self) -> f32 {
+        let pulse = PULSE_TABLE[(self.pulse1.value() + self.pulse2.value()) as usize];
+        let pcm = TND_TABLE[(self.pcm >> 1) as usize];
+        pulse + pcm
+    }
+}
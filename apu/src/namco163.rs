@@ -0,0 +1,146 @@
+use crate::ExpansionAudio;
+
+// Namco 163の拡張音源
+// https://www.nesdev.org/wiki/Namco_163_audio
+// 128バイトの内部RAMに波形とチャンネルのレジスタがあり、15CPUサイクルごとに1チャンネルずつ更新される
+
+// 1チャンネルの更新にかかるCPUサイクル
+const CYCLES_PER_CHANNEL : u8 = 15;
+
+// 出力1段分。(サンプル - 8) * 音量 の最大120が2A03の矩形波2本分程度になるようにしている
+const N163_UNIT : f32 = 0.0035;
+
+// 複数チャンネルの出力方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namco163Mixing {
+    // 実機と同じく、更新中のチャンネルだけを出力する。チャンネル数が多いと15サイクル周期の音が聞こえる
+    Multiplex,
+    // 有効なチャンネルの出力を平均する
+    Average,
+}
+
+#[derive(Debug)]
+pub struct Namco163Audio {
+    pub ram : [u8; 128],
+    pub mixing : Namco163Mixing,
+    ram_addr : u8,
+    is_auto_increment : bool,
+    is_disable : bool,
+
+    cycle : u8,
+    // 次に更新するチャンネル (7が最初)
+    channel : usize,
+    outputs : [i8; 8],
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; 128],
+            mixing: Namco163Mixing::Average,
+            ram_addr: 0,
+            is_auto_increment: false,
+            is_disable: false,
+            cycle: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    // $F800  IAAA AAAA  自動インクリメント (I)、アドレス (A)
+    pub fn write_addr(&mut self, v : u8) {
+        self.is_auto_increment = v & 0x80 != 0;
+        self.ram_addr = v & 0x7f;
+    }
+
+    // $4800
+    pub fn read_data(&mut self) -> u8 {
+        let v = self.peek_data();
+        self.increment_addr();
+        v
+    }
+
+    // アドレスを進めずに読む
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.ram_addr as usize]
+    }
+
+    // $4800
+    pub fn write_data(&mut self, v : u8) {
+        self.ram[self.ram_addr as usize] = v;
+        self.increment_addr();
+    }
+
+    fn increment_addr(&mut self) {
+        if self.is_auto_increment {
+            self.ram_addr = (self.ram_addr + 1) & 0x7f;
+        }
+    }
+
+    // $E000 bit 6
+    pub fn set_disable(&mut self, b : bool) {
+        self.is_disable = b;
+    }
+
+    // $7F bit 4-6 が有効なチャンネル数 - 1。有効なのは後ろのチャンネルから
+    fn channel_count(&self) -> usize {
+        ((self.ram[0x7f] >> 4) & 0x07) as usize + 1
+    }
+
+    fn update_channel(&mut self, ch : usize) {
+        let base = 0x40 + ch * 8;
+        let reg = &self.ram[base..base + 8];
+        let freq = reg[0] as u32 | (reg[2] as u32) << 8 | ((reg[4] & 0x03) as u32) << 16;
+        let phase = reg[1] as u32 | (reg[3] as u32) << 8 | (reg[5] as u32) << 16;
+        let length = 256 - (reg[4] & 0xfc) as u32;
+        let offset = reg[6] as u32;
+        let volume = (reg[7] & 0x0f) as i8;
+
+        let phase = (phase + freq) % (length << 16);
+        let index = (((phase >> 16) + offset) & 0xff) as usize;
+        let sample = (self.ram[index >> 1] >> ((index & 1) * 4)) & 0x0f;
+        self.outputs[ch] = (sample as i8 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn step_cycle(&mut self) {
+        if self.is_disable {
+            return;
+        }
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle = 0;
+
+        let count = self.channel_count();
+        self.channel = if self.channel <= 8 - count { 7 } else { self.channel - 1 };
+        self.update_channel(self.channel);
+    }
+
+    fn value(&self) -> f32 {
+        if self.is_disable {
+            return 0.0;
+        }
+        let count = self.channel_count();
+        let v = match self.mixing {
+            Namco163Mixing::Multiplex => self.outputs[self.channel] as f32,
+            Namco163Mixing::Average => {
+                let sum : i32 = self.outputs[8 - count..].iter().map(|v| *v as i32).sum();
+                sum as f32 / count as f32
+            }
+        };
+        v * N163_UNIT
+    }
+}
@@ -0,0 +1,255 @@
+use once_cell::sync::Lazy;
+
+use crate::ExpansionAudio;
+
+// Sunsoft 5Bの拡張音源 (YM2149F相当)
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+// 矩形波3チャンネルとノイズ、エンベロープジェネレータ
+
+// 1チャンネルが最大音量の時の出力
+const CHANNEL_VOLUME : f32 = 0.12;
+
+// 音量は5bit(1.5dB単位)の対数カーブ。0は無音
+static VOLUME_TABLE : Lazy<[f32; 32]> = Lazy::new(||{
+    let mut t = [0.0; 32];
+    for (i, v) in t.iter_mut().enumerate().skip(1) {
+        *v = 10f32.powf((i as f32 - 31.0) * 1.5 / 20.0) * CHANNEL_VOLUME;
+    }
+    t
+});
+
+#[derive(Debug)]
+struct Tone {
+    period : u16,
+    // ---E VVVV  エンベロープを使う (E)、音量 (V)
+    volume : u8,
+    is_envelope : bool,
+    is_tone_disable : bool,
+    is_noise_disable : bool,
+
+    timer_divider : u16,
+    output : bool,
+}
+
+impl Tone {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            volume: 0,
+            is_envelope: false,
+            is_tone_disable: true,
+            is_noise_disable: true,
+            timer_divider: 0,
+            output: false,
+        }
+    }
+
+    // 16サイクルごとに呼ばれる。周期ごとに出力が反転するので周波数は CPU / (32 * period)
+    fn step(&mut self) {
+        self.timer_divider += 1;
+        if self.timer_divider >= self.period.max(1) {
+            self.timer_divider = 0;
+            self.output = !self.output;
+        }
+    }
+}
+
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio#Envelope
+#[derive(Debug)]
+struct Envelope {
+    period : u16,
+    is_continue : bool,
+    is_attack : bool,
+    is_alternate : bool,
+    is_hold : bool,
+
+    timer_divider : u16,
+    // 0-31
+    step : u8,
+    is_up : bool,
+    is_holding : bool,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            is_continue: false,
+            is_attack: false,
+            is_alternate: false,
+            is_hold: false,
+            timer_divider: 0,
+            step: 0,
+            is_up: false,
+            is_holding: true,
+        }
+    }
+
+    // CAAH  continue (C)、attack (A)、alternate (A)、hold (H)
+    fn write_shape(&mut self, v : u8) {
+        self.is_continue = v & 0x08 != 0;
+        self.is_attack = v & 0x04 != 0;
+        self.is_alternate = v & 0x02 != 0;
+        self.is_hold = v & 0x01 != 0;
+        self.timer_divider = 0;
+        self.step = 0;
+        self.is_up = self.is_attack;
+        self.is_holding = false;
+    }
+
+    // 16サイクルごとに呼ばれる。32段なので1周期は 512 * period サイクル
+    fn step(&mut self) {
+        if self.is_holding {
+            return;
+        }
+        self.timer_divider += 1;
+        if self.timer_divider < self.period.max(1) {
+            return;
+        }
+        self.timer_divider = 0;
+        if self.step < 31 {
+            self.step += 1;
+            return;
+        }
+        // 1周期終わった
+        if !self.is_continue {
+            self.is_up = false;
+            self.is_holding = true;
+        } else if self.is_hold {
+            if self.is_alternate {
+                self.is_up = !self.is_up;
+            }
+            self.is_holding = true;
+        } else {
+            if self.is_alternate {
+                self.is_up = !self.is_up;
+            }
+            self.step = 0;
+        }
+    }
+
+    fn value(&self) -> u8 {
+        if self.is_holding {
+            // 保持中は最後の値のまま
+            if self.is_up { 31 } else { 0 }
+        } else if self.is_up {
+            self.step
+        } else {
+            31 - self.step
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Sunsoft5bAudio {
+    register_addr : u8,
+    tones : [Tone; 3],
+    envelope : Envelope,
+
+    noise_period : u8,
+    noise_divider : u8,
+    // 17bitのLFSR
+    noise_shift : u32,
+
+    cycle : u8,
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        Self {
+            register_addr: 0,
+            tones: [Tone::new(), Tone::new(), Tone::new()],
+            envelope: Envelope::new(),
+            noise_period: 0,
+            noise_divider: 0,
+            noise_shift: 1,
+            cycle: 0,
+        }
+    }
+
+    // $C000
+    pub fn write_addr(&mut self, v : u8) {
+        self.register_addr = v & 0x0f;
+    }
+
+    // $E000
+    pub fn write_data(&mut self, v : u8) {
+        match self.register_addr {
+            r @ (0x00 | 0x02 | 0x04) => {
+                let t = &mut self.tones[(r >> 1) as usize];
+                t.period = t.period & 0x0f00 | v as u16;
+            }
+            r @ (0x01 | 0x03 | 0x05) => {
+                let t = &mut self.tones[(r >> 1) as usize];
+                t.period = t.period & 0x00ff | ((v & 0x0f) as u16) << 8;
+            }
+            0x06 => self.noise_period = v & 0x1f,
+            0x07 => {
+                // --CB Acba  ノイズ無効 (CBA)、矩形波無効 (cba)
+                for (i, t) in self.tones.iter_mut().enumerate() {
+                    t.is_tone_disable = v & (1 << i) != 0;
+                    t.is_noise_disable = v & (1 << (i + 3)) != 0;
+                }
+            }
+            r @ 0x08 ..= 0x0a => {
+                let t = &mut self.tones[(r - 8) as usize];
+                t.volume = v & 0x0f;
+                t.is_envelope = v & 0x10 != 0;
+            }
+            0x0b => self.envelope.period = self.envelope.period & 0xff00 | v as u16,
+            0x0c => self.envelope.period = self.envelope.period & 0x00ff | (v as u16) << 8,
+            0x0d => self.envelope.write_shape(v),
+            _ => {}
+        }
+    }
+
+    fn step_noise(&mut self) {
+        // ノイズは矩形波の半分の速さで進む
+        self.noise_divider += 1;
+        if self.noise_divider >= self.noise_period.max(1) * 2 {
+            self.noise_divider = 0;
+            let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (bit << 16);
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn step_cycle(&mut self) {
+        // 内部では16サイクルごとに進む
+        self.cycle += 1;
+        if self.cycle < 16 {
+            return;
+        }
+        self.cycle = 0;
+        for t in self.tones.iter_mut() {
+            t.step();
+        }
+        self.step_noise();
+        self.envelope.step();
+    }
+
+    fn value(&self) -> f32 {
+        let noise = self.noise_shift & 1 != 0;
+        self.tones.iter().map(|t| {
+            let is_on = (t.output || t.is_tone_disable) && (noise || t.is_noise_disable);
+            if !is_on {
+                return 0.0;
+            }
+            let level = if t.is_envelope {
+                self.envelope.value()
+            } else if t.volume == 0 {
+                0
+            } else {
+                t.volume * 2 + 1
+            };
+            VOLUME_TABLE[level as usize]
+        }).sum()
+    }
+}
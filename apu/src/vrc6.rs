@@ -0,0 +1,242 @@
+use crate::ExpansionAudio;
+
+// 2A03の矩形波1段分の線形近似
+// https://www.nesdev.org/wiki/APU_Mixer#Linear_Approximation
+const VRC6_UNIT : f32 = 0.00752;
+
+// VRC6の矩形波
+// https://www.nesdev.org/wiki/VRC6_audio#Pulse_Channels
+#[derive(Debug)]
+pub struct Vrc6Pulse {
+    pub is_digitized : bool,
+    pub duty : u8,
+    pub volume : u8,
+    pub period : u16,
+    pub is_enable : bool,
+
+    timer_divider : u16,
+    step : u8,
+}
+
+impl Default for Vrc6Pulse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vrc6Pulse {
+    pub fn new() -> Self {
+        Self {
+            is_digitized: false,
+            duty: 0,
+            volume: 0,
+            period: 0,
+            is_enable: false,
+            timer_divider: 0,
+            step: 15,
+        }
+    }
+
+    pub fn write_reg1(&mut self, v : u8) {
+        // MDDD VVVV  mode (M), duty (D), volume (V)
+        self.is_digitized = v & (1 << 7) != 0;
+        self.duty = (v >> 4) & 0x07;
+        self.volume = v & 0x0f;
+    }
+
+    pub fn write_reg2(&mut self, v : u8) {
+        // FFFF FFFF  period low
+        self.period = self.period & 0x0f00 | v as u16;
+    }
+
+    pub fn write_reg3(&mut self, v : u8) {
+        // E--- FFFF  enable (E), period high
+        self.is_enable = v & (1 << 7) != 0;
+        self.period = self.period & 0x00ff | ((v & 0x0f) as u16) << 8;
+        if !self.is_enable {
+            self.step = 15;
+        }
+    }
+
+    fn step_cycle(&mut self, shift : u8) {
+        if !self.is_enable {
+            return;
+        }
+        // 分周器は周期+1サイクルごとに1段進む
+        self.timer_divider += 1;
+        if self.timer_divider > self.period >> shift {
+            self.timer_divider = 0;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        if self.is_enable && (self.is_digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+// VRC6のノコギリ波
+// https://www.nesdev.org/wiki/VRC6_audio#Sawtooth_Channel
+#[derive(Debug)]
+pub struct Vrc6Saw {
+    pub rate : u8,
+    pub period : u16,
+    pub is_enable : bool,
+
+    timer_divider : u16,
+    step : u8,
+    accumulator : u8,
+}
+
+impl Default for Vrc6Saw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vrc6Saw {
+    pub fn new() -> Self {
+        Self {
+            rate: 0,
+            period: 0,
+            is_enable: false,
+            timer_divider: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    pub fn write_reg1(&mut self, v : u8) {
+        // --AA AAAA  accumulator rate (A)
+        self.rate = v & 0x3f;
+    }
+
+    pub fn write_reg2(&mut self, v : u8) {
+        self.period = self.period & 0x0f00 | v as u16;
+    }
+
+    pub fn write_reg3(&mut self, v : u8) {
+        self.is_enable = v & (1 << 7) != 0;
+        self.period = self.period & 0x00ff | ((v & 0x0f) as u16) << 8;
+        if !self.is_enable {
+            self.step = 0;
+            self.accumulator = 0;
+        }
+    }
+
+    fn step_cycle(&mut self, shift : u8) {
+        if !self.is_enable {
+            return;
+        }
+        self.timer_divider += 1;
+        if self.timer_divider > self.period >> shift {
+            self.timer_divider = 0;
+            // 2回に1回アキュムレータに加算し、7回加算したら0に戻る
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 1 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        }
+    }
+
+    // 上位5bitが出力される
+    pub fn value(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// VRC6の拡張音源
+// https://www.nesdev.org/wiki/VRC6_audio
+#[derive(Debug)]
+pub struct Vrc6Audio {
+    pub pulse1 : Vrc6Pulse,
+    pub pulse2 : Vrc6Pulse,
+    pub saw : Vrc6Saw,
+
+    is_halt : bool,
+    frequency_shift : u8,
+}
+
+impl Default for Vrc6Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Self {
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            is_halt: false,
+            frequency_shift: 0,
+        }
+    }
+
+    // addrは $9000-$9003, $A000-$A002, $B000-$B002 に正規化済みのもの
+    pub fn write(&mut self, addr : u16, v : u8) {
+        match addr {
+            0x9000 => self.pulse1.write_reg1(v),
+            0x9001 => self.pulse1.write_reg2(v),
+            0x9002 => self.pulse1.write_reg3(v),
+            0x9003 => {
+                // ---- -ABH  16倍 (B)、256倍 (A)、全チャンネル停止 (H)
+                self.is_halt = v & 1 != 0;
+                self.frequency_shift = if v & 4 != 0 { 8 } else if v & 2 != 0 { 4 } else { 0 };
+            }
+            0xa000 => self.pulse2.write_reg1(v),
+            0xa001 => self.pulse2.write_reg2(v),
+            0xa002 => self.pulse2.write_reg3(v),
+            0xb000 => self.saw.write_reg1(v),
+            0xb001 => self.saw.write_reg2(v),
+            0xb002 => self.saw.write_reg3(v),
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn step_cycle(&mut self) {
+        if self.is_halt {
+            return;
+        }
+        self.pulse1.step_cycle(self.frequency_shift);
+        self.pulse2.step_cycle(self.frequency_shift);
+        self.saw.step_cycle(self.frequency_shift);
+    }
+
+    // VRC6は線形に足し合わされる。矩形波の音量15が2A03の矩形波の音量15とほぼ同じ
+    fn value(&self) -> f32 {
+        let v = self.pulse1.value() as f32 + self.pulse2.value() as f32 + self.saw.value() as f32;
+        v * VRC6_UNIT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 矩形波は周期プラス1サイクルごとに進む() {
+        let mut p = Vrc6Pulse::new();
+        p.write_reg2(3);
+        p.write_reg3(0x80);
+        let mut steps = vec![];
+        for i in 0..12 {
+            let step = p.step;
+            p.step_cycle(0);
+            if p.step != step {
+                steps.push(i);
+            }
+        }
+        assert_eq!(steps, vec![3, 7, 11]);
+    }
+}
@@ -0,0 +1,431 @@
+use std::f64::consts::PI;
+
+use crate::ExpansionAudio;
+
+// VRC7の拡張音源 (YM2413(OPLL)の6チャンネル版)
+// https://www.nesdev.org/wiki/VRC7_audio
+// エンベロープの計算はemu2413を参考にしている
+
+// 3.579545MHz / 72 = 49716Hz で1サンプル。CPUクロック(1.789773MHz)では36サイクルごと
+const CPU_CYCLES_PER_SAMPLE : usize = 36;
+const SAMPLE_RATE : f64 = 1_789_773.0 / CPU_CYCLES_PER_SAMPLE as f64;
+
+// 1チャンネルが最大音量の時の出力
+const CHANNEL_VOLUME : f32 = 0.1;
+
+// 内蔵音色 (0番はユーザー定義)
+// https://www.nesdev.org/wiki/VRC7_audio#Internal_patch_set
+static PATCH_TABLE : [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+// 周波数倍率の2倍
+static MULTIPLE_TABLE : [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// キースケールレベル (3dB/oct の時のdB)
+static KSL_TABLE : [f64; 16] = [
+    0.000, 9.000, 12.000, 13.875, 15.000, 16.125, 16.875, 17.625,
+    18.000, 18.750, 19.125, 19.500, 19.875, 20.250, 20.625, 21.000,
+];
+
+// エンベロープは7bit(0.375dB単位)、内部は22bitの固定小数点で進める
+const EG_STEP_DB : f64 = 0.375;
+const EG_MAX : u8 = 127;
+const EG_DP_BITS : u32 = 22;
+const EG_DP_WIDTH : u32 = 1 << EG_DP_BITS;
+const EG_SHIFT : u32 = EG_DP_BITS - 7;
+
+// トレモロ(AM)とビブラート(PM)
+const AM_SPEED : f64 = 3.6413;
+const AM_DEPTH_DB : f64 = 4.875;
+const PM_SPEED : f64 = 6.4;
+const PM_DEPTH_CENT : f64 = 13.75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    SustainHold,
+    Sustain,
+    Release,
+    Finish,
+}
+
+// 音色の1オペレータ分
+#[derive(Debug, Clone, Copy)]
+struct Operator {
+    is_am : bool,
+    is_vibrato : bool,
+    // true: 持続音 (キーオフまでサスティンを保つ)
+    is_sustained : bool,
+    is_ksr : bool,
+    multiple : u8,
+    ksl : u8,
+    is_half_sine : bool,
+    attack : u8,
+    decay : u8,
+    sustain_level : u8,
+    release : u8,
+}
+
+impl Operator {
+    // 音色データから取り出す。iが0ならモジュレータ、1ならキャリア
+    fn from_patch(patch : &[u8; 8], i : usize) -> Self {
+        Self {
+            is_am: patch[i] & 0x80 != 0,
+            is_vibrato: patch[i] & 0x40 != 0,
+            is_sustained: patch[i] & 0x20 != 0,
+            is_ksr: patch[i] & 0x10 != 0,
+            multiple: patch[i] & 0x0f,
+            ksl: patch[2 + i] >> 6,
+            is_half_sine: patch[3] & (if i == 0 { 0x08 } else { 0x10 }) != 0,
+            attack: patch[4 + i] >> 4,
+            decay: patch[4 + i] & 0x0f,
+            sustain_level: patch[6 + i] >> 4,
+            release: patch[6 + i] & 0x0f,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    // 1周期 = 2^19
+    phase : u32,
+    state : EnvelopeState,
+    eg_phase : u32,
+    eg_out : u8,
+    output : [f64; 2],
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            phase: 0,
+            state: EnvelopeState::Finish,
+            eg_phase: EG_DP_WIDTH,
+            eg_out: EG_MAX,
+            output: [0.0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.eg_phase = 0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Finish {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    fn step_envelope(&mut self, op : &Operator, rks : u32, is_channel_sustain : bool) {
+        match self.state {
+            EnvelopeState::Attack => {
+                self.eg_out = attack_curve(self.eg_phase >> EG_SHIFT);
+                self.eg_phase += attack_rate(op.attack, rks);
+                if self.eg_phase >= EG_DP_WIDTH || op.attack == 15 {
+                    self.eg_out = 0;
+                    self.eg_phase = 0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.eg_out = (self.eg_phase >> EG_SHIFT) as u8;
+                self.eg_phase += decay_rate(op.decay, rks);
+                // サスティンレベルは3dB単位
+                let sl = (op.sustain_level as u32 * 8) << EG_SHIFT;
+                if self.eg_phase >= sl {
+                    self.eg_phase = sl;
+                    self.state = if op.is_sustained { EnvelopeState::SustainHold } else { EnvelopeState::Sustain };
+                }
+            }
+            EnvelopeState::SustainHold => {
+                self.eg_out = (self.eg_phase >> EG_SHIFT) as u8;
+                if !op.is_sustained {
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain | EnvelopeState::Release => {
+                self.eg_out = (self.eg_phase >> EG_SHIFT).min(EG_MAX as u32) as u8;
+                let rate = if self.state == EnvelopeState::Sustain {
+                    op.release
+                } else if is_channel_sustain {
+                    5
+                } else if op.is_sustained {
+                    op.release
+                } else {
+                    7
+                };
+                self.eg_phase += decay_rate(rate, rks);
+                if self.eg_phase >= EG_DP_WIDTH {
+                    self.eg_out = EG_MAX;
+                    self.state = EnvelopeState::Finish;
+                }
+            }
+            EnvelopeState::Finish => {
+                self.eg_out = EG_MAX;
+            }
+        }
+    }
+
+    // attenuationはエンベロープ以外の減衰量(dB)、modulationは位相のずれ(rad)
+    fn calc(&mut self, op : &Operator, attenuation : f64, modulation : f64) -> f64 {
+        if self.state == EnvelopeState::Finish {
+            self.output = [0.0, self.output[0]];
+            return 0.0;
+        }
+        let rad = (self.phase as f64) / ((1 << 19) as f64) * 2.0 * PI + modulation;
+        let mut v = rad.sin();
+        if op.is_half_sine && v < 0.0 {
+            v = 0.0;
+        }
+        let db = self.eg_out as f64 * EG_STEP_DB + attenuation;
+        let out = v * 10f64.powf(-db / 20.0);
+        self.output = [out, self.output[0]];
+        out
+    }
+}
+
+// アタックはリニアに進む位相を対数に変換する
+fn attack_curve(i : u32) -> u8 {
+    if i == 0 {
+        return EG_MAX;
+    }
+    let v = EG_MAX as f64 - EG_MAX as f64 * (i as f64).ln() / (128f64).ln();
+    v.max(0.0) as u8
+}
+
+fn attack_rate(ar : u8, rks : u32) -> u32 {
+    if ar == 0 {
+        return 0;
+    }
+    let rm = (ar as u32 + (rks >> 2)).min(15);
+    let rl = rks & 3;
+    (3 * (rl + 4)) << (rm + 1)
+}
+
+fn decay_rate(dr : u8, rks : u32) -> u32 {
+    if dr == 0 {
+        return 0;
+    }
+    let rm = (dr as u32 + (rks >> 2)).min(15);
+    let rl = rks & 3;
+    (rl + 4) << (rm - 1)
+}
+
+#[derive(Debug)]
+struct Channel {
+    fnum : u16,
+    block : u8,
+    is_sustain : bool,
+    is_key_on : bool,
+    instrument : u8,
+    volume : u8,
+
+    modulator : Slot,
+    carrier : Slot,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            fnum: 0,
+            block: 0,
+            is_sustain: false,
+            is_key_on: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Slot::new(),
+            carrier: Slot::new(),
+        }
+    }
+
+    fn rks(&self, op : &Operator) -> u32 {
+        let v = ((self.block as u32) << 1) | (self.fnum as u32 >> 8);
+        if op.is_ksr { v } else { v >> 2 }
+    }
+
+    fn phase_increment(&self, op : &Operator, pm : f64) -> u32 {
+        let inc = (((self.fnum as u32) << self.block) * MULTIPLE_TABLE[op.multiple as usize]) >> 1;
+        if op.is_vibrato {
+            (inc as f64 * pm) as u32
+        } else {
+            inc
+        }
+    }
+
+    fn ksl_attenuation(&self, op : &Operator) -> f64 {
+        let db = (KSL_TABLE[(self.fnum >> 5) as usize] - 3.0 * (7 - self.block) as f64).max(0.0);
+        match op.ksl {
+            0 => 0.0,
+            1 => db / 2.0,
+            2 => db,
+            _ => db * 2.0,
+        }
+    }
+
+    fn calc(&mut self, patch : &[u8; 8], am : f64, pm : f64) -> f64 {
+        let m = Operator::from_patch(patch, 0);
+        let c = Operator::from_patch(patch, 1);
+
+        self.modulator.step_envelope(&m, self.rks(&m), self.is_sustain);
+        self.carrier.step_envelope(&c, self.rks(&c), self.is_sustain);
+
+        // モジュレータは自己フィードバックがある
+        let feedback = (patch[3] & 0x07) as i32;
+        let fb = if feedback == 0 {
+            0.0
+        } else {
+            (self.modulator.output[0] + self.modulator.output[1]) / 2.0 * 4.0 * PI / (1 << (7 - feedback)) as f64
+        };
+        // モジュレータはTL(0.75dB単位)、キャリアはチャンネルの音量(3dB単位)
+        let tl = (patch[2] & 0x3f) as f64 * 0.75;
+        let m_att = tl + self.ksl_attenuation(&m) + if m.is_am { am } else { 0.0 };
+        let mod_out = self.modulator.calc(&m, m_att, fb);
+
+        let c_att = self.volume as f64 * 3.0 + self.ksl_attenuation(&c) + if c.is_am { am } else { 0.0 };
+        let out = self.carrier.calc(&c, c_att, mod_out * 4.0 * PI);
+
+        self.modulator.phase = (self.modulator.phase + self.phase_increment(&m, pm)) & 0x7ffff;
+        self.carrier.phase = (self.carrier.phase + self.phase_increment(&c, pm)) & 0x7ffff;
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct Vrc7Audio {
+    register_addr : u8,
+    custom_patch : [u8; 8],
+    channels : [Channel; 6],
+    is_mute : bool,
+
+    am_phase : f64,
+    pm_phase : f64,
+
+    cycle : usize,
+    sample : [f32; 2],
+}
+
+impl Default for Vrc7Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Self {
+        Self {
+            register_addr: 0,
+            custom_patch: [0; 8],
+            channels: [Channel::new(), Channel::new(), Channel::new(), Channel::new(), Channel::new(), Channel::new()],
+            is_mute: false,
+            am_phase: 0.0,
+            pm_phase: 0.0,
+            cycle: 0,
+            sample: [0.0; 2],
+        }
+    }
+
+    // $9010
+    pub fn write_addr(&mut self, v : u8) {
+        self.register_addr = v;
+    }
+
+    // $9030
+    pub fn write_data(&mut self, v : u8) {
+        let r = self.register_addr as usize;
+        match r {
+            0x00 ..= 0x07 => self.custom_patch[r] = v,
+            0x10 ..= 0x15 => {
+                let ch = &mut self.channels[r & 0x0f];
+                ch.fnum = ch.fnum & 0x100 | v as u16;
+            }
+            0x20 ..= 0x25 => {
+                // --SK BBBF  sustain (S), key on (K), block (B), fnum bit 8 (F)
+                let ch = &mut self.channels[r & 0x0f];
+                ch.fnum = ch.fnum & 0xff | ((v & 1) as u16) << 8;
+                ch.block = (v >> 1) & 0x07;
+                ch.is_sustain = v & 0x20 != 0;
+                let is_key_on = v & 0x10 != 0;
+                if is_key_on && !ch.is_key_on {
+                    ch.modulator.key_on();
+                    ch.carrier.key_on();
+                } else if !is_key_on && ch.is_key_on {
+                    ch.modulator.key_off();
+                    ch.carrier.key_off();
+                }
+                ch.is_key_on = is_key_on;
+            }
+            0x30 ..= 0x35 => {
+                // IIII VVVV  instrument (I), volume (V)
+                let ch = &mut self.channels[r & 0x0f];
+                ch.instrument = v >> 4;
+                ch.volume = v & 0x0f;
+            }
+            _ => {}
+        }
+    }
+
+    // $E000 bit 6 で音源がリセットされ無音になる
+    pub fn set_mute(&mut self, b : bool) {
+        self.is_mute = b;
+        if b {
+            for ch in self.channels.iter_mut() {
+                ch.modulator = Slot::new();
+                ch.carrier = Slot::new();
+                ch.is_key_on = false;
+            }
+        }
+    }
+
+    fn calc_sample(&mut self) -> f32 {
+        self.am_phase = (self.am_phase + AM_SPEED / SAMPLE_RATE) % 1.0;
+        self.pm_phase = (self.pm_phase + PM_SPEED / SAMPLE_RATE) % 1.0;
+        let am = (1.0 + (self.am_phase * 2.0 * PI).sin()) / 2.0 * AM_DEPTH_DB;
+        let pm = 2f64.powf(PM_DEPTH_CENT * (self.pm_phase * 2.0 * PI).sin() / 1200.0);
+
+        if self.is_mute {
+            return 0.0;
+        }
+        let mut v = 0.0;
+        for ch in self.channels.iter_mut() {
+            let patch = if ch.instrument == 0 { &self.custom_patch } else { &PATCH_TABLE[ch.instrument as usize] };
+            v += ch.calc(patch, am, pm);
+        }
+        v as f32 * CHANNEL_VOLUME
+    }
+}
+
+impl ExpansionAudio for Vrc7Audio {
+    fn step_cycle(&mut self) {
+        self.cycle += 1;
+        if self.cycle >= CPU_CYCLES_PER_SAMPLE {
+            self.cycle = 0;
+            let v = self.calc_sample();
+            self.sample = [self.sample[1], v];
+        }
+    }
+
+    // 49716Hzの出力を線形補間してAPUのサンプリングに合わせる
+    fn value(&self) -> f32 {
+        let t = self.cycle as f32 / CPU_CYCLES_PER_SAMPLE as f32;
+        self.sample[0] + (self.sample[1] - self.sample[0]) * t
+    }
+}
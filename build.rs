@@ -0,0 +1,72 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+// db/以下のゲームデータベース(XML)を読み込み、src/rom_db.rsから読み込むテーブルを生成する
+// XMLの読み込みはテストできるようにsrc/rom_db_xml.rsに置いている
+#[path = "src/rom_db_xml.rs"]
+mod rom_db_xml;
+
+use rom_db_xml::parse_db;
+
+fn option<T : std::fmt::Debug>(v : &Option<T>) -> String {
+    format!("{:?}", v)
+}
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let db_dir = Path::new(&manifest_dir).join("db");
+    println!("cargo:rerun-if-changed={}", db_dir.display());
+    println!("cargo:rerun-if-env-changed=FAMIKO_REQUIRE_DB");
+
+    let mut entries = vec![];
+    if let Ok(dir) = fs::read_dir(&db_dir) {
+        let mut paths = dir.filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("xml")))
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let count = entries.len();
+            match fs::read_to_string(&path) {
+                Ok(xml) => parse_db(&xml, &mut entries),
+                Err(e) => println!("cargo:warning={}: {}", path.display(), e),
+            }
+            if entries.len() == count {
+                println!("cargo:warning={} にゲームのエントリがありません", path.display());
+            }
+        }
+    }
+
+    // データベースがないとヘッダの補正が何もしないので、気付けるように警告する
+    // FAMIKO_REQUIRE_DB を設定した場合はビルドを失敗させる
+    if entries.is_empty() {
+        let message = "db/ にゲームデータベース(XML)がないため、ヘッダの補正は行われません。入手方法は db/README.md を参照してください";
+        if env::var_os("FAMIKO_REQUIRE_DB").is_some() {
+            panic!("{}", message);
+        }
+        println!("cargo:warning={}", message);
+    }
+
+    let mut out = String::from("pub static ROM_DB : &[DbEntry] = &[\n");
+    for e in &entries {
+        let mirroring = match e.mirroring {
+            Some('H') | Some('h') => "Some(Mirroring::Horizontal)",
+            Some('V') | Some('v') => "Some(Mirroring::Vertical)",
+            Some('4') => "Some(Mirroring::FourScreen)",
+            _ => "None",
+        };
+        let _ = writeln!(
+            out,
+            "    DbEntry {{ crc32: {}, sha1: {}, title: {:?}, board: {:?}, mapper: {}, submapper: {}, mirroring: {}, battery: {}, prg_ram_size: {}, prg_nvram_size: {}, chr_ram_size: {}, timing: {}, console_type: {} }},",
+            option(&e.crc32), option(&e.sha1), e.title, e.board, option(&e.mapper), option(&e.submapper), mirroring,
+            option(&e.battery), option(&e.prg_ram_size), option(&e.prg_nvram_size), option(&e.chr_ram_size),
+            option(&e.timing), option(&e.console_type),
+        );
+    }
+    out.push_str("];\n");
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("rom_db.rs");
+    fs::write(out_path, out).unwrap();
+}
@@ -4,7 +4,7 @@ use std::{fs::{self, File}, io::BufWriter};
 use hound::WavWriter;
 use pa::{Stream, Blocking, Output, StreamAvailable};
 use portaudio as pa;
-use apu::{self, Apu};
+use apu::{self, Apu, ExpansionAudio};
 
 const CHANNELS: i32 = 1;
 const SAMPLE_RATE: f64 = 44_100.0;
@@ -127,8 +127,8 @@ impl ApuImpl {
         self.apu.write(addr, v);
     }
 
-    pub fn step(&mut self, cycle: usize) {
-        let is_irq = self.apu.step(cycle);
+    pub fn step(&mut self, cycle: usize, expansion: Option<&mut dyn ExpansionAudio>) {
+        let is_irq = self.apu.step_with_expansion(cycle, expansion);
         self.flush_buffer_if_need();
 
         if is_irq {
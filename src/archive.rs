@@ -0,0 +1,170 @@
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+// 圧縮されたROMの読み込み (.zip, .7z, .gz)
+// アーカイブ内のROMが複数ある場合は "archive.zip#inner.nes" の形式で指定する
+
+// アーカイブの中から探すファイルの拡張子
+const ROM_EXTENSIONS : [&str; 4] = ["nes", "fds", "unf", "unif"];
+
+#[derive(Debug)]
+pub struct RomFile {
+    pub data : Vec::<u8>,
+    // アーカイブの場合はアーカイブと同じディレクトリにある中のファイルとみなしたパス
+    // (拡張子での判別や.savの保存先に使う)
+    pub path : PathBuf,
+}
+
+fn is_rom_name(name : &str) -> bool {
+    Path::new(name).extension()
+        .is_some_and(|e| ROM_EXTENSIONS.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+// 候補から1つ選ぶ。指定がなければ1つだけの場合に限る
+fn select_entry(names : &[String], inner : Option<&str>) -> Result<String, String> {
+    if let Some(inner) = inner {
+        return names.iter().find(|n| n.eq_ignore_ascii_case(inner) || n.rsplit('/').next() == Some(inner))
+            .cloned()
+            .ok_or_else(|| format!("{} not found in archive", inner));
+    }
+    let roms = names.iter().filter(|n| is_rom_name(n)).collect::<Vec<_>>();
+    match roms.as_slice() {
+        [name] => Ok((*name).clone()),
+        [] => Err("no rom file in archive".into()),
+        _ => Err(format!(
+            "multiple roms in archive, specify one as archive#name: {}",
+            roms.iter().map(|v| v.as_str()).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+fn read_zip(data : &[u8], inner : Option<&str>) -> Result<(String, Vec::<u8>), String> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let names = zip.file_names().map(str::to_string).collect::<Vec<_>>();
+    let name = select_entry(&names, inner)?;
+    let mut file = zip.by_name(&name).map_err(|e| e.to_string())?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+    Ok((name, buf))
+}
+
+fn read_7z(data : &[u8], inner : Option<&str>) -> Result<(String, Vec::<u8>), String> {
+    let open = || sevenz_rust::SevenZReader::new(Cursor::new(data), data.len() as u64, sevenz_rust::Password::empty())
+        .map_err(|e| e.to_string());
+    // 一覧を作ってから、選んだファイルだけを展開する
+    let mut names = vec![];
+    open()?.for_each_entries(|entry, _| {
+        if !entry.is_directory() {
+            names.push(entry.name().to_string());
+        }
+        Ok(true)
+    }).map_err(|e| e.to_string())?;
+    let name = select_entry(&names, inner)?;
+
+    let mut buf = Vec::new();
+    open()?.for_each_entries(|entry, reader| {
+        if entry.name() == name {
+            reader.read_to_end(&mut buf)?;
+            return Ok(false);
+        }
+        // ソリッド圧縮では前のファイルを読み飛ばす必要がある
+        std::io::copy(reader, &mut std::io::sink())?;
+        Ok(true)
+    }).map_err(|e| e.to_string())?;
+    Ok((name, buf))
+}
+
+// gzipは1ファイルだけなので、元のファイル名がなければ拡張子を外した名前にする
+fn read_gzip(data : &[u8], path : &Path) -> Result<(String, Vec::<u8>), String> {
+    let mut decoder = flate2::read::GzDecoder::new(data);
+    let mut buf = Vec::new();
+    decoder.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+    let name = decoder.header()
+        .and_then(|h| h.filename())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+        .unwrap_or_else(|| path.file_stem().map(|v| v.to_string_lossy().into_owned()).unwrap_or_default());
+    Ok((name, buf))
+}
+
+// ROMファイルを読み込む。アーカイブは先頭のマジックで判別する
+pub fn load_rom_file(file : &str) -> Result<RomFile, String> {
+    // "archive.zip#inner.nes" は#の前がファイルとして存在する場合のみ分ける
+    let (path, inner) = match file.rsplit_once('#') {
+        Some((archive, inner)) if !Path::new(file).exists() && Path::new(archive).exists() => (Path::new(archive), Some(inner)),
+        _ => (Path::new(file), None),
+    };
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let extracted = if data.starts_with(b"PK\x03\x04") {
+        Some(read_zip(&data, inner)?)
+    } else if data.starts_with(b"7z\xbc\xaf\x27\x1c") {
+        Some(read_7z(&data, inner)?)
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        Some(read_gzip(&data, path)?)
+    } else {
+        None
+    };
+
+    Ok(match extracted {
+        Some((name, data)) => {
+            let file_name = name.rsplit('/').next().unwrap_or(&name).to_string();
+            RomFile { data, path: path.with_file_name(file_name) }
+        }
+        None => RomFile { data, path: path.to_path_buf() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn アーカイブ内のromを選ぶ() {
+        let names = vec!["readme.txt".to_string(), "dir/game.nes".to_string()];
+        assert_eq!(select_entry(&names, None), Ok("dir/game.nes".to_string()));
+        assert_eq!(select_entry(&names, Some("game.nes")), Ok("dir/game.nes".to_string()));
+
+        let names = vec!["a.nes".to_string(), "b.fds".to_string()];
+        assert!(select_entry(&names, None).is_err());
+        assert_eq!(select_entry(&names, Some("B.FDS")), Ok("b.fds".to_string()));
+    }
+
+    #[test]
+    fn zipから展開する() {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"readme").unwrap();
+        zip.start_file("dir/game.nes", options).unwrap();
+        zip.write_all(&[0x4e; 0x100]).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let (name, buf) = read_zip(&data, None).unwrap();
+        assert_eq!(name, "dir/game.nes");
+        assert_eq!(buf, vec![0x4e; 0x100]);
+        let (_, buf) = read_zip(&data, Some("readme.txt")).unwrap();
+        assert_eq!(buf, b"readme");
+    }
+
+    #[test]
+    fn gzipから展開する() {
+        use std::io::Write;
+        let gzip = |name : Option<&str>| {
+            let mut builder = flate2::GzBuilder::new();
+            if let Some(name) = name {
+                builder = builder.filename(name);
+            }
+            let mut encoder = builder.write(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&[0x4e; 0x100]).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let (name, buf) = read_gzip(&gzip(Some("game.nes")), Path::new("dir/a.gz")).unwrap();
+        assert_eq!(name, "game.nes");
+        assert_eq!(buf, vec![0x4e; 0x100]);
+        // 元のファイル名がなければ拡張子を外す
+        let (name, _) = read_gzip(&gzip(None), Path::new("dir/game.nes.gz")).unwrap();
+        assert_eq!(name, "game.nes");
+    }
+}
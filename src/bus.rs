@@ -2,7 +2,7 @@ use std::{rc::Rc, cell::RefCell};
 
 use log::debug;
 
-use crate::{ppu::PPU, joypad::Joypad, apu_impl::ApuImpl, mapper::Mapper};
+use crate::{ppu::PPU, joypad::Joypad, apu_impl::ApuImpl, mapper::{Mapper, Mirroring}};
 
 #[derive(Debug)]
 pub struct Bus {
@@ -11,37 +11,39 @@ pub struct Bus {
     ram : Vec<u8>,
     pub joy_pad : Joypad,
     pub apu : ApuImpl,
+    // 命令の開始からメモリにアクセスするまでのCPUサイクル数 (アドレッシングモードによって変わる)
+    // CPUは命令単位で実行してからPPUを進めるので、PPUのレジスタへのアクセスはその位置まで先にPPUを進めておく
+    // 例えば LDA $2002 は4サイクル目に読むので3、LDA ($00),Y は5か6サイクル目なので4か5
+    pub access_cycle : usize,
 }
 
 impl Bus {
 
-    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>, is_mirror_horizontal: bool, sound_debug : bool, no_sound : bool) -> Self {
+    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>, mirroring: Mirroring, sound_debug : bool, no_sound : bool) -> Self {
         
 
         Bus { 
-            ppu: PPU::new(mapper.clone(), is_mirror_horizontal),
+            ppu: PPU::new(mapper.clone(), mirroring),
             mapper: mapper,
             ram: [0,0,0,0,0xff,0xff,0xff,0xff].repeat(0x100),
             joy_pad: Joypad::new(),
             apu : ApuImpl::new(sound_debug, no_sound),
+            access_cycle: 0,
         }
     }
 
     // https://www.nesdev.org/wiki/CPU_memory_map
     pub fn read(&mut self, addr: u16, is_debug: bool) -> u8 {
+        if (0x2000..=0x3fff).contains(&addr) && !is_debug {
+            self.ppu.catch_up(self.access_cycle * 3);
+        }
         match addr {
             0x0000 ..= 0x1fff => {
                 let addr = addr & 0x7fff;
                 self.ram[addr as usize]
             }
-            0x2000 => 0xff,
-            0x2001 => 0xff,
-            0x2002 => self.ppu.read_status(),
-            0x2003 => 0xff,
-            0x2004 => { println!("cant read {:#02x}", addr); panic!("not impl write addr"); },
-            0x2005 => 0xff,
-            0x2006 => 0xff,
-            0x2007 => { self.ppu.read_ppudata(!is_debug) },
+            // $2008-$3FFFは$2000-$2007のミラー
+            0x2000 ..= 0x3fff => self.ppu.read_register(addr & 0x2007, is_debug),
             0x4000 ..= 0x4015 => {
                 self.apu.read(addr, is_debug)
             }
@@ -53,12 +55,17 @@ impl Bus {
                 0x00
             }
             0x4020 ..= 0xffff => {
-                // mapper-0 prg
                 if addr >= 0x8000 {
                     self.mapper.borrow().read_prg(addr as usize)
                 } else {
-                    println!("cant read {:#02x}", addr);
-                    panic!("not impl read addr");
+                    // マッパーが何も返さない場合はオープンバス(直前のアドレスの上位バイト)
+                    // デバッグ用の読み出しでレジスタの状態が変わらないようにする
+                    let v = if is_debug {
+                        self.mapper.borrow().peek_expansion(addr)
+                    } else {
+                        self.mapper.borrow_mut().read_expansion(addr)
+                    };
+                    v.unwrap_or((addr >> 8) as u8)
                 }
             }
             _ => {
@@ -71,34 +78,19 @@ impl Bus {
     // https://www.nesdev.org/wiki/CPU_memory_map
     pub fn write(&mut self, addr: u16, value: u8) {
         // println!("write {:#04x}: {:#02x}", addr, value);
+        if (0x2000..=0x3fff).contains(&addr) {
+            self.ppu.catch_up(self.access_cycle * 3);
+        }
 
         match addr {
             0x0000 ..= 0x1fff => {
                 let addr = addr & 0x7fff;
                 self.ram[addr as usize] = value;
             }
-            0x2000 => {
-                self.ppu.write_ppuctrl(value);
-            }
-            0x2001 => {
-                self.ppu.ppumask = value;
-            }
-            0x2003 => {
-                self.ppu.write_ppu_sprite_addr(value);
-            }
-            0x2004 => {
-                self.ppu.write_ppu_sprite_data(value);
-            }
-            0x2005 => {
-                self.ppu.write_ppuscroll(value);
-            }
-            0x2006 => {
-                //println!(" write ppuaddr: {:#02x}", value);
-                self.ppu.write_ppuaddr(value);
-            }
-            0x2007 => {
-                //println!(" write ppudata: {:#02x}", value);
-                self.ppu.write_ppudata(value);
+            0x2000 ..= 0x3fff => {
+                let addr = addr & 0x2007;
+                self.ppu.write_register(addr, value);
+                self.mapper.borrow_mut().notify_cpu_write(addr, value);
             }
             0x4014 => {
                 // スプライトDMA
@@ -116,6 +108,9 @@ impl Bus {
                 // apu
                 debug!(" write apu register: {:#02x}", value);
             }
+            0x4018 ..= 0x7fff => {
+                self.mapper.borrow_mut().write_expansion(addr, value);
+            }
             _ => {
                 self.mapper.borrow_mut().write_prg(addr, value);
             }
@@ -131,11 +126,26 @@ impl Bus {
     }
 
     pub fn read_irq(&mut self) -> bool {
-        let v = self.apu.irq;
+        let v = self.apu.irq || self.mapper.borrow().irq();
         self.apu.irq = false;
         v
     }
 
+    // 拡張音源を持つマッパーはAPUと一緒に進める
+    pub fn step_apu(&mut self, cycle: usize) {
+        let mut mapper = self.mapper.borrow_mut();
+        self.apu.step(cycle, mapper.expansion_audio());
+    }
+
+    // CPUサイクルで動くIRQカウンタなどを進める
+    pub fn step_mapper(&mut self, cycle: usize) {
+        self.mapper.borrow_mut().step(cycle);
+    }
+
+    pub fn change_disk_side(&mut self) {
+        self.mapper.borrow_mut().change_disk_side();
+    }
+
     pub fn debug_prg_bytes(&mut self, addr: u16, l: usize) -> String {
         (addr .. (addr + (l as u16)))
             .map(|v|{ self.read(v, false) })
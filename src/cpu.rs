@@ -259,7 +259,7 @@ impl CPU {
     }
 
     pub fn int_irq(&mut self) -> usize {
-        self.intrrupt(0xfffa)
+        self.intrrupt(0xfffe)
     }
 
     pub fn intrrupt(&mut self, addr: u16) -> usize {
@@ -280,6 +280,8 @@ impl CPU {
         self.bus.write(sp, self.p);
         let sp = sp -1;
         self.s = (sp & 0xff) as u8;
+        // 割り込み中は次のIRQを受け付けない
+        self.p |= P_MASK_INT_DISABLE;
         
         self.cycle += 7;
         self.pc = handler;
@@ -678,7 +680,7 @@ impl CPU {
                 let (v, addr1, load_cycle) = self.load_(a, &mut l, true);
                 self.update_status_carry(v & 0x80 != 0);
                 let v = v.wrapping_shl(1);
-                let store_cycle = self.store(&addr1, v, None);
+                let store_cycle = self.store_rmw(&addr1, v);
                 self.update_status_zero(v);
                 self.update_status_negative(v);
                 load_cycle + store_cycle + 1
@@ -687,7 +689,7 @@ impl CPU {
                 let (v, addr1, load_cycle) = self.load_(a, &mut l, true);
                 self.update_status_carry(v & 0x01 != 0);
                 let v = v.wrapping_shr(1);
-                let store_cycle = self.store(&addr1, v, None);
+                let store_cycle = self.store_rmw(&addr1, v);
                 self.update_status_zero(v);
                 self.update_status_negative(v);
                 load_cycle + store_cycle + 1
@@ -695,7 +697,7 @@ impl CPU {
             Command::ROL(a) => {
                 let (v0, addr1, load_cycle) = self.load_(a, &mut l, true);
                 let v1 = v0.wrapping_shl(1) | (self.p & 0x01);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_carry(v0 & 0x80 != 0);
                 self.update_status_zero(v1);
                 self.update_status_negative(v1);
@@ -704,7 +706,7 @@ impl CPU {
             Command::ROR(a) => {
                 let (v0, addr1, load_cycle) = self.load_(a, &mut l, true);
                 let v1 = v0.wrapping_shr(1) | ((self.p & 0x01) << 7);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_carry(v0 & 0x01 != 0);
                 self.update_status_zero(v1);
                 self.update_status_negative(v1);
@@ -741,7 +743,7 @@ impl CPU {
             Command::DEC(a) => {
                 let (v0, addr1, load_cycle) = self.load_(a, &mut l, true);
                 let v1 = v0.wrapping_sub(1);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_zero(v1);
                 self.update_status_negative(v1);
                 load_cycle + store_cycle + 1
@@ -761,7 +763,7 @@ impl CPU {
             Command::INC(a) => {
                 let (v0, addr1, load_cycle) = self.load_(a, &mut l, true);
                 let v1 = v0.wrapping_add(1);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_zero(v1);
                 self.update_status_negative(v1);
                 load_cycle + store_cycle + 1
@@ -911,7 +913,7 @@ impl CPU {
             Command::DCP(a) => {
                 let (m, addr1, load_cycle) = self.load(a, &mut l);
                 let m = m.wrapping_sub(1);
-                let store_cycle = self.store(&addr1, m, None);
+                let store_cycle = self.store_rmw(&addr1, m);
                 let (v, _) = self.a.overflowing_sub(m);
                 self.update_status_carry(self.a >= m);
                 self.update_status_zero(v);
@@ -922,7 +924,7 @@ impl CPU {
                 let a = self.a;
                 let (b, addr1, load_cycle) = self.load(addr, &mut l);
                 let b = b.wrapping_add(1);
-                let store_cycle = self.store(&addr1, b, None);
+                let store_cycle = self.store_rmw(&addr1, b);
                 let c = self.p & P_MASK_CARRY;
                 let d = (a as u16).wrapping_sub(b  as u16).wrapping_sub((1 - c) as u16);
                 self.a = (d & 0xff) as u8;
@@ -938,7 +940,7 @@ impl CPU {
                 let (v, addr1, load_cycle) = self.load(addr, &mut l);
                 self.update_status_carry(v & 0x80 != 0);
                 let v = v.wrapping_shl(1);
-                let store_cycle = self.store(&addr1, v, None);
+                let store_cycle = self.store_rmw(&addr1, v);
 
                 self.a = v | self.a;
                 self.update_status_zero(self.a);
@@ -948,7 +950,7 @@ impl CPU {
             Command::RLA(addr) => {
                 let (v0,addr1,  load_cycle) = self.load(addr, &mut l);
                 let v1 = v0.wrapping_shl(1) | (self.p & 0x01);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_carry(v0 & 0x80 != 0);
 
                 self.a = v1 & self.a;
@@ -960,7 +962,7 @@ impl CPU {
                 let (v, addr1, load_cycle) = self.load(addr, &mut l);
                 self.update_status_carry(v & 0x01 != 0);
                 let v = v.wrapping_shr(1);
-                let store_cycle = self.store(&addr1, v, None);
+                let store_cycle = self.store_rmw(&addr1, v);
 
                 self.a = v ^ self.a;
                 self.update_status_zero(self.a);
@@ -970,7 +972,7 @@ impl CPU {
             Command::RRA(addr) => {
                 let (v0, addr1, load_cycle) = self.load(addr, &mut l);
                 let v1 = v0.wrapping_shr(1) | ((self.p & 0x01) << 7);
-                let store_cycle = self.store(&addr1, v1, None);
+                let store_cycle = self.store_rmw(&addr1, v1);
                 self.update_status_carry(v0 & 0x01 != 0);
 
                 let a = self.a;
@@ -1130,29 +1132,35 @@ impl CPU {
             }
             AddressingMode::ZeroPage(addr) => {
                 let addr = addr as u16;
+                self.bus.access_cycle = 2;
                 let v = self.read_byte(addr, false);
                 write!(l, "${:02X} = {:02X}", addr, v).unwrap();
                 (v, AddressingMode::Absolute(addr), 1)
             }
             AddressingMode::ZeroPageX(addr) => {
                 let addr1 = addr.wrapping_add(self.x) as u16;
+                self.bus.access_cycle = 3;
                 let v = self.read_byte(addr1, false);
                 write!(l, "${:02X},X @ {:02X} = {:02X}", addr, addr1, v).unwrap();
                 (v, AddressingMode::Absolute(addr1), 2)
             },
             AddressingMode::ZeroPageY(addr) => {
                 let addr1 = addr.wrapping_add(self.y) as u16;
+                self.bus.access_cycle = 3;
                 let v = self.read_byte(addr1, false);
                 write!(l, "${:02X},Y @ {:02X} = {:02X}", addr, addr1, v).unwrap();
                 (v, AddressingMode::Absolute(addr1), 2)
             },
             AddressingMode::Absolute(addr) => {
+                self.bus.access_cycle = 3;
                 let v = self.read_byte(addr, false);
                 write!(l, "${:04X} = {:02X}", addr, v).unwrap();
                 (v, AddressingMode::Absolute(addr), 1)
             },
             AddressingMode::AbsoluteX(addr) => {
                 let addr1 = addr.wrapping_add(self.x as u16);
+                // ページをまたぐ場合とリードモディファイライトは上位バイトを直すために1サイクル遅れる
+                self.bus.access_cycle = if addr.page() == addr1.page() && !is_store {3} else {4};
                 let v = self.read_byte(addr1, false);
                 write!(l, "${:04X},X @ {:04X} = {:02X}", addr, addr1, v).unwrap();
                 
@@ -1160,6 +1168,8 @@ impl CPU {
             },
             AddressingMode::AbsoluteY(addr) => {
                 let addr1 = addr.wrapping_add(self.y as u16);
+                // ページをまたぐ場合とリードモディファイライトは上位バイトを直すために1サイクル遅れる
+                self.bus.access_cycle = if addr.page() == addr1.page() && !is_store {3} else {4};
                 let v = self.read_byte(addr1, false);
                 write!(l, "${:04X},Y @ {:04X} = {:02X}", addr, addr1, v).unwrap();
                 (v, AddressingMode::Absolute(addr1), if addr.page() == addr1.page() && !is_store {1} else {2})
@@ -1168,6 +1178,7 @@ impl CPU {
             AddressingMode::IndirectX(m) => {
                 let addr = m.wrapping_add(self.x);
                 let addr1 = self.read_word_zeropage(addr);
+                self.bus.access_cycle = 5;
                 let v = self.read_byte(addr1, false);
                 write!(l, "(${:02X},X) @ {:02X} = {:04X} = {:02X}", m, addr, addr1, v).unwrap();
                 (v, AddressingMode::Absolute(addr1), 4)
@@ -1175,6 +1186,7 @@ impl CPU {
             AddressingMode::IndirectY(m) => {
                 let addr0 = self.read_word_zeropage(m);
                 let addr1 = addr0.wrapping_add(self.y as u16);
+                self.bus.access_cycle = if addr0.page() == addr1.page() && !is_store {4} else {5};
                 let v = self.read_byte(addr1, false);
                 write!(l, "(${:02X}),Y = {:04X} @ {:04X} = {:02X}", m, addr0, addr1, v).unwrap();
                 
@@ -1185,59 +1197,66 @@ impl CPU {
         }
     }
 
+    // 書き込む前の値はログを取る場合のみ読む
     fn store(&mut self, addr_mode: &AddressingMode, v : u8, l: Option<&mut String>) -> usize {
         match *addr_mode {
             AddressingMode::Accumelator => { self.a = v; 0 },
             AddressingMode::Imm(_) => { self.a = v; 0 },
             AddressingMode::ZeroPage(addr) => {
-                let old = self.read_byte(addr as u16, true);
                 if let Some(l) = l {
+                    let old = self.read_byte(addr as u16, true);
                     write!(l, "${:02X} = {:02X}", addr, old).unwrap();
                 }
+                self.bus.access_cycle = 2;
                 self.write_byte(addr as u16, v);
                 1
             }
             AddressingMode::ZeroPageX(addr) => {
                 let addr1 = addr.wrapping_add(self.x) as u16;
-                let old_v = self.read_byte(addr1, true);
-                self.write_byte(addr1, v);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "${:02X},X @ {:02X} = {:02X}", addr, addr1, old_v).unwrap();
                 }
+                self.bus.access_cycle = 3;
+                self.write_byte(addr1, v);
                 2
             },
             AddressingMode::ZeroPageY(addr) => {
                 let addr1 = addr.wrapping_add(self.y) as u16;
-                let old_v = self.read_byte(addr1, true);
-                self.write_byte(addr1, v);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "${:02X},Y @ {:02X} = {:02X}", addr, addr1, old_v).unwrap();
                 }
+                self.bus.access_cycle = 3;
+                self.write_byte(addr1, v);
                 2
             },
             AddressingMode::Absolute(addr) => {
-                let old = self.read_byte(addr as u16, true);
-                self.write_byte(addr, v);
                 if let Some(l) = l {
+                    let old = self.read_byte(addr as u16, true);
                     write!(l, "${:04X} = {:02X}", addr, old).unwrap();
                 }
+                self.bus.access_cycle = 3;
+                self.write_byte(addr, v);
                 1
             },
             AddressingMode::AbsoluteX(addr) => {
                 let addr1 = addr.wrapping_add(self.x as u16);
-                let old_v = self.read_byte(addr1, true);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "${:04X},X @ {:04X} = {:02X}", addr, addr1, old_v).unwrap();
                 }
+                self.bus.access_cycle = 4;
                 self.write_byte(addr1, v);
                 2
             },
             AddressingMode::AbsoluteY(addr) => {
                 let addr1 = addr.wrapping_add(self.y as u16);
-                let old_v = self.read_byte(addr1, true);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "${:04X},Y @ {:04X} = {:02X}", addr, addr1, old_v).unwrap();
                 }
+                self.bus.access_cycle = 4;
                 self.write_byte(addr1, v);
                 2
             },
@@ -1245,21 +1264,22 @@ impl CPU {
             AddressingMode::IndirectX(m) => {
                 let addr = m.wrapping_add(self.x);
                 let addr1 = self.read_word_zeropage(addr);
-                let old_v = self.read_byte(addr1, true);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "(${:02X},X) @ {:02X} = {:04X} = {:02X}", m, addr, addr1, old_v).unwrap();
                 }
-
+                self.bus.access_cycle = 5;
                 self.write_byte(addr1, v);
                 4
             },
             AddressingMode::IndirectY(m) => {
                 let addr0 = self.read_word_zeropage(m);
                 let addr1 = addr0.wrapping_add(self.y as u16);
-                let old_v = self.read_byte(addr1, true);
                 if let Some(l) = l {
+                    let old_v = self.read_byte(addr1, true);
                     write!(l, "(${:02X}),Y = {:04X} @ {:04X} = {:02X}", m, addr0, addr1, old_v).unwrap();
                 }
+                self.bus.access_cycle = 5;
                 self.write_byte(addr1, v);
                 4
             },
@@ -1267,6 +1287,21 @@ impl CPU {
         }
     }
 
+    // リードモディファイライト命令の書き込み。どのアドレッシングモードでも読み込みの2サイクル後に書き込む
+    // (間のサイクルで元の値を書き戻すのは省略している)
+    // addr_modeはloadが返した実効アドレス (Absolute) かアキュムレータ
+    fn store_rmw(&mut self, addr_mode: &AddressingMode, v : u8) -> usize {
+        match *addr_mode {
+            AddressingMode::Accumelator => { self.a = v; 0 },
+            AddressingMode::Absolute(addr) => {
+                self.bus.access_cycle += 2;
+                self.write_byte(addr, v);
+                1
+            }
+            _ => panic!("store rmw"),
+        }
+    }
+
     pub fn step_next(&mut self, log : &mut CpuDebugLog) -> usize {
         if self.bus.read_nmi() {
             //println!("interruption nmi");
@@ -1378,4 +1413,91 @@ impl Address for u16 {
     fn page(&self) -> u8 {
         (*self >> 8) as u8
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+
+    use super::*;
+    use crate::mapper::{new_mapper, Mirroring};
+    use crate::rom_header::RomHeader;
+
+    // PRGの最後のバンク($E000)にプログラムを置く
+    fn new_cpu(mapper : u16, program : &[u8]) -> CPU {
+        let mut prg = vec![0xea; 0x8000];
+        prg[0x6000..0x6000 + program.len()].copy_from_slice(program);
+        let header = RomHeader { mapper, ..Default::default() };
+        let mapper = Rc::new(RefCell::new(new_mapper(&header, prg, vec![]).unwrap()));
+        let mut cpu = CPU::new(Bus::new(mapper, Mirroring::Vertical, false, true));
+        cpu.pc = 0xe000;
+        cpu
+    }
+
+    // プログラムを最後まで実行する
+    fn run(mapper : u16, program : &[u8]) -> CPU {
+        let mut cpu = new_cpu(mapper, program);
+        let mut log = CpuDebugLog::new();
+        while (cpu.pc as usize) < 0xe000 + program.len() {
+            cpu.step_next(&mut log);
+        }
+        cpu
+    }
+
+    #[test]
+    fn ストア命令で拡張レジスタの読み出しの副作用が起きない() {
+        // N163の$4800は読み書きでアドレスが自動で進む
+        let mut cpu = run(19, &[
+            0xa9, 0x80, 0x8d, 0x00, 0xf8, // LDA #$80; STA $F800
+            0xa9, 0x11, 0x8d, 0x00, 0x48, // LDA #$11; STA $4800
+            0xa9, 0x22, 0x8d, 0x00, 0x48, // LDA #$22; STA $4800
+        ]);
+        cpu.bus.write(0xf800, 0x80);
+        assert_eq!(cpu.bus.read(0x4800, false), 0x11);
+        assert_eq!(cpu.bus.read(0x4800, false), 0x22);
+    }
+
+    #[test]
+    fn ppuのレジスタにアクセスするサイクルまでppuを進める() {
+        // 1命令だけ実行して、PPUがどこまで進んだかを見る
+        let dots = |program : &[u8], y : u8| {
+            let mut cpu = new_cpu(0, program);
+            cpu.x = y;
+            cpu.y = y;
+            // ($10) = $20FD
+            cpu.bus.write(0x10, 0xfd);
+            cpu.bus.write(0x11, 0x20);
+            cpu.step_next(&mut CpuDebugLog::new());
+            cpu.bus.ppu.x_()
+        };
+        // LDA $2002 は4サイクル目
+        assert_eq!(dots(&[0xad, 0x02, 0x20], 0), 3 * 3);
+        // LDA abs,X はページをまたぐと5サイクル目
+        assert_eq!(dots(&[0xbd, 0x02, 0x20], 0), 3 * 3);
+        assert_eq!(dots(&[0xbd, 0xff, 0x20], 3), 4 * 3);
+        // STA $2000,X は常に5サイクル目
+        assert_eq!(dots(&[0x9d, 0x00, 0x20], 0), 4 * 3);
+        // LDA ($10),Y は5サイクル目、ページをまたぐと6サイクル目
+        assert_eq!(dots(&[0xb1, 0x10], 0), 4 * 3);
+        assert_eq!(dots(&[0xb1, 0x10], 3), 5 * 3);
+        // LDA ($10,X) は6サイクル目
+        assert_eq!(dots(&[0xa1, 0x10], 0), 5 * 3);
+        // INC $2000 は4サイクル目に読んで6サイクル目に書く。2回分進めない
+        assert_eq!(dots(&[0xee, 0x00, 0x20], 0), 5 * 3);
+        // INC $2000,X は5サイクル目に読んで7サイクル目に書く
+        assert_eq!(dots(&[0xfe, 0x00, 0x20], 0), 6 * 3);
+    }
+
+    #[test]
+    fn sta_2007で読み出しバッファが変わらない() {
+        let cpu = run(0, &[
+            0xa9, 0x20, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, // $2006 = $2000
+            0xa9, 0x55, 0x8d, 0x07, 0x20, 0xa9, 0x66, 0x8d, 0x07, 0x20, // $2000 = $55, $2001 = $66
+            0xa9, 0x20, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, // $2006 = $2000
+            0xad, 0x07, 0x20, // LDA $2007 (バッファに$55が入る)
+            0x8d, 0x07, 0x20, // STA $2007 ($2001に書き込む)
+            0xad, 0x07, 0x20, // LDA $2007
+        ]);
+        assert_eq!(cpu.a, 0x55);
+    }
+}
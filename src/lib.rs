@@ -4,4 +4,13 @@ pub mod ppu;
 pub mod joypad;
 pub mod hex;
 pub mod apu_impl;
-pub mod mapper;
\ No newline at end of file
+pub mod mapper;
+pub mod rom_header;
+pub mod archive;
+pub mod patch;
+pub mod rom_db;
+// build.rsと共有しているデータベースの読み込み。テストのためにライブラリにも含める
+#[cfg(test)]
+mod rom_db_xml;
+pub mod palette;
+pub mod unif;
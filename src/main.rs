@@ -1,13 +1,18 @@
 use std::borrow::BorrowMut;
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
 
-use famiko::mapper::new_mapper;
+use apu::namco163::Namco163Mixing;
+use famiko::mapper::{new_mapper, parse_fds_image, Fds, Mapper};
+use famiko::archive::{load_rom_file, RomFile};
+use famiko::patch::apply_patch;
+use famiko::{rom_db, unif};
+use famiko::palette::{Palette, PRESET_NAMES};
+use famiko::rom_header::{Rom, RomHeader};
 use famiko::{joypad, joypad::PadKey};
 use pixels::{Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
@@ -18,7 +23,7 @@ use winit_input_helper::WinitInputHelper;
 
 use famiko::cpu::{CPU, CpuDebugLog, CPU_CLOCK_UNIT_NSEC};
 use famiko::bus::Bus;
-use famiko::ppu::{WIDTH, HEIGHT, CHR_DEBUG_FRAME_SIZE, CHR_DEBUG_WIDTH, CHR_DEBUG_HEIGT, SPRITE_DEBUG_WIDTH, SPRITE_DEBUG_HEIGT};
+use famiko::ppu::{WIDTH, HEIGHT, FRAME_SIZE, CHR_DEBUG_FRAME_SIZE, CHR_DEBUG_WIDTH, CHR_DEBUG_HEIGT, SPRITE_DEBUG_WIDTH, SPRITE_DEBUG_HEIGT, SPRITE_DEBUG_FRAME_SIZE};
 use clap::{arg, Command, Arg, ArgAction};
 use hex;
 
@@ -30,6 +35,16 @@ enum RenderEvent {
     SpriteRender(Vec<u8>),
 }
 
+// UIスレッドからエミュレータへの操作
+#[derive(Debug)]
+enum EmuCommand {
+    ChangeDiskSide,
+    // 組み込みのパレットを順に切り替える
+    NextPalette,
+    // 終了前にセーブデータを書き出す
+    Quit,
+}
+
 #[derive(Debug)]
 struct FpsCounter {
     start_time : Instant,
@@ -95,6 +110,39 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .action(ArgAction::SetTrue)
                 .help("fps出力")
         )
+        .arg(
+            Arg::new("no-sprite-limit")
+                .long("no-sprite-limit")
+                .action(ArgAction::SetTrue)
+                .help("1ライン8個のスプライトの制限をなくす (ちらつき軽減)")
+        )
+        .arg(
+            Arg::new("no-db")
+                .long("no-db")
+                .action(ArgAction::SetTrue)
+                .help("ROMデータベースによるヘッダの補正をしない")
+        )
+        .arg(
+            Arg::new("patch")
+                .long("patch")
+                .takes_value(true)
+                .action(ArgAction::Append)
+                .help("IPS/UPS/BPSパッチ。複数指定すると順に当てる (省略時はROMと同じ名前のパッチ)")
+        )
+        .arg(
+            Arg::new("palette")
+                .long("palette")
+                .takes_value(true)
+                .help("パレット (.palファイルまたは famiko, ntsc, ntsc-vivid)。F2で切り替え")
+        )
+        .arg(
+            Arg::new("n163-mixing")
+                .long("n163-mixing")
+                .takes_value(true)
+                .value_parser(["average", "multiplex"])
+                .help("N163の拡張音源の出力方法。multiplexは実機と同じくチャンネルを切り替えて出力する")
+        )
+        .arg(arg!(--bios [file] "ディスクシステムのBIOS (省略時はROMと同じディレクトリのdisksys.rom)"))
         .arg(arg!([rom] "rom").help("ROMファイル"))
         .get_matches();
     
@@ -107,6 +155,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         None
     };
     let file = matches.get_one::<String>("rom").unwrap();
+    // zip, 7z, gzipの場合は中のROMを展開する
+    let RomFile { data: mut rom, path: rom_path } = load_rom_file(file)?;
+
+    // パッチはヘッダを含むファイル全体に当てる
+    let patches = match matches.get_many::<String>("patch") {
+        Some(v) => v.map(PathBuf::from).collect::<Vec<_>>(),
+        None => ["ips", "ups", "bps"].iter()
+            .map(|e| rom_path.with_extension(e))
+            .filter(|p| p.exists())
+            .take(1)
+            .collect(),
+    };
+    for patch_path in patches {
+        let patch = std::fs::read(&patch_path).map_err(|e| format!("{}: {}", patch_path.display(), e))?;
+        rom = apply_patch(&rom, &patch).map_err(|e| format!("{}: {}", patch_path.display(), e))?;
+        println!("patched {}", patch_path.display());
+    }
+    let bios_file = matches.get_one::<String>("bios").map(|v| Path::new(v).to_path_buf())
+        .unwrap_or_else(|| rom_path.with_file_name("disksys.rom"));
+    let is_fds_file = rom_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("fds"));
     let debug = matches.get_one::<bool>("debug").map_or(false, |v| *v);
     let sound_debug = matches.get_one::<bool>("sound-debug").map_or(false, |v| *v);
     let no_sound = matches.get_one::<bool>("no-sound").map_or(false, |v| *v);
@@ -114,19 +182,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let show_name_table = matches.get_one::<bool>("show-name-table").map_or(false, |v| *v);
     let show_sprite = matches.get_one::<bool>("show-sprite").map_or(false, |v| *v);
     let is_show_fps = matches.get_one::<bool>("fps").map_or(false, |v| *v);
+    let no_db = matches.get_one::<bool>("no-db").map_or(false, |v| *v);
+    let no_sprite_limit = matches.get_one::<bool>("no-sprite-limit").map_or(false, |v| *v);
+    let n163_mixing = match matches.get_one::<String>("n163-mixing").map(|v| v.as_str()) {
+        Some("multiplex") => Namco163Mixing::Multiplex,
+        _ => Namco163Mixing::Average,
+    };
 
-    let mut file = File::open(file)?;
-    let mut rom = Vec::new();
-    
-    let _ = file.read_to_end(&mut rom)?;
-    // println!("{:?}", buf);
+    // 指定したパレットの後に組み込みのパレットを並べて、F2で順に切り替える
+    let mut palettes = PRESET_NAMES.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    if let Some(name) = matches.get_one::<String>("palette") {
+        Palette::from_name(name)?;
+        palettes.retain(|v| v != name);
+        palettes.insert(0, name.clone());
+    }
 
-    let h = parse_header(&rom).unwrap();
+    // バッテリーバックアップのRAMはROMと同じ名前の.savに保存する
+    let save_path = rom_path.with_extension("sav");
 
-    let mut p : usize = 16;
-    let prg_rom = Vec::from(&rom[p .. p + h.prg_size]);
-    p += h.prg_size;
-    let chr_rom = Vec::from(&rom[p .. p+h.chr_size]);
+    // ディスクシステムのイメージはBIOSと一緒に読み込む
+    let is_fds = is_fds_file || rom.starts_with(b"FDS\x1a") || rom.starts_with(b"\x01*NINTENDO-HVC*");
+    let fds = if is_fds {
+        let sides = parse_fds_image(&rom)?;
+        let bios = std::fs::read(&bios_file).map_err(|e| format!("{}: {}", bios_file.display(), e))?;
+        if bios.len() != 0x2000 {
+            return Err(format!("{}: invalid bios size {}", bios_file.display(), bios.len()).into());
+        }
+        Some((bios, sides))
+    } else {
+        None
+    };
+
+    // ディスクシステムはiNESヘッダを持たない。書き込みは差分ファイルに保存するのでバッテリーありとして扱う
+    let Rom { header: mut h, trainer, prg: prg_rom, chr: chr_rom } = if is_fds {
+        Rom { header: RomHeader { has_battery: true, ..Default::default() }, trainer: None, prg: vec![], chr: vec![] }
+    } else if unif::is_unif(&rom) {
+        let unif = unif::parse_unif(&rom)?;
+        println!("{} ({})", unif.name.as_deref().unwrap_or(""), unif.board);
+        unif.rom
+    } else {
+        Rom::parse(&rom)?
+    };
+    // ヘッダが間違っているダンプはデータベースの内容で補正する
+    if !is_fds {
+        if !no_db && !rom_db::is_available() {
+            println!("warning: rom database is empty, see db/README.md");
+        }
+        if let Some(entry) = rom_db::find(&prg_rom, &chr_rom) {
+            println!("{} ({})", entry.title, entry.board);
+            if !no_db {
+                entry.apply(&mut h);
+            }
+        }
+    }
 
     // println!("{:?}", h);
     // println!("{:?}", prg_rom.hex_dump());
@@ -135,16 +243,51 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // 画面情報をUIスレッドに転送するチャネル
     let (render_sender, render_receiver) = mpsc::channel::<RenderEvent>();
+    // 描画が終わった画面のバッファをエミュレータスレッドに返すチャネル
+    let (frame_buffer_sender, frame_buffer_receiver) = mpsc::channel::<Vec<u8>>();
 
     // キー情報をUIスレッドから転送するチャネル
     let (key_sender, key_receiver) = mpsc::channel::<(PadKey, bool)>();
+    let (command_sender, command_receiver) = mpsc::channel::<EmuCommand>();
+    // エミュレータスレッドの終了通知
+    let (quit_sender, quit_receiver) = mpsc::channel::<()>();
+    // マッパーを作れたかどうかの通知 (マッパーはスレッド間で送れないのでエミュレータスレッドで作る)
+    let (ready_sender, ready_receiver) = mpsc::channel::<Result<(), String>>();
 
 
     thread::spawn(move ||{
-        let mapper = Rc::new(RefCell::new(new_mapper(h.mapper, prg_rom, chr_rom)));
+        let mapper : Result<Box<dyn Mapper>, String> = match fds {
+            Some((bios, sides)) => Ok(Box::new(Fds::new(bios, sides))),
+            None => new_mapper(&h, prg_rom, chr_rom),
+        };
+        let mut mapper = match mapper {
+            Ok(mapper) => mapper,
+            Err(e) => {
+                let _ = ready_sender.send(Err(e));
+                return;
+            }
+        };
+        let _ = ready_sender.send(Ok(()));
+        mapper.set_namco163_mixing(n163_mixing);
+        // トレーナーは$7000-$71FFのRAMに置く。セーブデータがあればそちらが優先
+        if let Some(trainer) = &trainer {
+            mapper.load_trainer(trainer);
+        }
+        let is_battery = h.has_battery;
+        if is_battery {
+            if let Ok(data) = std::fs::read(&save_path) {
+                mapper.load_battery_ram(&data);
+            }
+        }
+        let mut battery = BatterySave::new(save_path, mapper.battery_ram());
+        let mapper = Rc::new(RefCell::new(mapper));
+        let mut save_frame_count = 0;
 
-        let bus = Bus::new(mapper, h.flag6 & 1 == 0, sound_debug, no_sound);
+        let bus = Bus::new(mapper, h.name_table_mirroring(), sound_debug, no_sound);
         let mut cpu = CPU::new(bus);
+        cpu.bus.ppu.remove_sprite_limit = no_sprite_limit;
+        let mut palette_index = 0;
+        let mut palette = Palette::from_name(&palettes[palette_index]).unwrap();
 
         // apu開始
         _ = cpu.bus.apu.start();
@@ -172,9 +315,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             if debug {
                 log.log();
             }
-            let frame_ = cpu.bus.ppu.step(cycle*3);
+            let is_frame_ready = cpu.bus.ppu.step(cycle*3);
 
-            cpu.bus.apu.step(cycle);
+            cpu.bus.step_apu(cycle);
+            cpu.bus.step_mapper(cycle);
 
             elapsed_time += (cycle as u128) * CPU_CLOCK_UNIT_NSEC;
             let actual = Instant::now().duration_since(time_base).as_nanos();
@@ -183,23 +327,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 sleep(Duration::from_nanos(t as u64));
             }
 
-            if let Some(f) = frame_ {
-
-                render_sender.send(RenderEvent::Render(*f)).unwrap();
+            if is_frame_ready {
+                // 描画済みのバッファが戻ってきていれば使い回す
+                let mut f = frame_buffer_receiver.try_recv().unwrap_or_else(|_| vec![0u8; FRAME_SIZE * 4]);
+                cpu.bus.ppu.render_frame(&palette, &mut f);
+                render_sender.send(RenderEvent::Render(f)).unwrap();
+
+                // 1秒ごとに変更があれば保存する
+                save_frame_count += 1;
+                if is_battery && save_frame_count >= 60 {
+                    save_frame_count = 0;
+                    battery.save(cpu.bus.mapper.borrow().battery_ram());
+                }
 
                 if show_chr_table {
                     let mut draw_chr_frame = [0u8].repeat(CHR_DEBUG_FRAME_SIZE*4);
-                    cpu.bus.ppu.draw_chr(draw_chr_frame.as_mut_slice());
+                    cpu.bus.ppu.draw_chr(draw_chr_frame.as_mut_slice(), &palette);
                     render_sender.send(RenderEvent::ChrTableRender(draw_chr_frame)).unwrap();
                 }
                 if show_name_table {
                     let draw_name_frame = RefCell::new(vec![0u8;256*240*4*4]);
-                    cpu.bus.ppu.draw_name_table(&draw_name_frame);
+                    cpu.bus.ppu.draw_name_table(&draw_name_frame, &palette);
                     render_sender.send(RenderEvent::NameTableRender(draw_name_frame)).unwrap();
                 }
                 if show_sprite {
-                    let mut frame = Some([0u8].repeat(64*64*4));
-                    cpu.bus.ppu.write_sprite(&mut frame);
+                    let mut frame = Some([0u8].repeat(SPRITE_DEBUG_FRAME_SIZE*4));
+                    cpu.bus.ppu.write_sprite(&mut frame, &palette);
                     render_sender.send(RenderEvent::SpriteRender(frame.unwrap())).unwrap();
                 }
 
@@ -214,11 +367,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if let Ok((k, b)) = key_receiver.try_recv() {
                     cpu.bus.joy_pad.update_key(k, b);
                 }
+                match command_receiver.try_recv() {
+                    Ok(EmuCommand::ChangeDiskSide) => cpu.bus.change_disk_side(),
+                    Ok(EmuCommand::NextPalette) => {
+                        palette_index = (palette_index + 1) % palettes.len();
+                        match Palette::from_name(&palettes[palette_index]) {
+                            Ok(p) => {
+                                println!("palette {}", palettes[palette_index]);
+                                palette = p;
+                            }
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                    Ok(EmuCommand::Quit) => {
+                        if is_battery {
+                            battery.save(cpu.bus.mapper.borrow().battery_ram());
+                        }
+                        let _ = quit_sender.send(());
+                        break;
+                    }
+                    _ => {}
+                }
             }
         };
     });
 
 
+    ready_receiver.recv()??;
+
     // 画面表示
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
@@ -290,9 +466,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
             }
+            // 終了時はエミュレータスレッドがセーブを書き出すのを待つ
+            Event::LoopDestroyed => {
+                let _ = command_sender.send(EmuCommand::Quit)
+                    .map(|_| quit_receiver.recv_timeout(Duration::from_secs(1)));
+            }
             Event::MainEventsCleared => {
                 match render_receiver.try_recv() {
-                    Ok(RenderEvent::Render(buffer)) => pixels.get_frame().copy_from_slice(buffer.as_slice()),
+                    Ok(RenderEvent::Render(buffer)) => {
+                        pixels.get_frame().copy_from_slice(buffer.as_slice());
+                        let _ = frame_buffer_sender.send(buffer);
+                    }
                     Ok(RenderEvent::ChrTableRender(buffer)) => {
                         if let Some((_, p)) = chr_table_window.borrow_mut() {
                             p.get_frame().copy_from_slice(buffer.as_slice());
@@ -342,6 +526,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
+            // ディスクの面の切り替え
+            if input.key_pressed(VirtualKeyCode::F1) {
+                command_sender.send(EmuCommand::ChangeDiskSide).unwrap();
+            }
+            // パレットの切り替え
+            if input.key_pressed(VirtualKeyCode::F2) {
+                command_sender.send(EmuCommand::NextPalette).unwrap();
+            }
+
             // Update internal state and request a redraw
             window.request_redraw();
             name_table_window.as_ref().map(|(x, _)| { x.request_redraw() });
@@ -351,6 +544,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 }
 
+// バッテリーバックアップのRAMを.savに書き出す。前回から変更がない場合は書かない
+struct BatterySave {
+    path : PathBuf,
+    saved : Option<Vec<u8>>,
+}
+
+impl BatterySave {
+    fn new(path : PathBuf, saved : Option<Vec<u8>>) -> Self {
+        Self {
+            path,
+            saved,
+        }
+    }
+
+    fn save(&mut self, ram : Option<Vec<u8>>) {
+        if ram == self.saved {
+            return;
+        }
+        if let Some(data) = &ram {
+            if let Err(e) = std::fs::write(&self.path, data) {
+                println!("save error {:?}", e);
+            }
+        }
+        self.saved = ram;
+    }
+}
+
 fn create_window<T>(
     title: String,
      w: u32,
@@ -370,54 +590,3 @@ fn create_window<T>(
 
     Ok((win, p))
 }
-
-#[allow(dead_code)]
-#[derive(Debug)]
-struct NesHeader {
-    prg : u8,
-    prg_size : usize,
-    chr : u8,
-    chr_size : usize,
-    flag6 : u8,
-    // flag6
-    // 76543210
-    // ||||||||
-    // |||||||+- Mirroring: 0: horizontal (vertical arrangement) (CIRAM A10 = PPU A11)
-    // |||||||              1: vertical (horizontal arrangement) (CIRAM A10 = PPU A10)
-    // ||||||+-- 1: Cartridge contains battery-backed PRG RAM ($6000-7FFF) or other persistent memory
-    // |||||+--- 1: 512-byte trainer at $7000-$71FF (stored before PRG data)
-    // ||||+---- 1: Ignore mirroring control or above mirroring bit; instead provide four-screen VRAM
-    // ++++----- Lower nybble of mapper number
-    flag7 : u8,
-    mapper : u8,
-    trainer_exist : bool,
-}
-
-fn parse_header(buf : &[u8]) -> Result<Box<NesHeader>, Box<dyn std::error::Error>> {
-    
-    if buf.len() < 4 {
-        panic!("header size error");
-    }
-
-    if buf[0] != 'N' as u8 || buf[1] != 'E' as u8 || buf[2] != 'S' as u8 || buf[3] != 0x1A {
-        panic!("constant bytes error");
-    }
-
-    let prg = buf[4];
-    let chr = buf[5];
-    let flag6 = buf[6];
-    let flag7 = buf[7];
-
-    let mapper = ((flag6 >> 4) & 0x0f) | (flag7 & 0xf0); 
-
-    Ok(Box::new(NesHeader{
-        prg : prg,
-        prg_size: prg as usize * 16 * 1024,
-        chr : chr,
-        chr_size: chr as usize * 8 * 1024,
-        flag6 : flag6,
-        flag7 : flag7,
-        mapper : mapper,
-        trainer_exist : flag6 & 0x40 != 0
-    }))
-}
@@ -1,28 +1,213 @@
 use std::{fmt::Debug, ops::Range};
 
+use crate::rom_header::{HeaderFormat, RomHeader};
 
+use apu::{ExpansionAudio, namco163::Namco163Mixing};
+
+mod bandai;
+mod eeprom;
+mod fds;
+mod fme7;
+mod mmc2;
+mod mmc5;
+mod namco163;
+mod prg_ram;
+mod unrom512;
+mod vrc_irq;
+mod vrc4;
+mod vrc6;
+mod vrc7;
+
+use bandai::Bandai;
+use fme7::Fme7;
+use mmc2::Mmc2;
+use mmc5::Mmc5;
+use namco163::Namco163;
+use prg_ram::PrgRam;
+use unrom512::Unrom512;
+use vrc4::Vrc4;
+use vrc6::Vrc6;
+use vrc7::Vrc7;
+
+pub use fds::{Fds, parse_fds_image};
+
+// https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+    // $2000/$2400/$2800/$2C00 がそれぞれどの1Kを使うか (MMC5など)
+    Custom([usize; 4]),
+}
+
+impl Mirroring {
+    // $2000-$2FFFのアドレスを4画面分のネームテーブル内のオフセットに変換する
+    pub fn name_table_offset(&self, addr: usize) -> usize {
+        let a = addr & 0x0fff;
+        match self {
+            Mirroring::Horizontal => a & !0x400,
+            Mirroring::Vertical => a & !0x800,
+            Mirroring::SingleScreenLower => a & 0x3ff,
+            Mirroring::SingleScreenUpper => a & 0x3ff | 0x400,
+            Mirroring::FourScreen => a,
+            Mirroring::Custom(pages) => pages[(a >> 10) & 3] * 0x400 | a & 0x3ff,
+        }
+    }
+}
+
+// 描画中のPPUが何を読み出しているか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuFetch {
+    Background,
+    Sprite,
+}
+
+// カートリッジ基板
+// 必須なのはPRG($8000-$FFFF)とCHR($0000-$1FFF)の読み書きだけで、
+// IRQ、拡張音源、PPUのバスの監視などは必要な基板だけがデフォルト実装を上書きする
 pub trait Mapper : Debug {
+    // --- CPUバス ---
+
     fn read_prg(&self, addr: usize) -> u8;
     fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8];
+    // $8000-$FFFFへの書き込み (write_expansionを上書きしない基板は$4018-$7FFFもここに来る)
     fn write_prg(&mut self, addr: u16, v: u8);
 
+    // $4020-$7FFFの読み出し。Noneの場合はオープンバスになる
+    // 読み出しでフラグが落ちるなどの副作用があるレジスタを持つ基板だけが上書きする
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        self.peek_expansion(addr)
+    }
+
+    // 副作用のない$4020-$7FFFの読み出し。デバッグ表示などの読み出しはこちらを使う
+    fn peek_expansion(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // $4018-$7FFFへの書き込み
+    fn write_expansion(&mut self, addr: u16, v: u8) {
+        self.write_prg(addr, v);
+    }
+
+    // CPUからPPUレジスタ($2000-$2007)への書き込みを監視する
+    fn notify_cpu_write(&mut self, _addr: u16, _v: u8) {}
+
+    // --- PPUバス ---
+
     fn read_chr(&self, addr: usize) -> u8;
     fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8];
     fn write_chr(&mut self, addr: u16, v: u8);
-}
 
-pub fn new_mapper(n : u8, prg : Vec::<u8>, chr: Vec::<u8>) -> Box::<dyn Mapper> {
-    match n {
-        0 => Box::new(Mapper0::new(prg, chr)),
-        2 => Box::new(Mapper2::new(prg, chr)),
-        3 => Box::new(Mapper3::new(prg, chr)),
-        _ => panic!("not impl {:}", n)
+    // PPUがバスから読み出したアドレスを読み出した順に通知する
+    // MMC2/MMC4のようにパターンの読み出しでバンクが切り替わるマッパー用
+    fn notify_ppu_read(&mut self, _addr: u16) {}
+
+    // マッパーがミラーリングを制御する場合に返す。Noneの場合はヘッダの設定を使う
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // PPUが次のラインの先読みを始める時 (前のラインの321ドット) に、そのライン番号で呼ばれる
+    fn notify_ppu_scanline(&mut self, _line: usize) {}
+
+    // PPUが背景とスプライトのどちらを読み出し始めたか
+    fn notify_ppu_fetch(&mut self, _fetch: PpuFetch) {}
+
+    // ネームテーブルをマッパー側で差し替える場合にSomeを返す
+    fn read_name_table(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // ネームテーブルへの書き込みをマッパー側で処理した場合にtrueを返す
+    fn write_name_table(&mut self, _addr: u16, _v: u8) -> bool {
+        false
     }
+
+    // --- クロック、IRQ、音声 ---
+
+    // CPUの1クロックごとに呼ばれる (CPUサイクルで動くIRQカウンタなど)
+    fn step_cycle(&mut self) {}
+
+    fn step(&mut self, cycle: usize) {
+        for _ in 0..cycle {
+            self.step_cycle();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    // N163の拡張音源の複数チャンネルの出力方法
+    fn set_namco163_mixing(&mut self, _mixing: Namco163Mixing) {}
+
+    // --- 保存 ---
+
+    // バッテリーバックアップされているRAMの内容。保存の必要がない場合はNone
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn load_battery_ram(&mut self, _data: &[u8]) {}
+
+    // ステートセーブ用にレジスタやRAMの状態をバイト列にする。空の場合は未対応
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _data: &[u8]) {}
+
+    // ディスクシステムの面を切り替える
+    fn change_disk_side(&mut self) {}
+
+    // トレーナー (512バイト) を$7000-$71FFのRAMに置く。RAMがない基板では捨てる
+    fn load_trainer(&mut self, _data: &[u8]) {}
+}
+
+// 未対応のマッパーの場合はエラー
+pub fn new_mapper(header : &RomHeader, prg : Vec::<u8>, chr: Vec::<u8>) -> Result<Box::<dyn Mapper>, String> {
+    let submapper = header.submapper;
+    let prg_ram_size = header.total_prg_ram_size();
+    let n = u8::try_from(header.mapper).map_err(|_| format!("mapper {} is not supported", header.mapper))?;
+    let mapper : Box::<dyn Mapper> = match n {
+        0 => Box::new(Mapper0::new(prg, chr, prg_ram_size)),
+        2 => Box::new(Mapper2::new(prg, chr, prg_ram_size)),
+        3 => Box::new(Mapper3::new(prg, chr, prg_ram_size)),
+        // iNESのヘッダではRAMのサイズが当てにならないので、最大の64Kにする
+        5 => Box::new(Mmc5::new(prg, chr, if header.format == HeaderFormat::Nes20 { prg_ram_size } else { 0x10000 })),
+        9 => Box::new(Mmc2::new(prg, chr, prg_ram_size, false)),
+        10 => Box::new(Mmc2::new(prg, chr, prg_ram_size, true)),
+        16 | 153 | 157 | 159 => Box::new(Bandai::new(prg, chr, n, submapper)),
+        19 => Box::new(Namco163::new(prg, chr, prg_ram_size)),
+        // VRC2a (mapper 22) の基板にはRAMがない
+        22 => Box::new(Vrc4::new(prg, chr, 0, n, submapper)),
+        21 | 23 | 25 => Box::new(Vrc4::new(prg, chr, prg_ram_size, n, submapper)),
+        24 | 26 => Box::new(Vrc6::new(prg, chr, prg_ram_size, n)),
+        // 4画面のビットだけが立っている場合は1画面 (切り替え可)
+        30 => {
+            let is_one_screen = header.is_four_screen && header.mirroring == Mirroring::Horizontal;
+            Box::new(Unrom512::new(prg, chr, is_one_screen, header.has_battery))
+        }
+        69 => Box::new(Fme7::new(prg, chr, prg_ram_size)),
+        85 => Box::new(Vrc7::new(prg, chr, prg_ram_size, submapper)),
+        _ => return Err(format!("mapper {} is not supported", n)),
+    };
+    Ok(mapper)
 }
 
+// NROM
+// https://www.nesdev.org/wiki/NROM
 struct Mapper0 {
     prg : Vec::<u8>,
     chr : Vec::<u8>,
+    prg_ram : PrgRam,
 }
 
 impl Debug for Mapper0 {
@@ -32,20 +217,17 @@ impl Debug for Mapper0 {
 }
 
 impl Mapper0 {
-    fn new(prg : Vec::<u8>, chr : Vec::<u8>) -> Self {
+    fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
         Self {
             prg: prg,
-            chr: chr
+            chr: chr,
+            prg_ram: PrgRam::new(prg_ram_size),
         }
     }
 
+    // PRGが16Kの場合は$C000-$FFFFにミラーされる
     fn offset_from(&self, addr: usize) -> usize {
-        let offset_ = addr - 0x8000;
-        if offset_ >= 16 * 0x400 && self.prg.len() == 16 * 0x400 {
-            offset_ - 16 * 0x400
-        } else {
-            offset_
-        }
+        (addr - 0x8000) % self.prg.len()
     }
 }
 
@@ -62,6 +244,19 @@ impl Mapper for Mapper0 {
     fn write_prg(&mut self, _addr: u16, _v: u8) {
     }
 
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, v: u8) {
+        if let 0x6000 ..= 0x7fff = addr {
+            self.prg_ram.write(addr, v);
+        }
+    }
+
     fn read_chr(&self, addr: usize) -> u8 {
         self.chr[addr as usize]
     }
@@ -70,12 +265,36 @@ impl Mapper for Mapper0 {
     }
     fn write_chr(&mut self, _addr: u16, _v: u8) {
     }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.prg_ram.data().to_vec()
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
 }
 
-//https://www.nesdev.org/wiki/INES_Mapper_003
+// UxROM
+// https://www.nesdev.org/wiki/UxROM
+// $8000-$BFFFが切り替え可能な16K、$C000-$FFFFは最後のバンクに固定
 struct Mapper2 {
     prg : Vec::<u8>,
     chr : Vec::<u8>,
+    is_chr_ram : bool,
+    prg_ram : PrgRam,
     bank : usize
 }
 
@@ -86,28 +305,19 @@ impl Debug for Mapper2 {
 }
 
 impl Mapper2 {
-    fn new(prg : Vec::<u8>, chr : Vec::<u8>) -> Self {
+    fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
         Self {
            prg: prg,
-           chr: if chr.len() == 0 {
-            let mut v = Vec::<u8>::with_capacity(0x2000);
-            for _ in 0..0x2000 {
-                v.push(0)
-            }
-            v
-           } else {
-            chr
-           },
+           prg_ram: PrgRam::new(prg_ram_size),
+           is_chr_ram: chr.is_empty(),
+           chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
            bank: 0,
         }
     }
     fn offset_from(&self, addr: usize) -> usize {
-        let offset_ = addr - 0x8000;
-        if offset_ >= 16 * 0x400 && self.prg.len() == 16 * 0x400 {
-            offset_ - 16 * 0x400
-        } else {
-            offset_
-        }
+        let bank_count = self.prg.len() / 0x4000;
+        let bank = if addr < 0xc000 { self.bank % bank_count } else { bank_count - 1 };
+        bank * 0x4000 + (addr & 0x3fff)
     }
 }
 
@@ -120,7 +330,20 @@ impl Mapper for Mapper2 {
         &self.prg[offset..offset + addr.len()]
     }
     fn write_prg(&mut self, _addr: u16, v: u8) {
-        self.bank = (0x03 & (v as usize)) * 0x2000;
+        self.bank = v as usize;
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, v: u8) {
+        if let 0x6000 ..= 0x7fff = addr {
+            self.prg_ram.write(addr, v);
+        }
     }
 
     fn read_chr(&self, addr: usize) -> u8 {
@@ -130,14 +353,51 @@ impl Mapper for Mapper2 {
         &self.chr[addr]
     }
     fn write_chr(&mut self, addr: u16, v: u8) {
-        self.chr[addr as usize] = v;
+        if self.is_chr_ram {
+            self.chr[addr as usize] = v;
+        }
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+
+    // バンク、CHR-RAM、PRG-RAMの順
+    fn save_state(&self) -> Vec<u8> {
+        let mut v = vec![self.bank as u8];
+        if self.is_chr_ram {
+            v.extend_from_slice(&self.chr);
+        }
+        v.extend_from_slice(self.prg_ram.data());
+        v
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        let Some((bank, mut rest)) = data.split_first() else { return };
+        self.bank = *bank as usize;
+        if self.is_chr_ram && rest.len() >= self.chr.len() {
+            let (chr, ram) = rest.split_at(self.chr.len());
+            self.chr.copy_from_slice(chr);
+            rest = ram;
+        }
+        self.prg_ram.load(rest);
     }
 }
 
-//https://www.nesdev.org/wiki/INES_Mapper_003#Bank_select_($8000-$FFFF)
+// CNROM
+// https://www.nesdev.org/wiki/INES_Mapper_003#Bank_select_($8000-$FFFF)
 struct Mapper3 {
     prg : Vec::<u8>,
     chr : Vec::<u8>,
+    prg_ram : PrgRam,
     bank : usize
 }
 
@@ -149,20 +409,19 @@ impl Debug for Mapper3 {
 
 
 impl Mapper3 {
-    fn new(prg : Vec::<u8>, chr : Vec::<u8>) -> Self {
+    fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
         Self {
            prg: prg,
            chr: chr,
+           prg_ram: PrgRam::new(prg_ram_size),
            bank: 0,
         }
     }
     fn offset_from(&self, addr: usize) -> usize {
-        let offset_ = addr - 0x8000;
-        if offset_ >= 16 * 0x400 && self.prg.len() == 16 * 0x400 {
-            offset_ - 16 * 0x400
-        } else {
-            offset_
-        }
+        (addr - 0x8000) % self.prg.len()
+    }
+    fn chr_offset(&self, addr: usize) -> usize {
+        (self.bank * 0x2000) % self.chr.len() + (addr & 0x1fff)
     }
 }
 
@@ -175,18 +434,90 @@ impl Mapper for Mapper3 {
         &self.prg[offset..offset + addr.len()]
     }
     fn write_prg(&mut self, _addr: u16, v: u8) {
-        self.bank = (0x03 & (v as usize)) * 0x2000;
+        self.bank = v as usize;
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, v: u8) {
+        if let 0x6000 ..= 0x7fff = addr {
+            self.prg_ram.write(addr, v);
+        }
     }
 
     fn read_chr(&self, addr: usize) -> u8{
-        self.chr[addr | self.bank]
+        self.chr[self.chr_offset(addr)]
     }
 
     fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
-        let r = (addr.start | self.bank)..(addr.end | self.bank);
-        &self.chr[r]
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
     }
 
     fn write_chr(&mut self, _addr: u16, _v: u8) {
     }
-}
\ No newline at end of file
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut v = vec![self.bank as u8];
+        v.extend_from_slice(self.prg_ram.data());
+        v
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        if let Some((bank, ram)) = data.split_first() {
+            self.bank = *bank as usize;
+            self.prg_ram.load(ram);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uxromは8000に選択したバンク_c000に最後のバンクが見える() {
+        let prg = (0..8u8).flat_map(|b| vec![b; 0x4000]).collect::<Vec<u8>>();
+        let header = RomHeader { mapper: 2, prg_ram_size: 0x2000, ..Default::default() };
+        let mut m = new_mapper(&header, prg, vec![]).unwrap();
+        m.write_prg(0x8000, 3);
+        assert_eq!(m.read_prg(0x8000), 3);
+        assert_eq!(m.read_prg(0xffff), 7);
+
+        // $6000への書き込みはバンクを切り替えずRAMに入る
+        m.write_expansion(0x6000, 0x55);
+        assert_eq!(m.read_prg(0x8000), 3);
+        assert_eq!(m.read_expansion(0x6000), Some(0x55));
+    }
+
+    #[test]
+    fn トレーナーは7000のramに置き未対応のマッパーはエラーになる() {
+        let header = RomHeader { mapper: 0, prg_ram_size: 0x2000, ..Default::default() };
+        let mut m = new_mapper(&header, vec![0; 0x4000], vec![0; 0x2000]).unwrap();
+        m.load_trainer(&[0x12; 0x200]);
+        assert_eq!(m.read_expansion(0x7000), Some(0x12));
+        assert_eq!(m.read_expansion(0x7200), Some(0x00));
+
+        let header = RomHeader { mapper: 0x141, ..Default::default() };
+        assert!(new_mapper(&header, vec![0; 0x4000], vec![]).is_err());
+        let header = RomHeader { mapper: 255, ..Default::default() };
+        assert!(new_mapper(&header, vec![0; 0x4000], vec![]).is_err());
+    }
+}
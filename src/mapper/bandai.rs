@@ -0,0 +1,292 @@
+use std::{fmt::Debug, ops::Range};
+
+use super::{Mapper, Mirroring};
+use super::eeprom::{EepromKind, I2cEeprom};
+
+// Bandai FCG-1/FCG-2/LZ93D50 (mapper 16, 153, 157, 159)
+// https://www.nesdev.org/wiki/Bandai_FCG_board
+// FCG-1/2はレジスタが$6000-$7FFF、LZ93D50は$8000-$FFFFにある
+// LZ93D50はシリアルEEPROMをセーブに使う (153はEEPROMの代わりにSRAM)
+pub struct Bandai {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    is_fcg : bool,
+    is_lz93d50 : bool,
+    // 153: 8KのSRAMとPRG 256Kの外側のバンク
+    prg_ram : Option<Vec::<u8>>,
+    is_prg_ram_enable : bool,
+    eeprom : Option<I2cEeprom>,
+    is_eeprom_read : bool,
+
+    prg_bank : usize,
+    prg_outer_bank : usize,
+    chr_banks : [usize; 8],
+    mirroring : Mirroring,
+
+    irq_counter : u16,
+    irq_latch : u16,
+    is_irq_enable : bool,
+    is_irq_pending : bool,
+}
+
+impl Debug for Bandai {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Bandai FCG")
+    }
+}
+
+impl Bandai {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, n : u8, submapper : u8) -> Self {
+        // 16のサブマッパー 4: FCG-1/2、5: LZ93D50。0は両方のアドレスで受け付ける
+        let (is_fcg, is_lz93d50) = match (n, submapper) {
+            (16, 4) => (true, false),
+            (16, 0) => (true, true),
+            _ => (false, true),
+        };
+        let eeprom = match (n, submapper) {
+            (16, 4) | (153, _) => None,
+            (159, _) => Some(I2cEeprom::new(EepromKind::X24C01)),
+            // 157 (データック) は本体側の24C02のみ。カードリーダーとカセット側の24C01は未対応
+            _ => Some(I2cEeprom::new(EepromKind::C24C02)),
+        };
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            is_fcg,
+            is_lz93d50,
+            prg_ram: if n == 153 { Some(vec![0; 0x2000]) } else { None },
+            is_prg_ram_enable: false,
+            eeprom,
+            is_eeprom_read: false,
+            prg_bank: 0,
+            prg_outer_bank: 0,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            irq_counter: 0,
+            irq_latch: 0,
+            is_irq_enable: false,
+            is_irq_pending: false,
+        }
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        // 153以外は外側のバンクが常に0
+        let bank_count = (self.prg.len() / 0x4000).min(16);
+        let bank = if addr < 0xc000 { self.prg_bank % bank_count } else { bank_count - 1 };
+        (self.prg_outer_bank * 16 * 0x4000 + bank * 0x4000 + (addr & 0x3fff)) % self.prg.len()
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        // 153はCHR-RAM 8Kでバンク切り替えなし
+        if self.prg_ram.is_some() {
+            return addr & 0x1fff;
+        }
+        let bank = self.chr_banks[(addr >> 10) & 7];
+        (bank * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+
+    fn write_register(&mut self, addr: u16, v: u8) {
+        match addr & 0x0f {
+            i @ 0x0 ..= 0x7 => {
+                if self.prg_ram.is_some() {
+                    // 153: bit0がPRGの外側のバンク (A18)
+                    self.prg_outer_bank = (v & 1) as usize;
+                } else {
+                    self.chr_banks[i as usize] = v as usize;
+                }
+            }
+            0x8 => self.prg_bank = (v & 0x0f) as usize,
+            0x9 => {
+                self.mirroring = match v & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0xa => {
+                // ---- ---E  IRQ有効 (E)。書き込みでIRQは解除され、LZ93D50はラッチがカウンタにコピーされる
+                self.is_irq_enable = v & 1 != 0;
+                self.is_irq_pending = false;
+                if self.is_lz93d50 {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            // FCG-1/2はカウンタに直接書き込む
+            0xb if self.is_fcg && !self.is_lz93d50 => self.irq_counter = self.irq_counter & 0xff00 | v as u16,
+            0xc if self.is_fcg && !self.is_lz93d50 => self.irq_counter = self.irq_counter & 0x00ff | (v as u16) << 8,
+            0xb => self.irq_latch = self.irq_latch & 0xff00 | v as u16,
+            0xc => self.irq_latch = self.irq_latch & 0x00ff | (v as u16) << 8,
+            0xd => {
+                // RDC- ----  SDAの方向 (R)、SDA (D)、SCL (C)。153はbit5がSRAM有効
+                if self.prg_ram.is_some() {
+                    self.is_prg_ram_enable = v & 0x20 != 0;
+                }
+                self.is_eeprom_read = v & 0x80 != 0;
+                if let Some(eeprom) = &mut self.eeprom {
+                    eeprom.write((v >> 5) & 1, (v >> 6) & 1);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Bandai {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            // 153のSRAMは無効の間は書き込めない (FCGのレジスタでもない)
+            0x6000 ..= 0x7fff if self.prg_ram.is_some() => {
+                match &mut self.prg_ram {
+                    Some(ram) if self.is_prg_ram_enable => ram[(addr & 0x1fff) as usize] = v,
+                    _ => {}
+                }
+            }
+            0x6000 ..= 0x7fff if self.is_fcg => self.write_register(addr, v),
+            0x8000 ..= 0xffff if self.is_lz93d50 => self.write_register(addr, v),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, addr: u16, v: u8) {
+        if self.prg_ram.is_some() {
+            self.chr[(addr & 0x1fff) as usize] = v;
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    // $6000-$7FFFの読み出しはbit4がEEPROMのSDA。それ以外のビットはオープンバス
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => {
+                if let Some(ram) = &self.prg_ram {
+                    return if self.is_prg_ram_enable { Some(ram[(addr & 0x1fff) as usize]) } else { None };
+                }
+                match &self.eeprom {
+                    Some(eeprom) if self.is_eeprom_read => Some(eeprom.read() << 4),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // 16bitのカウンタがCPUサイクルごとに減り、0の時にIRQ
+    fn step_cycle(&mut self) {
+        if !self.is_irq_enable {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.is_irq_pending = true;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+    }
+
+    fn irq(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        match (&self.prg_ram, &self.eeprom) {
+            (Some(ram), _) => Some(ram.clone()),
+            (_, Some(eeprom)) => Some(eeprom.data.clone()),
+            _ => None,
+        }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let dest = match (&mut self.prg_ram, &mut self.eeprom) {
+            (Some(ram), _) => ram,
+            (_, Some(eeprom)) => &mut eeprom.data,
+            _ => return,
+        };
+        let n = data.len().min(dest.len());
+        dest[..n].copy_from_slice(&data[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(m: &mut Bandai, n: usize) {
+        for _ in 0..n {
+            m.step_cycle();
+        }
+    }
+
+    #[test]
+    fn fcgはカウンタに直接書き込む() {
+        let mut m = Bandai::new(vec![0; 0x40000], vec![], 16, 4);
+        m.write_prg(0x600b, 0x03);
+        m.write_prg(0x600c, 0x00);
+        m.write_prg(0x600a, 0x01);
+        // 0になったサイクルでIRQ
+        step(&mut m, 3);
+        assert!(!m.irq());
+        step(&mut m, 1);
+        assert!(m.irq());
+        // $600Aの書き込みで解除
+        m.write_prg(0x600a, 0x00);
+        assert!(!m.irq());
+        // $8000-はFCGのレジスタではない
+        m.write_prg(0x800b, 0x10);
+        assert_eq!(m.irq_counter, 0xffff);
+    }
+
+    #[test]
+    fn lz93d50はラッチを有効にした時にカウンタへコピーする() {
+        let mut m = Bandai::new(vec![0; 0x40000], vec![], 16, 5);
+        m.write_prg(0x800b, 0x02);
+        m.write_prg(0x800c, 0x00);
+        assert_eq!(m.irq_counter, 0);
+        m.write_prg(0x800a, 0x01);
+        assert_eq!(m.irq_counter, 2);
+        step(&mut m, 2);
+        assert!(!m.irq());
+        step(&mut m, 1);
+        assert!(m.irq());
+    }
+
+    #[test]
+    fn 外側のバンクとsram() {
+        let prg = (0..32).flat_map(|i| vec![i as u8; 0x4000]).collect::<Vec<_>>();
+        let mut m = Bandai::new(prg, vec![], 153, 0);
+        m.write_prg(0x8000, 0x01);
+        m.write_prg(0x8008, 0x02);
+        assert_eq!(m.read_prg(0x8000), 16 + 2);
+        assert_eq!(m.read_prg(0xc000), 16 + 15);
+
+        // $800Dのbit5でSRAMが有効になる
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), None);
+        m.write_prg(0x800d, 0x20);
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x12));
+    }
+
+    #[test]
+    fn 読み出しモードの時だけsdaがbit4に見える() {
+        let mut m = Bandai::new(vec![0; 0x40000], vec![], 159, 0);
+        assert_eq!(m.peek_expansion(0x6000), None);
+        m.write_prg(0x800d, 0x80);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x10));
+    }
+}
@@ -0,0 +1,301 @@
+// I2C接続のシリアルEEPROM (24C01, 24C02)
+// https://www.nesdev.org/wiki/Bandai_FCG_board#Serial_EEPROM
+// マッパーがSCLとSDAの線の状態を書き込み、SDAの出力を読み出す
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EepromKind {
+    // X24C01 (128バイト)。デバイスアドレスがなく、7bitのワードアドレスとR/WをLSBから送る
+    X24C01,
+    // 24C02 (256バイト)。デバイスアドレス、ワードアドレスの順にMSBから送る
+    C24C02,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EepromMode {
+    Idle,
+    ChipAddress,
+    Address,
+    Read,
+    Write,
+    SendAck,
+    WaitAck,
+}
+
+#[derive(Debug)]
+pub struct I2cEeprom {
+    kind : EepromKind,
+    pub data : Vec::<u8>,
+
+    mode : EepromMode,
+    next_mode : EepromMode,
+    chip_address : u8,
+    address : u8,
+    buffer : u8,
+    counter : u8,
+    output : u8,
+
+    prev_scl : u8,
+    prev_sda : u8,
+}
+
+impl I2cEeprom {
+    pub fn new(kind : EepromKind) -> Self {
+        let size = match kind {
+            EepromKind::X24C01 => 128,
+            EepromKind::C24C02 => 256,
+        };
+        Self {
+            kind,
+            data: vec![0; size],
+            mode: EepromMode::Idle,
+            next_mode: EepromMode::Idle,
+            chip_address: 0,
+            address: 0,
+            buffer: 0,
+            counter: 0,
+            output: 1,
+            prev_scl: 0,
+            prev_sda: 0,
+        }
+    }
+
+    // SDAの出力 (0 or 1)
+    pub fn read(&self) -> u8 {
+        self.output
+    }
+
+    pub fn write(&mut self, scl : u8, sda : u8) {
+        if self.prev_scl == 1 && scl == 1 && sda < self.prev_sda {
+            // SCLがHの間にSDAがH→LでSTART
+            self.mode = match self.kind {
+                EepromKind::X24C01 => EepromMode::Address,
+                EepromKind::C24C02 => EepromMode::ChipAddress,
+            };
+            if self.kind == EepromKind::X24C01 {
+                self.address = 0;
+            }
+            self.counter = 0;
+            self.output = 1;
+        } else if self.prev_scl == 1 && scl == 1 && sda > self.prev_sda {
+            // SCLがHの間にSDAがL→HでSTOP
+            self.mode = EepromMode::Idle;
+            self.output = 1;
+        } else if scl > self.prev_scl {
+            self.clock_rise(sda);
+        } else if scl < self.prev_scl {
+            self.clock_fall();
+        }
+        self.prev_scl = scl;
+        self.prev_sda = sda;
+    }
+
+    fn mask(&self) -> usize {
+        self.data.len() - 1
+    }
+
+    // 24C01はLSBから、24C02はMSBから送る
+    fn bit_index(&self) -> u8 {
+        match self.kind {
+            EepromKind::X24C01 => self.counter,
+            EepromKind::C24C02 => 7 - self.counter,
+        }
+    }
+
+    fn shift_in(&mut self, dest : u8, sda : u8) -> u8 {
+        if self.counter >= 8 {
+            return dest;
+        }
+        let bit = self.bit_index();
+        self.counter += 1;
+        dest & !(1 << bit) | sda << bit
+    }
+
+    fn clock_rise(&mut self, sda : u8) {
+        match self.mode {
+            EepromMode::ChipAddress => self.chip_address = self.shift_in(self.chip_address, sda),
+            EepromMode::Address if self.kind == EepromKind::X24C01 => {
+                if self.counter < 7 {
+                    self.address = self.shift_in(self.address, sda);
+                } else if self.counter == 7 {
+                    // 8bit目がR/W
+                    self.counter = 8;
+                    if sda == 1 {
+                        self.next_mode = EepromMode::Read;
+                        self.buffer = self.data[self.address as usize & self.mask()];
+                    } else {
+                        self.next_mode = EepromMode::Write;
+                    }
+                }
+            }
+            EepromMode::Address => self.address = self.shift_in(self.address, sda),
+            EepromMode::Read => {
+                if self.counter < 8 {
+                    self.output = (self.buffer >> self.bit_index()) & 1;
+                    self.counter += 1;
+                }
+            }
+            EepromMode::Write => self.buffer = self.shift_in(self.buffer, sda),
+            EepromMode::SendAck => self.output = 0,
+            EepromMode::WaitAck => {
+                // マスターがACKを返したら続けて読む
+                if sda == 0 {
+                    self.next_mode = EepromMode::Read;
+                    self.buffer = self.data[self.address as usize & self.mask()];
+                }
+            }
+            EepromMode::Idle => {}
+        }
+    }
+
+    fn clock_fall(&mut self) {
+        match self.mode {
+            EepromMode::ChipAddress if self.counter == 8 => {
+                // 1010 xxxR  デバイスアドレス、R/W (R)
+                self.counter = 0;
+                self.output = 1;
+                if self.chip_address & 0xf0 == 0xa0 {
+                    self.mode = EepromMode::SendAck;
+                    if self.chip_address & 1 != 0 {
+                        self.next_mode = EepromMode::Read;
+                        self.buffer = self.data[self.address as usize & self.mask()];
+                    } else {
+                        self.next_mode = EepromMode::Address;
+                    }
+                } else {
+                    self.mode = EepromMode::Idle;
+                }
+            }
+            EepromMode::Address if self.counter == 8 => {
+                self.mode = EepromMode::SendAck;
+                self.output = 1;
+                if self.kind == EepromKind::C24C02 {
+                    self.counter = 0;
+                    self.next_mode = EepromMode::Write;
+                }
+            }
+            EepromMode::Read if self.counter == 8 => {
+                self.mode = EepromMode::WaitAck;
+                self.address = ((self.address as usize + 1) & self.mask()) as u8;
+            }
+            EepromMode::Write if self.counter == 8 => {
+                self.counter = 0;
+                self.mode = EepromMode::SendAck;
+                // 24C01は1バイト書いたら終わり、24C02は続けて書ける
+                self.next_mode = match self.kind {
+                    EepromKind::X24C01 => EepromMode::Idle,
+                    EepromKind::C24C02 => EepromMode::Write,
+                };
+                let index = self.address as usize & self.mask();
+                self.data[index] = self.buffer;
+                self.address = ((self.address as usize + 1) & self.mask()) as u8;
+            }
+            EepromMode::SendAck | EepromMode::WaitAck => {
+                self.mode = self.next_mode;
+                self.counter = 0;
+                self.output = 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SCL=Lの間にSDAを変えて、SCLを上げ下げする
+    fn send_bit(e: &mut I2cEeprom, bit: u8) {
+        e.write(0, bit);
+        e.write(1, bit);
+        e.write(0, bit);
+    }
+
+    fn start(e: &mut I2cEeprom) {
+        e.write(0, 1);
+        e.write(1, 1);
+        e.write(1, 0);
+        e.write(0, 0);
+    }
+
+    fn stop(e: &mut I2cEeprom) {
+        e.write(0, 0);
+        e.write(1, 0);
+        e.write(1, 1);
+    }
+
+    fn send_byte_msb(e: &mut I2cEeprom, v: u8) {
+        for i in (0..8).rev() {
+            send_bit(e, (v >> i) & 1);
+        }
+        // ACK
+        send_bit(e, 1);
+    }
+
+    fn read_byte_msb(e: &mut I2cEeprom) -> u8 {
+        let mut v = 0;
+        for _ in 0..8 {
+            e.write(0, 1);
+            e.write(1, 1);
+            v = v << 1 | e.read();
+            e.write(0, 1);
+        }
+        v
+    }
+
+    fn send_byte_lsb(e: &mut I2cEeprom, v: u8) {
+        for i in 0..8 {
+            send_bit(e, (v >> i) & 1);
+        }
+        // ACK
+        send_bit(e, 1);
+    }
+
+    fn read_byte_lsb(e: &mut I2cEeprom) -> u8 {
+        let mut v = 0;
+        for i in 0..8 {
+            e.write(0, 1);
+            e.write(1, 1);
+            v |= e.read() << i;
+            e.write(0, 1);
+        }
+        v
+    }
+
+    #[test]
+    fn c24c02に書き込んだ値を読み出せる() {
+        let mut e = I2cEeprom::new(EepromKind::C24C02);
+        start(&mut e);
+        send_byte_msb(&mut e, 0xa0);
+        send_byte_msb(&mut e, 0x10);
+        send_byte_msb(&mut e, 0x12);
+        send_byte_msb(&mut e, 0x34);
+        stop(&mut e);
+        assert_eq!(e.data[0x10..0x12], [0x12, 0x34]);
+
+        // アドレスを設定し直してから読み出す
+        start(&mut e);
+        send_byte_msb(&mut e, 0xa0);
+        send_byte_msb(&mut e, 0x10);
+        start(&mut e);
+        send_byte_msb(&mut e, 0xa1);
+        assert_eq!(read_byte_msb(&mut e), 0x12);
+        stop(&mut e);
+    }
+
+    #[test]
+    fn x24c01に書き込んだ値を読み出せる() {
+        // ワードアドレスの7bitとR/Wを1バイトとしてLSBから送る
+        let mut e = I2cEeprom::new(EepromKind::X24C01);
+        start(&mut e);
+        send_byte_lsb(&mut e, 0x05);
+        send_byte_lsb(&mut e, 0x5a);
+        stop(&mut e);
+        assert_eq!(e.data[0x05], 0x5a);
+        assert_eq!(e.data[0x04], 0x00);
+
+        start(&mut e);
+        send_byte_lsb(&mut e, 0x80 | 0x05);
+        assert_eq!(read_byte_lsb(&mut e), 0x5a);
+        stop(&mut e);
+    }
+}
@@ -0,0 +1,539 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, fds::FdsAudio};
+
+use crate::patch::{apply_ips, make_ips};
+
+use super::{Mapper, Mirroring};
+
+// ディスクシステム (RAMアダプタとディスクドライブ)
+// https://www.nesdev.org/wiki/Family_Computer_Disk_System
+// $6000-$DFFFが32KのPRG-RAM、$E000-$FFFFがBIOS、CHRは8KのRAM
+
+// .fdsの1面のサイズ
+// https://www.nesdev.org/wiki/FDS_file_format
+pub const FDS_SIDE_SIZE : usize = 65500;
+
+// ディスクの先頭と各ブロックの後ろにあるギャップ (28300bit, 976bit)
+const FIRST_GAP_SIZE : usize = 28300 / 8;
+const BLOCK_GAP_SIZE : usize = 976 / 8;
+
+// ヘッドが先頭に戻ってから読み始めるまでと、1バイトの転送にかかるCPUサイクル
+const HEAD_RESET_CYCLES : usize = 50000;
+const BYTE_TRANSFER_CYCLES : usize = 150;
+
+// 面を切り替える時にディスクを抜いておく時間
+const EJECT_CYCLES : usize = 1_789_773;
+
+// .fdsを面ごとのデータに分ける。fwNESのヘッダはあってもなくてもよい
+pub fn parse_fds_image(data : &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let body = if data.len() >= 16 && &data[0..4] == b"FDS\x1a" { &data[16..] } else { data };
+    if body.is_empty() || body.len() % FDS_SIDE_SIZE != 0 {
+        return Err(format!("invalid fds image size {}", data.len()));
+    }
+    let sides = body.chunks(FDS_SIDE_SIZE).map(|s| s.to_vec()).collect::<Vec<_>>();
+    for (i, s) in sides.iter().enumerate() {
+        if s[0] != 1 || &s[1..15] != b"*NINTENDO-HVC*" {
+            return Err(format!("side {} has no disk header", i));
+        }
+    }
+    Ok(sides)
+}
+
+// ブロックの長さ。ファイルデータの長さは直前のファイルヘッダに書かれている
+fn block_length(block_type : u8, file_size : usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+// ファイルヘッダの13, 14バイト目がファイルサイズ
+fn file_size(block : &[u8], file_size : usize) -> usize {
+    if block[0] == 3 {
+        block[13] as usize | (block[14] as usize) << 8
+    } else {
+        file_size
+    }
+}
+
+// .fdsの面をギャップとCRCを含むドライブ上の並びに変換する
+fn to_raw_side(side : &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; FIRST_GAP_SIZE];
+    let mut pos = 0;
+    let mut size = 0;
+    while let Some(len) = side.get(pos).and_then(|t| block_length(*t, size)) {
+        if pos + len > side.len() {
+            break;
+        }
+        let block = &side[pos..pos + len];
+        size = file_size(block, size);
+        // ブロックの開始マーク、データ、CRC (検査しないので固定値)
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&[0x4d, 0x62]);
+        raw.resize(raw.len() + BLOCK_GAP_SIZE, 0);
+        pos += len;
+    }
+    if raw.len() < FDS_SIDE_SIZE * 2 {
+        raw.resize(FDS_SIDE_SIZE * 2, 0);
+    }
+    raw
+}
+
+// ドライブ上の並びから.fdsの面に戻す
+fn from_raw_side(raw : &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut pos = 0;
+    let mut size = 0;
+    loop {
+        // ギャップを読み飛ばして開始マークを探す
+        while pos < raw.len() && raw[pos] != 0x80 {
+            pos += 1;
+        }
+        pos += 1;
+        let len = match raw.get(pos).and_then(|t| block_length(*t, size)) {
+            Some(len) if pos + len <= raw.len() => len,
+            _ => break,
+        };
+        let block = &raw[pos..pos + len];
+        size = file_size(block, size);
+        side.extend_from_slice(block);
+        pos += len + 2;
+    }
+    side.resize(FDS_SIDE_SIZE, 0);
+    side
+}
+
+pub struct Fds {
+    bios : Vec::<u8>,
+    prg_ram : Vec::<u8>,
+    chr_ram : Vec::<u8>,
+
+    // 読み込んだ.fdsの各面と、ギャップを含むドライブ上の並び
+    original_sides : Vec<Vec<u8>>,
+    sides : Vec<Vec<u8>>,
+    // 入っている面。Noneは取り出し中
+    side : Option<usize>,
+    next_side : usize,
+    eject_counter : usize,
+
+    // $4020-$4022
+    irq_reload : u16,
+    irq_counter : u16,
+    is_irq_repeat : bool,
+    is_irq_enable : bool,
+    is_timer_irq : bool,
+    // $4023
+    is_disk_reg_enable : bool,
+    is_sound_reg_enable : bool,
+
+    // $4025
+    is_motor_on : bool,
+    is_transfer_reset : bool,
+    is_read_mode : bool,
+    mirroring : Mirroring,
+    is_crc_control : bool,
+    is_disk_ready : bool,
+    is_disk_irq_enable : bool,
+
+    // ドライブ
+    position : usize,
+    delay : usize,
+    is_end_of_head : bool,
+    is_scanning : bool,
+    is_gap_ended : bool,
+    read_data : u8,
+    write_data : u8,
+    is_transfer_complete : bool,
+    is_disk_irq : bool,
+
+    audio : FdsAudio,
+}
+
+impl Debug for Fds {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FDS")
+    }
+}
+
+impl Fds {
+    pub fn new(bios : Vec::<u8>, sides : Vec<Vec<u8>>) -> Self {
+        Self {
+            bios,
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+            sides: sides.iter().map(|s| to_raw_side(s)).collect(),
+            original_sides: sides,
+            side: Some(0),
+            next_side: 0,
+            eject_counter: 0,
+            irq_reload: 0,
+            irq_counter: 0,
+            is_irq_repeat: false,
+            is_irq_enable: false,
+            is_timer_irq: false,
+            is_disk_reg_enable: false,
+            is_sound_reg_enable: false,
+            is_motor_on: false,
+            is_transfer_reset: false,
+            is_read_mode: true,
+            mirroring: Mirroring::Horizontal,
+            is_crc_control: false,
+            is_disk_ready: false,
+            is_disk_irq_enable: false,
+            position: 0,
+            delay: 0,
+            is_end_of_head: true,
+            is_scanning: false,
+            is_gap_ended: false,
+            read_data: 0,
+            write_data: 0,
+            is_transfer_complete: false,
+            is_disk_irq: false,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    fn step_timer_irq(&mut self) {
+        if !self.is_irq_enable {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.is_timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.is_irq_repeat {
+                self.is_irq_enable = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    // ディスクは1バイトずつ一定の速さで流れていく
+    fn step_drive(&mut self) {
+        let side = match self.side {
+            Some(side) if self.is_motor_on => side,
+            _ => {
+                self.is_end_of_head = true;
+                self.is_scanning = false;
+                return;
+            }
+        };
+        if self.is_transfer_reset && !self.is_scanning {
+            return;
+        }
+        if self.is_end_of_head {
+            self.delay = HEAD_RESET_CYCLES;
+            self.is_end_of_head = false;
+            self.position = 0;
+            self.is_gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.is_scanning = true;
+        let mut is_irq = self.is_disk_irq_enable;
+        if self.is_read_mode {
+            let v = self.sides[side][self.position];
+            if !self.is_disk_ready {
+                self.is_gap_ended = false;
+            } else if v != 0 && !self.is_gap_ended {
+                // 開始マークを読んだら次のバイトから転送する
+                self.is_gap_ended = true;
+                is_irq = false;
+            }
+            if self.is_gap_ended {
+                self.is_transfer_complete = true;
+                self.read_data = v;
+                if is_irq {
+                    self.is_disk_irq = true;
+                }
+            }
+        } else {
+            let v = if !self.is_crc_control {
+                self.is_transfer_complete = true;
+                if is_irq {
+                    self.is_disk_irq = true;
+                }
+                if self.is_disk_ready { self.write_data } else { 0 }
+            } else {
+                // CRCは検査しないので固定値を書く
+                0x4d
+            };
+            self.sides[side][self.position] = v;
+            self.is_gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.is_motor_on = false;
+            self.is_end_of_head = true;
+        } else {
+            self.delay = BYTE_TRANSFER_CYCLES;
+        }
+    }
+
+    fn step_eject(&mut self) {
+        if self.side.is_some() || self.eject_counter == 0 {
+            return;
+        }
+        self.eject_counter -= 1;
+        if self.eject_counter == 0 {
+            self.side = Some(self.next_side);
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn read_prg(&self, addr: usize) -> u8 {
+        match addr {
+            0xe000 ..= 0xffff => self.bios[addr & 0x1fff],
+            _ => self.prg_ram[addr - 0x6000],
+        }
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        match addr.start {
+            0xe000 ..= 0xffff => &self.bios[addr.start & 0x1fff..(addr.start & 0x1fff) + addr.len()],
+            _ => &self.prg_ram[addr.start - 0x6000..addr.start - 0x6000 + addr.len()],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x4020 => self.irq_reload = self.irq_reload & 0xff00 | v as u16,
+            0x4021 => self.irq_reload = self.irq_reload & 0x00ff | (v as u16) << 8,
+            0x4022 => {
+                // ---- --ER  IRQ有効 (E)、繰り返し (R)
+                self.is_irq_repeat = v & 0x01 != 0;
+                self.is_irq_enable = v & 0x02 != 0 && self.is_disk_reg_enable;
+                if self.is_irq_enable {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.is_timer_irq = false;
+                }
+            }
+            0x4023 => {
+                // ---- --SD  音源有効 (S)、ディスク有効 (D)
+                self.is_disk_reg_enable = v & 0x01 != 0;
+                self.is_sound_reg_enable = v & 0x02 != 0;
+                if !self.is_disk_reg_enable {
+                    self.is_irq_enable = false;
+                    self.is_timer_irq = false;
+                }
+            }
+            0x4024 if self.is_disk_reg_enable => {
+                self.write_data = v;
+                self.is_transfer_complete = false;
+                self.is_disk_irq = false;
+            }
+            0x4025 if self.is_disk_reg_enable => {
+                // IS1C MRTD  転送IRQ (I)、読み書き開始 (S)、CRC (C)、ミラーリング (M)、読み込みモード (R)、転送リセット (T)、モーター (D)
+                self.is_disk_irq = false;
+                self.is_motor_on = v & 0x01 != 0;
+                self.is_transfer_reset = v & 0x02 != 0;
+                self.is_read_mode = v & 0x04 != 0;
+                self.mirroring = if v & 0x08 != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+                self.is_crc_control = v & 0x10 != 0;
+                self.is_disk_ready = v & 0x40 != 0;
+                self.is_disk_irq_enable = v & 0x80 != 0;
+            }
+            0x4040 ..= 0x408a if self.is_sound_reg_enable => self.audio.write(addr, v),
+            0x6000 ..= 0xdfff => self.prg_ram[addr as usize - 0x6000] = v,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr_ram[addr & 0x1fff]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        &self.chr_ram[addr.start & 0x1fff..(addr.start & 0x1fff) + addr.len()]
+    }
+    fn write_chr(&mut self, addr: u16, v: u8) {
+        self.chr_ram[addr as usize & 0x1fff] = v;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 if self.is_disk_reg_enable => {
+                let v = self.peek_expansion(addr);
+                self.is_timer_irq = false;
+                self.is_transfer_complete = false;
+                self.is_disk_irq = false;
+                v
+            }
+            0x4031 if self.is_disk_reg_enable => {
+                self.is_transfer_complete = false;
+                self.is_disk_irq = false;
+                Some(self.read_data)
+            }
+            _ => self.peek_expansion(addr),
+        }
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 if self.is_disk_reg_enable => {
+                // E--C --TI  ヘッド終端 (E)、CRCエラー (C)、転送完了 (T)、タイマーIRQ (I)
+                Some((self.is_timer_irq as u8) | (self.is_transfer_complete as u8) << 1
+                    | (self.is_end_of_head as u8) << 6)
+            }
+            0x4031 if self.is_disk_reg_enable => Some(self.read_data),
+            0x4032 if self.is_disk_reg_enable => {
+                // ---- -PRS  書き込み禁止 (P)、準備できていない (R)、ディスクなし (S)
+                let is_inserted = self.side.is_some();
+                Some(0x40 | (!is_inserted as u8) | ((!is_inserted || !self.is_scanning) as u8) << 1
+                    | (!is_inserted as u8) << 2)
+            }
+            // bit 7 はバッテリーの状態
+            0x4033 if self.is_disk_reg_enable => Some(0x80),
+            0x4040 ..= 0x4092 if self.is_sound_reg_enable => self.audio.read(addr),
+            0x6000 ..= 0x7fff => Some(self.prg_ram[addr as usize - 0x6000]),
+            _ => None,
+        }
+    }
+
+    fn step_cycle(&mut self) {
+        self.step_timer_irq();
+        self.step_drive();
+        self.step_eject();
+    }
+
+    fn irq(&self) -> bool {
+        self.is_timer_irq || self.is_disk_irq
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    // 一度ディスクを取り出し、しばらくしてから次の面を入れる
+    fn change_disk_side(&mut self) {
+        let current = self.side.unwrap_or(self.next_side);
+        self.next_side = (current + 1) % self.sides.len();
+        self.side = None;
+        self.eject_counter = EJECT_CYCLES;
+    }
+
+    // ディスクへの書き込みは元の.fdsとの差分をIPS形式で保存する
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        let original = self.original_sides.concat();
+        let modified = self.sides.iter().map(|s| from_raw_side(s)).collect::<Vec<_>>().concat();
+        Some(make_ips(&original, &modified))
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let original = self.original_sides.concat();
+        if let Ok(mut image) = apply_ips(&original, data) {
+            image.resize(original.len(), 0);
+            self.sides = image.chunks(FDS_SIDE_SIZE).map(to_raw_side).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_side() -> Vec<u8> {
+        let mut side = vec![0; FDS_SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        side[56] = 2;
+        side[57] = 1;
+        // ファイルヘッダとファイルデータ (3バイト)
+        side[58] = 3;
+        side[58 + 13] = 3;
+        side[74] = 4;
+        side[75..78].copy_from_slice(&[0xaa, 0xbb, 0xcc]);
+        side
+    }
+
+    #[test]
+    fn ギャップを入れて戻すと元の面になる() {
+        let side = test_side();
+        let raw = to_raw_side(&side);
+        assert_eq!(raw[FIRST_GAP_SIZE], 0x80);
+        assert_eq!(from_raw_side(&raw), side);
+    }
+
+    fn step(m : &mut Fds, n : usize) {
+        for _ in 0..n {
+            m.step_cycle();
+        }
+    }
+
+    #[test]
+    fn タイマーirq() {
+        let mut m = Fds::new(vec![0; 0x2000], vec![test_side()]);
+        // $4023でディスクのレジスタを有効にしないと動かない
+        m.write_prg(0x4020, 0x02);
+        m.write_prg(0x4022, 0x02);
+        step(&mut m, 10);
+        assert!(!m.irq());
+
+        m.write_prg(0x4023, 0x01);
+        m.write_prg(0x4022, 0x02);
+        step(&mut m, 2);
+        assert!(!m.irq());
+        step(&mut m, 1);
+        assert!(m.irq());
+        assert_eq!(m.peek_expansion(0x4030).unwrap() & 0x01, 0x01);
+        // $4030を読むと解除される。繰り返しでなければ止まる
+        m.read_expansion(0x4030);
+        assert!(!m.irq());
+        step(&mut m, 10);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn ディスクを読み込んで転送irqを起こす() {
+        let mut m = Fds::new(vec![0; 0x2000], vec![test_side()]);
+        m.write_prg(0x4023, 0x01);
+        // モーターが止まっている間は準備できていない
+        assert_eq!(m.peek_expansion(0x4032), Some(0x42));
+
+        // 転送IRQ、読み書き開始、読み込みモード、モーター
+        m.write_prg(0x4025, 0xc5);
+        let mut count = 0;
+        while !m.irq() {
+            m.step_cycle();
+            count += 1;
+        }
+        // ヘッドが戻るのを待ち、先頭のギャップと開始マークを読み飛ばしてからブロックの1バイト目
+        assert!(count > HEAD_RESET_CYCLES + (FIRST_GAP_SIZE + 1) * BYTE_TRANSFER_CYCLES);
+        assert_eq!(m.peek_expansion(0x4032), Some(0x40));
+        assert_eq!(m.peek_expansion(0x4030).unwrap() & 0x02, 0x02);
+        assert_eq!(m.read_expansion(0x4031), Some(0x01));
+        assert!(!m.irq());
+
+        step(&mut m, BYTE_TRANSFER_CYCLES + 1);
+        assert!(m.irq());
+        assert_eq!(m.read_expansion(0x4031), Some(b'*'));
+    }
+
+    #[test]
+    fn 面を切り替えるとしばらくディスクがない() {
+        let mut m = Fds::new(vec![0; 0x2000], vec![test_side(), test_side()]);
+        m.write_prg(0x4023, 0x01);
+        m.change_disk_side();
+        assert_eq!(m.peek_expansion(0x4032), Some(0x47));
+        step(&mut m, EJECT_CYCLES);
+        assert_eq!(m.side, Some(1));
+        assert_eq!(m.peek_expansion(0x4032), Some(0x42));
+        // 最後の面の次は最初の面
+        m.change_disk_side();
+        step(&mut m, EJECT_CYCLES);
+        assert_eq!(m.side, Some(0));
+    }
+}
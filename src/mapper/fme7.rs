@@ -0,0 +1,173 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, sunsoft5b::Sunsoft5bAudio};
+
+use super::{Mapper, Mirroring, PrgRam};
+
+// Sunsoft FME-7 / 5B (mapper 69)
+// https://www.nesdev.org/wiki/Sunsoft_FME-7
+// $8000にコマンド番号、$A000にパラメータを書き込む
+pub struct Fme7 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    prg_ram : PrgRam,
+
+    command : u8,
+    // $6000, $8000, $A000, $C000
+    prg_banks : [usize; 4],
+    is_prg_ram : bool,
+    chr_banks : [usize; 8],
+    mirroring : Mirroring,
+
+    irq_counter : u16,
+    is_irq_enable : bool,
+    is_irq_counter_enable : bool,
+    is_irq_pending : bool,
+
+    audio : Sunsoft5bAudio,
+}
+
+impl Debug for Fme7 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FME-7")
+    }
+}
+
+impl Fme7 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram: PrgRam::new(prg_ram_size),
+            command: 0,
+            prg_banks: [0; 4],
+            is_prg_ram: false,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            irq_counter: 0,
+            is_irq_enable: false,
+            is_irq_counter_enable: false,
+            is_irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let bank_count = self.prg.len() / 0x2000;
+        let bank = match (addr - 0x6000) / 0x2000 {
+            i @ 0 ..= 3 => self.prg_banks[i],
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * 0x2000 + (addr & 0x1fff)
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        let bank = self.chr_banks[(addr >> 10) & 7];
+        (bank * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+
+    fn write_parameter(&mut self, v: u8) {
+        match self.command {
+            i @ 0x0 ..= 0x7 => self.chr_banks[i as usize] = v as usize,
+            0x8 => {
+                // ERBB BBBB  RAM有効 (E)、RAM/ROM選択 (R)、バンク (B)
+                self.prg_ram.is_enable = v & 0x80 != 0;
+                self.is_prg_ram = v & 0x40 != 0;
+                self.prg_banks[0] = (v & 0x3f) as usize;
+                // 8Kを超えるRAMは同じバンク番号で切り替える
+                self.prg_ram.bank = (v & 0x3f) as usize;
+            }
+            i @ 0x9 ..= 0xb => self.prg_banks[(i - 8) as usize] = (v & 0x3f) as usize,
+            0xc => {
+                self.mirroring = match v & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0xd => {
+                // C--- ---T  カウンタ有効 (C)、IRQ有効 (T)。書き込みでIRQは解除される
+                self.is_irq_enable = v & 0x01 != 0;
+                self.is_irq_counter_enable = v & 0x80 != 0;
+                self.is_irq_pending = false;
+            }
+            0xe => self.irq_counter = self.irq_counter & 0xff00 | v as u16,
+            _ => self.irq_counter = self.irq_counter & 0x00ff | (v as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x6000 ..= 0x7fff if self.is_prg_ram => self.prg_ram.write(addr, v),
+            0x8000 ..= 0x9fff => self.command = v & 0x0f,
+            0xa000 ..= 0xbfff => self.write_parameter(v),
+            0xc000 ..= 0xdfff => self.audio.write_addr(v),
+            0xe000 ..= 0xffff => self.audio.write_data(v),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    // $6000-$7FFFはRAMかROMのどちらかを割り当てる。RAMが無効の場合はオープンバス
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff if !self.is_prg_ram => Some(self.read_prg(addr as usize)),
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    // 16bitのカウンタがCPUサイクルごとに減り、$0000から$FFFFになる時にIRQ
+    fn step_cycle(&mut self) {
+        if !self.is_irq_counter_enable {
+            return;
+        }
+        if self.irq_counter == 0 && self.is_irq_enable {
+            self.is_irq_pending = true;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+    }
+
+    fn irq(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
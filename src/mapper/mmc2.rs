@@ -0,0 +1,200 @@
+use std::{fmt::Debug, ops::Range};
+
+use super::{Mapper, Mirroring, PrgRam};
+
+// MMC2 (mapper 9) / MMC4 (mapper 10)
+// https://www.nesdev.org/wiki/MMC2
+// https://www.nesdev.org/wiki/MMC4
+// PPUがタイル$FD/$FEのパターンを読み出すとラッチが切り替わり、CHRバンクが変わる
+pub struct Mmc2 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    // $6000-$7FFFのRAM (MMC4の基板にある)
+    prg_ram : PrgRam,
+    is_mmc4 : bool,
+
+    prg_bank : usize,
+    // [ラッチ0が$FDの時, ラッチ0が$FEの時, ラッチ1が$FDの時, ラッチ1が$FEの時]
+    chr_banks : [usize; 4],
+    // true: $FE, false: $FD
+    latch : [bool; 2],
+    mirroring : Mirroring,
+}
+
+impl Debug for Mmc2 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_mmc4 {
+            write!(f, "MMC4")
+        } else {
+            write!(f, "MMC2")
+        }
+    }
+}
+
+impl Mmc2 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize, is_mmc4 : bool) -> Self {
+        Self {
+            prg,
+            chr,
+            prg_ram: PrgRam::new(prg_ram_size),
+            is_mmc4,
+            prg_bank: 0,
+            chr_banks: [0; 4],
+            latch: [true, true],
+            mirroring: Mirroring::Vertical,
+        }
+    }
+
+    // MMC2は8K単位で$8000のみ切り替え、残り3バンクは最後に固定
+    // MMC4は16K単位で$8000のみ切り替え、$C000は最後に固定
+    fn prg_offset(&self, addr: usize) -> usize {
+        let (bank_size, bank) = if self.is_mmc4 {
+            let last = self.prg.len() / 0x4000 - 1;
+            (0x4000, if addr < 0xc000 { self.prg_bank } else { last })
+        } else {
+            let last = self.prg.len() / 0x2000 - 1;
+            let bank = match addr {
+                0x8000 ..= 0x9fff => self.prg_bank,
+                0xa000 ..= 0xbfff => last - 2,
+                0xc000 ..= 0xdfff => last - 1,
+                _ => last,
+            };
+            (0x2000, bank)
+        };
+        let bank_count = self.prg.len() / bank_size;
+        (bank % bank_count) * bank_size + (addr & (bank_size - 1))
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        let half = (addr >> 12) & 1;
+        let bank = self.chr_banks[half * 2 + self.latch[half] as usize];
+        let bank_count = std::cmp::max(self.chr.len() / 0x1000, 1);
+        (bank % bank_count) * 0x1000 + (addr & 0x0fff)
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0xa000 ..= 0xafff => self.prg_bank = (v & 0x0f) as usize,
+            0xb000 ..= 0xbfff => self.chr_banks[0] = (v & 0x1f) as usize,
+            0xc000 ..= 0xcfff => self.chr_banks[1] = (v & 0x1f) as usize,
+            0xd000 ..= 0xdfff => self.chr_banks[2] = (v & 0x1f) as usize,
+            0xe000 ..= 0xefff => self.chr_banks[3] = (v & 0x1f) as usize,
+            0xf000 ..= 0xffff => {
+                self.mirroring = if v & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            _ => {}
+        }
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, v: u8) {
+        if let 0x6000 ..= 0x7fff = addr {
+            self.prg_ram.write(addr, v);
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    // 読み出しが終わってからラッチが切り替わるので、タイル$FD/$FE自体は切り替え前のバンクで描かれる
+    fn notify_ppu_read(&mut self, addr: u16) {
+        // MMC2のラッチ0は$0FD8/$0FE8の1アドレスのみ、それ以外は8バイトの範囲で反応する
+        let (fd, fe) = match addr {
+            0x0fd8 => (true, false),
+            0x0fe8 => (false, true),
+            0x0fd9 ..= 0x0fdf => (self.is_mmc4, false),
+            0x0fe9 ..= 0x0fef => (false, self.is_mmc4),
+            0x1fd8 ..= 0x1fdf => (true, false),
+            0x1fe8 ..= 0x1fef => (false, true),
+            _ => return,
+        };
+        let half = ((addr >> 12) & 1) as usize;
+        if fd {
+            self.latch[half] = false;
+        } else if fe {
+            self.latch[half] = true;
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4Kバンクごとに先頭のバイトをバンク番号にしたCHR
+    fn mmc2(is_mmc4 : bool) -> Mmc2 {
+        let chr = (0..32 * 0x1000).map(|i| (i / 0x1000) as u8).collect();
+        let mut m = Mmc2::new(vec![0; 0x20000], chr, 0, is_mmc4);
+        m.write_prg(0xb000, 1);
+        m.write_prg(0xc000, 2);
+        m.write_prg(0xd000, 3);
+        m.write_prg(0xe000, 4);
+        m
+    }
+
+    #[test]
+    fn タイルfdとfeの読み出しでchrバンクが切り替わる() {
+        let mut m = mmc2(false);
+        // 初期状態は$FE
+        assert_eq!(m.read_chr(0x0000), 2);
+        assert_eq!(m.read_chr(0x1000), 4);
+
+        m.notify_ppu_read(0x0fd8);
+        assert_eq!(m.read_chr(0x0000), 1);
+        m.notify_ppu_read(0x1fe8);
+        assert_eq!(m.read_chr(0x1000), 4);
+        m.notify_ppu_read(0x1fdf);
+        assert_eq!(m.read_chr(0x1000), 3);
+        m.notify_ppu_read(0x0fe8);
+        assert_eq!(m.read_chr(0x0000), 2);
+    }
+
+    #[test]
+    fn mmc2のラッチ0は1アドレスだけmmc4は8バイトの範囲で反応する() {
+        let mut m = mmc2(false);
+        m.notify_ppu_read(0x0fd9);
+        assert_eq!(m.read_chr(0x0000), 2);
+
+        let mut m = mmc2(true);
+        m.notify_ppu_read(0x0fd9);
+        assert_eq!(m.read_chr(0x0000), 1);
+    }
+}
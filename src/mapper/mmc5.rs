@@ -0,0 +1,505 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, mmc5::Mmc5Audio};
+
+use super::{Mapper, Mirroring, PpuFetch, PrgRam};
+
+// MMC5 (mapper 5)
+// https://www.nesdev.org/wiki/MMC5
+pub struct Mmc5 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    prg_ram : PrgRam,
+    ex_ram : [u8; 0x400],
+
+    prg_mode : u8,
+    chr_mode : u8,
+    prg_ram_protect : [u8; 2],
+    ex_ram_mode : u8,
+    name_table_mapping : u8,
+    fill_tile : u8,
+    fill_attr : u8,
+    // $5113-$5117
+    prg_banks : [u8; 5],
+    // $5120-$5127 (スプライト用)
+    chr_banks_a : [usize; 8],
+    // $5128-$512B (背景用)
+    chr_banks_b : [usize; 4],
+    chr_upper : usize,
+    is_last_chr_b : bool,
+
+    split_control : u8,
+    split_scroll : u8,
+    split_bank : usize,
+
+    irq_compare : u8,
+    irq_enable : bool,
+    irq_pending : bool,
+    in_frame : bool,
+    scanline : u8,
+
+    multiplicand : u8,
+    multiplier : u8,
+
+    // $2000/$2001の書き込みを監視して得たPPUの状態
+    is_sprite_8x16 : bool,
+    is_rendering : bool,
+
+    // 描画中のPPUの状態
+    fetch : PpuFetch,
+    line : usize,
+    tile_count : usize,
+    tile_x : usize,
+    ex_attr : u8,
+    is_split_tile : bool,
+
+    audio : Mmc5Audio,
+}
+
+impl Debug for Mmc5 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "MMC5")
+    }
+}
+
+impl Mmc5 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
+        let mut prg_ram = PrgRam::new(prg_ram_size);
+        // $5102/$5103に決まった値が書かれるまで書き込み禁止
+        prg_ram.is_write_protect = true;
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram,
+            ex_ram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            ex_ram_mode: 0,
+            name_table_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [0, 0, 0, 0, 0xff],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            is_last_chr_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enable: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            is_sprite_8x16: false,
+            is_rendering: false,
+            fetch: PpuFetch::Background,
+            line: 0,
+            tile_count: 0,
+            tile_x: 0,
+            ex_attr: 0,
+            is_split_tile: false,
+            audio: Mmc5Audio::new(),
+        }
+    }
+
+    // $8000-$FFFFの8K単位のバンクを返す (ROMならtrue, バンク番号)
+    fn prg_bank(&self, addr: usize) -> (bool, usize) {
+        let slot = (addr - 0x8000) / 0x2000;
+        let r = |i: usize| self.prg_banks[i] as usize;
+        let is_rom = |i: usize| self.prg_banks[i] & 0x80 != 0;
+        match self.prg_mode {
+            0 => (true, (r(4) & 0x7c) | slot),
+            1 => {
+                if slot < 2 {
+                    (is_rom(2), (r(2) & 0x7e) | slot)
+                } else {
+                    (true, (r(4) & 0x7e) | (slot - 2))
+                }
+            }
+            2 => match slot {
+                0 | 1 => (is_rom(2), (r(2) & 0x7e) | slot),
+                2 => (is_rom(3), r(3) & 0x7f),
+                _ => (true, r(4) & 0x7f),
+            },
+            _ => {
+                if slot < 3 {
+                    (is_rom(slot + 1), r(slot + 1) & 0x7f)
+                } else {
+                    (true, r(4) & 0x7f)
+                }
+            }
+        }
+    }
+
+    fn prg_rom_offset(&self, bank: usize, addr: usize) -> usize {
+        (bank % (self.prg.len() / 0x2000)) * 0x2000 + (addr & 0x1fff)
+    }
+
+    // $5102に%10、$5103に%01が書かれている時だけ書き込める
+    fn update_prg_ram_protect(&mut self) {
+        self.prg_ram.is_write_protect = !(self.prg_ram_protect[0] == 0x02 && self.prg_ram_protect[1] == 0x01);
+    }
+
+    fn is_bg_fetch(&self) -> bool {
+        self.in_frame && self.fetch == PpuFetch::Background
+    }
+
+    fn is_split(&self, tile_x: usize) -> bool {
+        if self.split_control & 0x80 == 0 || self.ex_ram_mode > 1 {
+            return false;
+        }
+        let count = (self.split_control & 0x1f) as usize;
+        if self.split_control & 0x40 != 0 {
+            tile_x >= count
+        } else {
+            tile_x < count
+        }
+    }
+
+    fn split_y(&self) -> usize {
+        (self.split_scroll as usize + self.line) % 240
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        let addr = addr & 0x1fff;
+        if self.is_bg_fetch() {
+            // 分割画面の領域は$5202のバンクと分割用の縦スクロールで読む
+            if self.is_split_tile {
+                let offset = self.split_bank * 0x1000 + (addr & 0x0ff8) + self.split_y() % 8;
+                return offset % self.chr.len();
+            }
+            // 拡張属性モードではタイルごとにExRAMで4Kバンクを選ぶ
+            if self.ex_ram_mode == 1 {
+                let bank = (self.ex_attr & 0x3f) as usize | self.chr_upper << 6;
+                return (bank * 0x1000 + (addr & 0x0fff)) % self.chr.len();
+            }
+        }
+
+        // 8x16スプライトの時は背景がB、スプライトがAを使う
+        // 8x8の時は最後に書き込まれた方を使う
+        let use_b = if self.is_sprite_8x16 && self.in_frame {
+            self.fetch == PpuFetch::Background
+        } else {
+            self.is_last_chr_b
+        };
+
+        let offset = if use_b {
+            let b = &self.chr_banks_b;
+            match self.chr_mode {
+                0 => b[3] * 0x2000 + addr,
+                1 => b[3] * 0x1000 + (addr & 0x0fff),
+                2 => b[((addr >> 11) & 1) * 2 + 1] * 0x800 + (addr & 0x07ff),
+                _ => b[(addr >> 10) & 3] * 0x400 + (addr & 0x03ff),
+            }
+        } else {
+            let a = &self.chr_banks_a;
+            match self.chr_mode {
+                0 => a[7] * 0x2000 + addr,
+                1 => a[(addr >> 12) * 4 + 3] * 0x1000 + (addr & 0x0fff),
+                2 => a[(addr >> 11) * 2 + 1] * 0x800 + (addr & 0x07ff),
+                _ => a[addr >> 10] * 0x400 + (addr & 0x03ff),
+            }
+        };
+        offset % self.chr.len()
+    }
+
+    fn read_split_name_table(&self, offset: usize) -> u8 {
+        let y = self.split_y();
+        let x = self.tile_x % 32;
+        if offset >= 0x3c0 {
+            let attribute = self.ex_ram[0x3c0 + (y / 32) * 8 + x / 4];
+            let shift_bit = (((x % 4) / 2) + ((y / 16) % 2) * 2) * 2;
+            ((attribute >> shift_bit) & 3) * 0x55
+        } else {
+            self.ex_ram[(y / 8) * 32 + x]
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        let (is_rom, bank) = self.prg_bank(addr);
+        if is_rom {
+            self.prg[self.prg_rom_offset(bank, addr)]
+        } else {
+            self.prg_ram.read_bank(bank, addr as u16).unwrap_or((addr >> 8) as u8)
+        }
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let (is_rom, bank) = self.prg_bank(addr.start);
+        if is_rom {
+            let offset = self.prg_rom_offset(bank, addr.start);
+            &self.prg[offset..offset + addr.len()]
+        } else {
+            self.prg_ram.bank_range(bank, addr)
+        }
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x5000 ..= 0x5015 => self.audio.write(addr, v),
+            0x5100 => self.prg_mode = v & 3,
+            0x5101 => self.chr_mode = v & 3,
+            0x5102 | 0x5103 => {
+                self.prg_ram_protect[(addr - 0x5102) as usize] = v & 3;
+                self.update_prg_ram_protect();
+            }
+            0x5104 => self.ex_ram_mode = v & 3,
+            0x5105 => self.name_table_mapping = v,
+            0x5106 => self.fill_tile = v,
+            0x5107 => self.fill_attr = v & 3,
+            0x5113 ..= 0x5117 => self.prg_banks[(addr - 0x5113) as usize] = v,
+            0x5120 ..= 0x5127 => {
+                // 上位ビットは書き込み時の$5130の値が使われる
+                self.chr_banks_a[(addr - 0x5120) as usize] = v as usize | self.chr_upper << 8;
+                self.is_last_chr_b = false;
+            }
+            0x5128 ..= 0x512b => {
+                self.chr_banks_b[(addr - 0x5128) as usize] = v as usize | self.chr_upper << 8;
+                self.is_last_chr_b = true;
+            }
+            0x5130 => self.chr_upper = (v & 3) as usize,
+            0x5200 => self.split_control = v,
+            0x5201 => self.split_scroll = v,
+            0x5202 => self.split_bank = v as usize,
+            0x5203 => self.irq_compare = v,
+            0x5204 => self.irq_enable = v & 0x80 != 0,
+            0x5205 => self.multiplicand = v,
+            0x5206 => self.multiplier = v,
+            // モード3は読み込み専用
+            0x5c00 ..= 0x5fff if self.ex_ram_mode != 3 => {
+                self.ex_ram[(addr - 0x5c00) as usize] = v;
+            }
+            0x6000 ..= 0x7fff => self.prg_ram.write_bank(self.prg_banks[0] as usize & 0x7f, addr, v),
+            0x8000 ..= 0xffff => {
+                let (is_rom, bank) = self.prg_bank(addr as usize);
+                if !is_rom {
+                    self.prg_ram.write_bank(bank, addr, v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        let m = self.name_table_mapping as usize;
+        // ExRAMとフィルモードの面はread_name_tableで差し替える
+        Some(Mirroring::Custom([m & 1, (m >> 2) & 1, (m >> 4) & 1, (m >> 6) & 1]))
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        let v = self.peek_expansion(addr);
+        // $5204の読み出しでIRQを解除する
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        v
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 | 0x5015 => Some(self.audio.read(addr)),
+            0x5204 => Some((self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6),
+            0x5205 => Some(((self.multiplicand as u16 * self.multiplier as u16) & 0xff) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5c00 ..= 0x5fff => {
+                if self.ex_ram_mode >= 2 {
+                    Some(self.ex_ram[(addr - 0x5c00) as usize])
+                } else {
+                    None
+                }
+            }
+            0x6000 ..= 0x7fff => self.prg_ram.read_bank(self.prg_banks[0] as usize & 0x7f, addr),
+            _ => None,
+        }
+    }
+
+    fn notify_cpu_write(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x2000 => self.is_sprite_8x16 = v & (1 << 5) != 0,
+            0x2001 => {
+                self.is_rendering = v & 0x18 != 0;
+                if !self.is_rendering {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 実機はネームテーブルの同じアドレスを3回読むことでラインの開始を検出している
+    fn notify_ppu_scanline(&mut self, line: usize) {
+        self.line = line;
+        self.tile_count = 0;
+        self.is_split_tile = false;
+        if line < 240 && self.is_rendering {
+            if self.in_frame {
+                self.scanline = self.scanline.wrapping_add(1);
+                if self.scanline == self.irq_compare {
+                    self.irq_pending = true;
+                }
+            } else {
+                self.in_frame = true;
+                self.scanline = 0;
+            }
+        } else {
+            self.in_frame = false;
+        }
+    }
+
+    fn notify_ppu_fetch(&mut self, fetch: PpuFetch) {
+        self.fetch = fetch;
+    }
+
+    fn read_name_table(&mut self, addr: u16) -> Option<u8> {
+        let offset = addr as usize & 0x3ff;
+        let is_attribute = offset >= 0x3c0;
+
+        if self.is_bg_fetch() {
+            if !is_attribute {
+                // ネームテーブルの読み出しごとに次のタイルに進む
+                self.tile_x = self.tile_count;
+                self.tile_count += 1;
+                self.is_split_tile = self.is_split(self.tile_x);
+                self.ex_attr = self.ex_ram[offset];
+            }
+            if self.is_split_tile {
+                return Some(self.read_split_name_table(offset));
+            }
+            if is_attribute && self.ex_ram_mode == 1 {
+                return Some((self.ex_attr >> 6) * 0x55);
+            }
+        }
+
+        let slot = (addr as usize >> 10) & 3;
+        match (self.name_table_mapping >> (slot * 2)) & 3 {
+            2 => Some(if self.ex_ram_mode <= 1 { self.ex_ram[offset] } else { 0 }),
+            3 => Some(if is_attribute { self.fill_attr * 0x55 } else { self.fill_tile }),
+            _ => None,
+        }
+    }
+
+    fn write_name_table(&mut self, addr: u16, v: u8) -> bool {
+        let slot = (addr as usize >> 10) & 3;
+        match (self.name_table_mapping >> (slot * 2)) & 3 {
+            2 => {
+                if self.ex_ram_mode <= 1 {
+                    self.ex_ram[addr as usize & 0x3ff] = v;
+                }
+                true
+            }
+            3 => true,
+            _ => false,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending && self.irq_enable
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mmc5() -> Mmc5 {
+        let mut m = Mmc5::new(vec![0; 0x8000], vec![0; 0x2000], 0x2000);
+        // 描画を有効にしてラインを数えさせる
+        m.notify_cpu_write(0x2001, 0x18);
+        m
+    }
+
+    #[test]
+    fn exramをネームテーブルとcpuから使う() {
+        let mut m = mmc5();
+        // 左上の面をExRAMにする
+        m.write_prg(0x5105, 0x02);
+        assert!(m.write_name_table(0x2005, 0x42));
+        assert_eq!(m.read_name_table(0x2005), Some(0x42));
+        assert_eq!(m.read_name_table(0x2405), None);
+
+        // モード2はCPUから読み書きでき、モード3は読み出し専用
+        m.write_prg(0x5104, 2);
+        m.write_prg(0x5c05, 0x24);
+        assert_eq!(m.read_expansion(0x5c05), Some(0x24));
+        m.write_prg(0x5104, 3);
+        m.write_prg(0x5c05, 0x99);
+        assert_eq!(m.read_expansion(0x5c05), Some(0x24));
+    }
+
+    #[test]
+    fn 分割画面の領域はexramのタイルを読む() {
+        let mut m = mmc5();
+        m.ex_ram[0x21] = 0x55;
+        // 左から2タイルを分割画面にして、縦スクロールを8ドットずらす
+        m.write_prg(0x5200, 0x80 | 2);
+        m.write_prg(0x5201, 8);
+        m.notify_ppu_scanline(0);
+        m.notify_ppu_fetch(PpuFetch::Background);
+        assert_eq!(m.read_name_table(0x2000), Some(0x00));
+        assert_eq!(m.read_name_table(0x23c0), Some(0x00));
+        assert_eq!(m.read_name_table(0x2001), Some(0x55));
+        assert_eq!(m.read_name_table(0x23c0), Some(0x00));
+        // 3タイル目からは通常のネームテーブル
+        assert_eq!(m.read_name_table(0x2002), None);
+    }
+
+    #[test]
+    fn 指定したラインでirqが起きて5204の読み出しで解除される() {
+        let mut m = mmc5();
+        m.write_prg(0x5203, 2);
+        m.write_prg(0x5204, 0x80);
+        m.notify_ppu_scanline(0);
+        m.notify_ppu_scanline(1);
+        assert!(!m.irq());
+        m.notify_ppu_scanline(2);
+        assert!(m.irq());
+
+        // デバッグ用の読み出しでは解除されない
+        assert_eq!(m.peek_expansion(0x5204), Some(0xc0));
+        assert!(m.irq());
+        assert_eq!(m.read_expansion(0x5204), Some(0xc0));
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn prg_ramは5102と5103の値が揃うまで書き込めない() {
+        let mut m = mmc5();
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x00));
+        m.write_prg(0x5102, 0x02);
+        m.write_prg(0x5103, 0x01);
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x12));
+        assert_eq!(m.battery_ram().map(|v| v[0]), Some(0x12));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, namco163::{Namco163Audio, Namco163Mixing}};
+
+use super::{Mapper, Mirroring, PrgRam};
+
+// Namco 163 (mapper 19)
+// https://www.nesdev.org/wiki/Namco_163
+pub struct Namco163 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    prg_ram : PrgRam,
+
+    prg_banks : [usize; 3],
+    // $0000-$1FFFの1Kバンク8個と$2000-$2FFFのネームテーブル4個
+    chr_banks : [u8; 12],
+    // $F800  0100 DCBA  $6000-$7FFFの2Kごとの書き込み禁止 (A-D)
+    write_protect : u8,
+
+    irq_counter : u16,
+    is_irq_enable : bool,
+
+    audio : Namco163Audio,
+}
+
+impl Debug for Namco163 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Namco163")
+    }
+}
+
+impl Namco163 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize) -> Self {
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram: PrgRam::new(prg_ram_size),
+            prg_banks: [0; 3],
+            chr_banks: [0; 12],
+            write_protect: 0,
+            irq_counter: 0,
+            is_irq_enable: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let bank_count = self.prg.len() / 0x2000;
+        let bank = match (addr - 0x8000) / 0x2000 {
+            i @ 0 ..= 2 => self.prg_banks[i],
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * 0x2000 + (addr & 0x1fff)
+    }
+
+    fn chr_offset(&self, bank: u8, addr: usize) -> usize {
+        (bank as usize * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+
+    // ネームテーブルの$E0以上はCIRAMを指す
+    fn is_ciram(&self, slot: usize) -> bool {
+        self.chr_banks[8 + slot] >= 0xe0
+    }
+
+    fn is_prg_ram_writable(&self, addr: u16) -> bool {
+        self.write_protect & 0xf0 == 0x40 && self.write_protect & (1 << ((addr - 0x6000) >> 11)) == 0
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x4800 ..= 0x4fff => self.audio.write_data(v),
+            0x5000 ..= 0x57ff => {
+                self.irq_counter = self.irq_counter & 0x7f00 | v as u16;
+            }
+            0x5800 ..= 0x5fff => {
+                // EHHH HHHH  IRQ有効 (E)、カウンタ上位 (H)
+                self.is_irq_enable = v & 0x80 != 0;
+                self.irq_counter = self.irq_counter & 0x00ff | ((v & 0x7f) as u16) << 8;
+            }
+            0x6000 ..= 0x7fff if self.is_prg_ram_writable(addr) => self.prg_ram.write(addr, v),
+            0x8000 ..= 0xdfff => self.chr_banks[((addr - 0x8000) >> 11) as usize] = v,
+            0xe000 ..= 0xe7ff => {
+                // -SPP PPPP  音源無効 (S)、PRGバンク (P)
+                self.prg_banks[0] = (v & 0x3f) as usize;
+                self.audio.set_disable(v & 0x40 != 0);
+            }
+            0xe800 ..= 0xefff => {
+                // HLPP PPPP  $1000-$1FFF (H), $0000-$0FFF (L) のCIRAM無効、PRGバンク (P)
+                // パターンテーブルにCIRAMを割り当てるモードは未対応なので、H, Lは使わない
+                self.prg_banks[1] = (v & 0x3f) as usize;
+            }
+            0xf000 ..= 0xf7ff => self.prg_banks[2] = (v & 0x3f) as usize,
+            0xf800 ..= 0xffff => {
+                self.write_protect = v;
+                self.audio.write_addr(v);
+            }
+            _ => {}
+        }
+    }
+
+    // $E0以上のバンクもCHR-ROMとして扱う
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(self.chr_banks[(addr >> 10) & 7], addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(self.chr_banks[(addr.start >> 10) & 7], addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    // CIRAMを指しているネームテーブルはbit 0でどちらの1Kを使うかが決まる
+    fn mirroring(&self) -> Option<Mirroring> {
+        let mut pages = [0; 4];
+        for (i, p) in pages.iter_mut().enumerate() {
+            *p = (self.chr_banks[8 + i] & 1) as usize;
+        }
+        Some(Mirroring::Custom(pages))
+    }
+
+    fn read_name_table(&mut self, addr: u16) -> Option<u8> {
+        let slot = (addr as usize >> 10) & 3;
+        if self.is_ciram(slot) {
+            None
+        } else {
+            Some(self.chr[self.chr_offset(self.chr_banks[8 + slot], addr as usize)])
+        }
+    }
+
+    // CHR-ROMを指しているネームテーブルへの書き込みは無視する
+    fn write_name_table(&mut self, addr: u16, _v: u8) -> bool {
+        !self.is_ciram((addr as usize >> 10) & 3)
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // 自動インクリメントが有効ならアドレスが進む
+            0x4800 ..= 0x4fff => Some(self.audio.read_data()),
+            _ => self.peek_expansion(addr),
+        }
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800 ..= 0x4fff => Some(self.audio.peek_data()),
+            0x5000 ..= 0x57ff => Some(self.irq_counter as u8),
+            0x5800 ..= 0x5fff => Some((self.is_irq_enable as u8) << 7 | (self.irq_counter >> 8) as u8),
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    // 15bitのカウンタが$7FFFに達するとIRQ。カウンタへの書き込みで解除される
+    fn step_cycle(&mut self) {
+        if self.is_irq_enable && self.irq_counter < 0x7fff {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.is_irq_enable && self.irq_counter == 0x7fff
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn set_namco163_mixing(&mut self, mixing: Namco163Mixing) {
+        self.audio.mixing = mixing;
+    }
+
+    // PRG-RAMと内部RAM 128バイトをバッテリーバックアップする
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        let mut v = self.prg_ram.data().to_vec();
+        v.extend_from_slice(&self.audio.ram);
+        Some(v)
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let ram_size = self.prg_ram.data().len();
+        self.prg_ram.load(data);
+        if data.len() > ram_size {
+            let ram = &data[ram_size..];
+            let n = ram.len().min(self.audio.ram.len());
+            self.audio.ram[..n].copy_from_slice(&ram[..n]);
+        }
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prg_ramは2kごとに書き込み禁止にできる() {
+        let mut m = Namco163::new(vec![0; 0x8000], vec![], 0x2000);
+        // 上位4bitが$4でなければ全体が書き込み禁止
+        m.write_prg(0x6000, 0x11);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x00));
+        // $6800-$6FFFだけ禁止
+        m.write_prg(0xf800, 0x42);
+        m.write_prg(0x6000, 0x11);
+        m.write_prg(0x6800, 0x22);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x11));
+        assert_eq!(m.peek_expansion(0x6800), Some(0x00));
+    }
+
+    #[test]
+    fn 内部ramは自動インクリメントで読み書きしてバッテリーに保存する() {
+        let mut m = Namco163::new(vec![0; 0x8000], vec![], 0x2000);
+        m.write_prg(0xf800, 0x80 | 0x7f);
+        m.write_prg(0x4800, 0x12);
+        m.write_prg(0x4800, 0x34);
+        m.write_prg(0xf800, 0x80 | 0x7f);
+        assert_eq!(m.peek_expansion(0x4800), Some(0x12));
+        assert_eq!(m.read_expansion(0x4800), Some(0x12));
+        assert_eq!(m.read_expansion(0x4800), Some(0x34));
+
+        let ram = m.battery_ram().unwrap();
+        assert_eq!(ram.len(), 0x2000 + 128);
+        assert_eq!(ram[0x2000], 0x34);
+        assert_eq!(ram[0x2000 + 127], 0x12);
+    }
+}
@@ -0,0 +1,118 @@
+use std::ops::Range;
+
+// $6000-$7FFFのPRG-RAM (WRAM)
+// https://www.nesdev.org/wiki/PRG_RAM_circuit
+// サイズはヘッダから決まり、有効/書き込み禁止のビットを持つ基板はマッパー側で切り替える
+#[derive(Debug)]
+pub struct PrgRam {
+    data : Vec::<u8>,
+    // 8Kを超える場合のバンク
+    pub bank : usize,
+    pub is_enable : bool,
+    pub is_write_protect : bool,
+}
+
+impl PrgRam {
+    pub fn new(size : usize) -> Self {
+        Self {
+            data: vec![0; size],
+            bank: 0,
+            is_enable: true,
+            is_write_protect: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // 8K未満のRAMは$6000-$7FFFにミラーされる
+    fn offset(&self, bank: usize, addr: u16) -> usize {
+        (bank * 0x2000 + (addr & 0x1fff) as usize) % self.data.len()
+    }
+
+    // RAMがない、または無効の場合はNone (オープンバス)
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        self.read_bank(self.bank, addr)
+    }
+
+    pub fn write(&mut self, addr: u16, v: u8) {
+        self.write_bank(self.bank, addr, v);
+    }
+
+    // 8Kのバンクを指定して読み書きする (MMC5のように$8000以降にもRAMを置ける基板用)
+    pub fn read_bank(&self, bank: usize, addr: u16) -> Option<u8> {
+        if self.data.is_empty() || !self.is_enable {
+            return None;
+        }
+        Some(self.data[self.offset(bank, addr)])
+    }
+
+    pub fn write_bank(&mut self, bank: usize, addr: u16, v: u8) {
+        if self.data.is_empty() || !self.is_enable || self.is_write_protect {
+            return;
+        }
+        let offset = self.offset(bank, addr);
+        self.data[offset] = v;
+    }
+
+    pub fn bank_range(&self, bank: usize, addr: Range<usize>) -> &[u8] {
+        if self.data.is_empty() {
+            return &[];
+        }
+        let offset = self.offset(bank, addr.start as u16);
+        &self.data[offset..offset + addr.len()]
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        let n = data.len().min(self.data.len());
+        self.data[..n].copy_from_slice(&data[..n]);
+    }
+
+    // トレーナーは$7000-$71FFに置く。有効/書き込み禁止の状態に関係なく書き込む
+    pub fn load_trainer(&mut self, data: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for (i, v) in data.iter().enumerate() {
+            let offset = self.offset(0, 0x7000 + i as u16);
+            self.data[offset] = *v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn 小さいramはミラーされて書き込み禁止と無効を守る() {
+        let mut ram = PrgRam::new(0x800);
+        ram.write(0x6001, 0x12);
+        assert_eq!(ram.read(0x6801), Some(0x12));
+
+        ram.is_write_protect = true;
+        ram.write(0x6001, 0x34);
+        assert_eq!(ram.read(0x6001), Some(0x12));
+
+        ram.is_enable = false;
+        assert_eq!(ram.read(0x6001), None);
+
+        assert_eq!(PrgRam::new(0).read(0x6000), None);
+    }
+
+    #[test]
+    fn バンクを切り替えて読み書きする() {
+        let mut ram = PrgRam::new(0x8000);
+        ram.bank = 2;
+        ram.write(0x6000, 0x56);
+        assert_eq!(ram.read_bank(2, 0x6000), Some(0x56));
+        assert_eq!(ram.read_bank(0, 0x6000), Some(0x00));
+        assert_eq!(ram.data()[0x4000], 0x56);
+        assert_eq!(ram.bank_range(2, 0x8000..0x8002), &[0x56, 0x00]);
+    }
+}
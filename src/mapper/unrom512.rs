@@ -0,0 +1,210 @@
+use std::{fmt::Debug, ops::Range};
+
+use super::{Mapper, Mirroring};
+
+// UNROM 512 (mapper 30)
+// https://www.nesdev.org/wiki/UNROM_512
+// バッテリーありの場合はPRGがフラッシュメモリ(SST39SF040)で、ゲームが自分で書き換えてセーブする
+pub struct Unrom512 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    is_flashable : bool,
+    is_one_screen : bool,
+
+    prg_bank : usize,
+    chr_bank : usize,
+    mirroring : Mirroring,
+
+    flash : FlashState,
+}
+
+// https://www.nesdev.org/wiki/UNROM_512#Flash_Memory_Commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashState {
+    Ready,
+    // $5555 <- $AA
+    Unlock1,
+    // $2AAA <- $55
+    Unlock2,
+    // $5555 <- $A0 の次の書き込みで1バイト書き込む
+    ByteProgram,
+    // $5555 <- $80 の後、再度 $AA, $55 を待つ
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+    // $5555 <- $90 で製造者IDとデバイスIDが読めるようになる
+    SoftwareId,
+}
+
+// 製造者ID(SST)とデバイスID(SST39SF040)
+const FLASH_ID : [u8; 2] = [0xbf, 0xb7];
+
+impl Debug for Unrom512 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "UNROM512")
+    }
+}
+
+impl Unrom512 {
+    // ヘッダのbyte 6 の bit 3 と bit 0 で 水平/垂直/1画面(切り替え可)/4画面
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, is_one_screen : bool, is_flashable : bool) -> Self {
+        Self {
+            prg,
+            // CHR-RAMは8Kが4バンク
+            chr: if chr.is_empty() { vec![0; 0x8000] } else { chr },
+            is_flashable,
+            is_one_screen,
+            prg_bank: 0,
+            chr_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+            flash: FlashState::Ready,
+        }
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let bank_count = self.prg.len() / 0x4000;
+        let bank = if addr < 0xc000 { self.prg_bank % bank_count } else { bank_count - 1 };
+        bank * 0x4000 + (addr & 0x3fff)
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        (self.chr_bank * 0x2000 + (addr & 0x1fff)) % self.chr.len()
+    }
+
+    // フラッシュからは$8000-$BFFFに見えているバンクを含めた19bitのアドレスになる
+    fn write_flash(&mut self, addr: u16, v: u8) {
+        let flash_addr = self.prg_offset(addr as usize);
+        let command_addr = flash_addr & 0x7fff;
+        self.flash = match (self.flash, command_addr, v) {
+            // 書き込むデータが0xF0の場合もリセットにはならない
+            (FlashState::ByteProgram, _, _) => {
+                // 書き込みでは1のビットを0にすることしかできない
+                self.prg[flash_addr] &= v;
+                FlashState::Ready
+            }
+            (_, _, 0xf0) => FlashState::Ready,
+            (FlashState::Ready, 0x5555, 0xaa) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2aaa, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0xa0) => FlashState::ByteProgram,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::Erase,
+            (FlashState::Unlock2, 0x5555, 0x90) => FlashState::SoftwareId,
+            (FlashState::Erase, 0x5555, 0xaa) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2aaa, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                // 4Kのセクタ消去
+                let sector = flash_addr & !0x0fff;
+                self.prg[sector..sector + 0x1000].fill(0xff);
+                FlashState::Ready
+            }
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                self.prg.fill(0xff);
+                FlashState::Ready
+            }
+            (FlashState::SoftwareId, _, _) => FlashState::SoftwareId,
+            _ => FlashState::Ready,
+        };
+    }
+
+    fn write_register(&mut self, v: u8) {
+        // MCCP PPPP  1画面ミラーリング (M)、CHR-RAMバンク (C)、PRGバンク (P)
+        self.prg_bank = (v & 0x1f) as usize;
+        self.chr_bank = ((v >> 5) & 0x03) as usize;
+        self.mirroring = if v & 0x80 != 0 { Mirroring::SingleScreenUpper } else { Mirroring::SingleScreenLower };
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        if self.flash == FlashState::SoftwareId {
+            return FLASH_ID[addr & 1];
+        }
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    // フラッシュの場合、$8000-$BFFFはフラッシュへのコマンド、$C000-$FFFFがバンクレジスタ
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x8000 ..= 0xbfff if self.is_flashable => self.write_flash(addr, v),
+            0x8000 ..= 0xffff => self.write_register(v),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, addr: u16, v: u8) {
+        let offset = self.chr_offset(addr as usize);
+        self.chr[offset] = v;
+    }
+
+    // 1画面の基板のみミラーリングを切り替えられる。それ以外はヘッダの設定に従う
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.is_one_screen {
+            Some(self.mirroring)
+        } else {
+            None
+        }
+    }
+
+    // フラッシュに書き込まれたPRG全体を保存する
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.is_flashable {
+            Some(self.prg.clone())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        if self.is_flashable && data.len() == self.prg.len() {
+            self.prg.copy_from_slice(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn コマンドを送るとフラッシュに書き込める() {
+        let mut m = Unrom512::new(vec![0xff; 0x80000], vec![], false, true);
+        // $5555 はバンク1の$9555、$2AAAはバンク0の$AAAA
+        let command = |m: &mut Unrom512, bank: u8, addr: u16, v: u8| {
+            m.write_prg(0xc000, bank);
+            m.write_prg(addr, v);
+        };
+        command(&mut m, 1, 0x9555, 0xaa);
+        command(&mut m, 0, 0xaaaa, 0x55);
+        command(&mut m, 1, 0x9555, 0xa0);
+        command(&mut m, 3, 0x8123, 0x5a);
+        m.write_prg(0xc000, 3);
+        assert_eq!(m.read_prg(0x8123), 0x5a);
+
+        // データの0xF0はリセットのコマンドとみなさない
+        command(&mut m, 1, 0x9555, 0xaa);
+        command(&mut m, 0, 0xaaaa, 0x55);
+        command(&mut m, 1, 0x9555, 0xa0);
+        command(&mut m, 3, 0x8124, 0xf0);
+        m.write_prg(0xc000, 3);
+        assert_eq!(m.read_prg(0x8124), 0xf0);
+
+        // セクタ消去で0xffに戻る
+        command(&mut m, 1, 0x9555, 0xaa);
+        command(&mut m, 0, 0xaaaa, 0x55);
+        command(&mut m, 1, 0x9555, 0x80);
+        command(&mut m, 1, 0x9555, 0xaa);
+        command(&mut m, 0, 0xaaaa, 0x55);
+        command(&mut m, 3, 0x8000, 0x30);
+        m.write_prg(0xc000, 3);
+        assert_eq!(m.read_prg(0x8123), 0xff);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{fmt::Debug, ops::Range};
+
+use super::{Mapper, Mirroring, PrgRam, vrc_irq::VrcIrq};
+
+// Konami VRC2/VRC4 (mapper 21, 22, 23, 25)
+// https://www.nesdev.org/wiki/VRC2_and_VRC4
+// 基板ごとにレジスタのA0/A1に繋がっているCPUのアドレス線が異なる
+pub struct Vrc4 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    prg_ram : PrgRam,
+    mapper : u8,
+    is_vrc2 : bool,
+    // レジスタのA0, A1になるCPUアドレスのビット
+    a0_mask : u16,
+    a1_mask : u16,
+
+    prg_banks : [usize; 2],
+    is_prg_swap : bool,
+    chr_banks : [usize; 8],
+    mirroring : Mirroring,
+    // RAMのないVRC2の$6000-$6FFFにある1bitのラッチ
+    vrc2_latch : u8,
+
+    irq : VrcIrq,
+}
+
+impl Debug for Vrc4 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_vrc2 {
+            write!(f, "VRC2 (mapper {})", self.mapper)
+        } else {
+            write!(f, "VRC4 (mapper {})", self.mapper)
+        }
+    }
+}
+
+impl Vrc4 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize, mapper : u8, submapper : u8) -> Self {
+        // https://www.nesdev.org/wiki/NES_2.0_submappers#021,_022,_023,_025:_Konami_VRC2/VRC4
+        // サブマッパーが不明な場合は両方の配線のORを取る
+        let (is_vrc2, a0_mask, a1_mask) = match (mapper, submapper) {
+            (21, 1) => (false, 0x02, 0x04),
+            (21, 2) => (false, 0x40, 0x80),
+            (21, _) => (false, 0x42, 0x84),
+            (22, _) => (true, 0x02, 0x01),
+            (23, 1) => (false, 0x01, 0x02),
+            (23, 2) => (false, 0x04, 0x08),
+            (23, 3) => (true, 0x01, 0x02),
+            (23, _) => (false, 0x05, 0x0a),
+            (25, 1) => (false, 0x02, 0x01),
+            (25, 2) => (false, 0x08, 0x04),
+            (25, 3) => (true, 0x02, 0x01),
+            _ => (false, 0x0a, 0x05),
+        };
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram: PrgRam::new(prg_ram_size),
+            mapper,
+            is_vrc2,
+            a0_mask,
+            a1_mask,
+            prg_banks: [0, 0],
+            is_prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            vrc2_latch: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    // アドレス線の配線を吸収して $x000-$x003 に正規化する
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.a0_mask != 0) as u16;
+        let a1 = (addr & self.a1_mask != 0) as u16;
+        addr & 0xf000 | a1 << 1 | a0
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let bank_count = self.prg.len() / 0x2000;
+        let second_last = bank_count - 2;
+        let bank = match (addr - 0x8000) / 0x2000 {
+            0 => if self.is_prg_swap { second_last } else { self.prg_banks[0] },
+            1 => self.prg_banks[1],
+            2 => if self.is_prg_swap { self.prg_banks[0] } else { second_last },
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * 0x2000 + (addr & 0x1fff)
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        let bank = self.chr_banks[(addr >> 10) & 7];
+        // VRC2a(mapper 22)はCHRバンクの最下位ビットが繋がっていない
+        let bank = if self.mapper == 22 { bank >> 1 } else { bank };
+        (bank * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+
+    fn write_chr_bank(&mut self, reg: u16, v: u8) {
+        // $B000-$E003 で2レジスタずつ1Kバンクの下位4bit/上位ビット
+        let i = (((reg >> 12) - 0xb) * 2 + ((reg >> 1) & 1)) as usize;
+        let bank = self.chr_banks[i];
+        self.chr_banks[i] = if reg & 1 == 0 {
+            bank & !0x0f | (v & 0x0f) as usize
+        } else {
+            let mask = if self.is_vrc2 { 0x0f } else { 0x1f };
+            bank & 0x0f | ((v & mask) as usize) << 4
+        };
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        if addr < 0x8000 {
+            if (0x6000..=0x7fff).contains(&addr) {
+                if !self.prg_ram.is_empty() {
+                    self.prg_ram.write(addr, v);
+                } else if self.is_vrc2 && addr < 0x7000 {
+                    self.vrc2_latch = v & 1;
+                }
+            }
+            return;
+        }
+        let reg = self.register(addr);
+        match reg {
+            0x8000 ..= 0x8003 => self.prg_banks[0] = (v & 0x1f) as usize,
+            0x9000 ..= 0x9003 if self.is_vrc2 => {
+                self.mirroring = if v & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            0x9000 | 0x9001 => {
+                self.mirroring = match v & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0x9002 | 0x9003 => self.is_prg_swap = v & 2 != 0,
+            0xa000 ..= 0xa003 => self.prg_banks[1] = (v & 0x1f) as usize,
+            0xb000 ..= 0xe003 => self.write_chr_bank(reg, v),
+            0xf000 if !self.is_vrc2 => self.irq.write_latch_low(v),
+            0xf001 if !self.is_vrc2 => self.irq.write_latch_high(v),
+            0xf002 if !self.is_vrc2 => self.irq.write_control(v),
+            0xf003 if !self.is_vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff if !self.prg_ram.is_empty() => self.prg_ram.read(addr),
+            // 下位1bitのみラッチ、それ以外はオープンバス
+            0x6000 ..= 0x6fff if self.is_vrc2 => Some(0x60 | self.vrc2_latch),
+            _ => None,
+        }
+    }
+
+    fn step_cycle(&mut self) {
+        self.irq.step_cycle();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramがある基板は6000に読み書きできてramがないvrc2はラッチになる() {
+        let mut m = Vrc4::new(vec![0; 0x8000], vec![], 0x2000, 21, 0);
+        m.write_prg(0x6123, 0x5a);
+        assert_eq!(m.peek_expansion(0x6123), Some(0x5a));
+        assert_eq!(m.battery_ram().map(|v| v[0x123]), Some(0x5a));
+
+        let mut m = Vrc4::new(vec![0; 0x8000], vec![], 0, 22, 0);
+        m.write_prg(0x6000, 0xff);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x61));
+        assert_eq!(m.peek_expansion(0x7000), None);
+        assert_eq!(m.battery_ram(), None);
+    }
+}
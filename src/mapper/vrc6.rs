@@ -0,0 +1,183 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, vrc6::Vrc6Audio};
+
+use super::{Mapper, Mirroring, PrgRam, vrc_irq::VrcIrq};
+
+// Konami VRC6 (mapper 24, 26)
+// https://www.nesdev.org/wiki/VRC6
+// mapper 26はレジスタのA0とA1が入れ替わっている
+pub struct Vrc6 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    prg_ram : PrgRam,
+    is_swap_a0_a1 : bool,
+
+    prg_bank_16k : usize,
+    prg_bank_8k : usize,
+    chr_banks : [usize; 8],
+    chr_mode : u8,
+    mirroring : Mirroring,
+
+    irq : VrcIrq,
+    audio : Vrc6Audio,
+}
+
+impl Debug for Vrc6 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "VRC6")
+    }
+}
+
+impl Vrc6 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize, mapper : u8) -> Self {
+        let mut prg_ram = PrgRam::new(prg_ram_size);
+        // $B003のbit 7で有効になる
+        prg_ram.is_enable = false;
+        Self {
+            prg,
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram,
+            is_swap_a0_a1: mapper == 26,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            chr_mode: 0,
+            mirroring: Mirroring::Vertical,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn register(&self, addr: u16) -> u16 {
+        if self.is_swap_a0_a1 {
+            addr & 0xf000 | (addr & 1) << 1 | (addr >> 1) & 1
+        } else {
+            addr & 0xf003
+        }
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let offset = match addr {
+            0x8000 ..= 0xbfff => self.prg_bank_16k * 0x4000 + (addr & 0x3fff),
+            0xc000 ..= 0xdfff => self.prg_bank_8k * 0x2000 + (addr & 0x1fff),
+            _ => self.prg.len() - 0x2000 + (addr & 0x1fff),
+        };
+        offset % self.prg.len()
+    }
+
+    // https://www.nesdev.org/wiki/VRC6#PPU_Banking_Style_($B003)
+    // CHR-ROMをネームテーブルに使うモードは未対応
+    fn chr_offset(&self, addr: usize) -> usize {
+        let a10 = (addr >> 10) & 1;
+        let bank = match (self.chr_mode, addr >> 10) {
+            (0, i) => self.chr_banks[i],
+            (1, i) => self.chr_banks[i >> 1] & !1 | a10,
+            (_, i) if i < 4 => self.chr_banks[i],
+            (_, i) => self.chr_banks[4 + ((i - 4) >> 1)] & !1 | a10,
+        };
+        (bank * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        if addr < 0x8000 {
+            if (0x6000..=0x7fff).contains(&addr) {
+                self.prg_ram.write(addr, v);
+            }
+            return;
+        }
+        let reg = self.register(addr);
+        match reg {
+            0x8000 ..= 0x8003 => self.prg_bank_16k = (v & 0x0f) as usize,
+            0x9000 ..= 0x9003 | 0xa000 ..= 0xa002 | 0xb000 ..= 0xb002 => self.audio.write(reg, v),
+            0xb003 => {
+                // W.PN MMDD  PRG RAM (W)、ミラーリング (M)、PPUバンクモード (D)
+                self.prg_ram.is_enable = v & 0x80 != 0;
+                self.chr_mode = v & 3;
+                self.mirroring = match (v >> 2) & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0xc000 ..= 0xc003 => self.prg_bank_8k = (v & 0x1f) as usize,
+            0xd000 ..= 0xd003 => self.chr_banks[(reg & 3) as usize] = v as usize,
+            0xe000 ..= 0xe003 => self.chr_banks[4 + (reg & 3) as usize] = v as usize,
+            0xf000 => self.irq.write_latch(v),
+            0xf001 => self.irq.write_control(v),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, _addr: u16, _v: u8) {
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn step_cycle(&mut self) {
+        self.irq.step_cycle();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prg_ramはb003のbit7で有効になる() {
+        let mut m = Vrc6::new(vec![0; 0x8000], vec![], 0x2000, 24);
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), None);
+        m.write_prg(0xb003, 0x80);
+        m.write_prg(0x6000, 0x12);
+        assert_eq!(m.peek_expansion(0x6000), Some(0x12));
+        assert_eq!(m.battery_ram().map(|v| v[0]), Some(0x12));
+    }
+}
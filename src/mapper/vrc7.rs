@@ -0,0 +1,195 @@
+use std::{fmt::Debug, ops::Range};
+
+use apu::{ExpansionAudio, vrc7::Vrc7Audio};
+
+use super::{Mapper, Mirroring, PrgRam, vrc_irq::VrcIrq};
+
+// Konami VRC7 (mapper 85)
+// https://www.nesdev.org/wiki/VRC7
+// VRC7aはA4、VRC7bはA3がレジスタの選択に繋がっている
+pub struct Vrc7 {
+    prg : Vec::<u8>,
+    chr : Vec::<u8>,
+    is_chr_ram : bool,
+    prg_ram : PrgRam,
+    // レジスタの下位を選択するCPUアドレスのビット
+    a_mask : u16,
+
+    prg_banks : [usize; 3],
+    chr_banks : [usize; 8],
+    mirroring : Mirroring,
+
+    irq : VrcIrq,
+    audio : Vrc7Audio,
+}
+
+impl Debug for Vrc7 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "VRC7")
+    }
+}
+
+impl Vrc7 {
+    pub fn new(prg : Vec::<u8>, chr : Vec::<u8>, prg_ram_size : usize, submapper : u8) -> Self {
+        // https://www.nesdev.org/wiki/NES_2.0_submappers#085:_Konami_VRC7
+        // サブマッパーが不明な場合は両方の配線のORを取る
+        let a_mask = match submapper {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+        let mut prg_ram = PrgRam::new(prg_ram_size);
+        // $E000のbit 7で有効になる
+        prg_ram.is_enable = false;
+        Self {
+            prg,
+            is_chr_ram: chr.is_empty(),
+            chr: if chr.is_empty() { vec![0; 0x2000] } else { chr },
+            prg_ram,
+            a_mask,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+
+    // $x000/$x010 に正規化する
+    fn register(&self, addr: u16) -> u16 {
+        let low = if addr & self.a_mask != 0 { 0x10 } else { 0 };
+        addr & 0xf000 | low
+    }
+
+    fn prg_offset(&self, addr: usize) -> usize {
+        let bank_count = self.prg.len() / 0x2000;
+        let bank = match (addr - 0x8000) / 0x2000 {
+            i @ 0 ..= 2 => self.prg_banks[i],
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * 0x2000 + (addr & 0x1fff)
+    }
+
+    fn chr_offset(&self, addr: usize) -> usize {
+        let bank = self.chr_banks[(addr >> 10) & 7];
+        (bank * 0x400 + (addr & 0x3ff)) % self.chr.len()
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn read_prg(&self, addr: usize) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+    fn read_prg_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.prg_offset(addr.start);
+        &self.prg[offset..offset + addr.len()]
+    }
+    fn write_prg(&mut self, addr: u16, v: u8) {
+        if addr < 0x8000 {
+            if addr >= 0x6000 {
+                self.prg_ram.write(addr, v);
+            }
+            return;
+        }
+        // 音源のレジスタはどちらの配線でもA4とA5で選ぶ
+        match addr & 0xf030 {
+            0x9010 => return self.audio.write_addr(v),
+            0x9030 => return self.audio.write_data(v),
+            _ => {}
+        }
+        match self.register(addr) {
+            0x8000 => self.prg_banks[0] = (v & 0x3f) as usize,
+            0x8010 => self.prg_banks[1] = (v & 0x3f) as usize,
+            0x9000 => self.prg_banks[2] = (v & 0x3f) as usize,
+            reg @ 0xa000 ..= 0xd010 => {
+                let i = (((reg >> 12) - 0xa) * 2 + ((reg >> 4) & 1)) as usize;
+                self.chr_banks[i] = v as usize;
+            }
+            0xe000 => {
+                // RS-- --MM  PRG RAM有効 (R)、音源リセット (S)、ミラーリング (M)
+                self.mirroring = match v & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+                self.audio.set_mute(v & 0x40 != 0);
+                self.prg_ram.is_enable = v & 0x80 != 0;
+            }
+            0xe010 => self.irq.write_latch(v),
+            0xf000 => self.irq.write_control(v),
+            0xf010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: usize) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn read_chr_range<'a>(&'a self, addr: Range<usize>) -> &'a [u8] {
+        let offset = self.chr_offset(addr.start);
+        &self.chr[offset..offset + addr.len()]
+    }
+    fn write_chr(&mut self, addr: u16, v: u8) {
+        // Lagrange Point はCHR-RAM
+        if !self.is_chr_ram {
+            return;
+        }
+        let offset = self.chr_offset(addr as usize);
+        self.chr[offset] = v;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram.read(addr),
+            _ => None,
+        }
+    }
+
+    fn step_cycle(&mut self) {
+        self.irq.step_cycle();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        if self.prg_ram.is_empty() { None } else { Some(self.prg_ram.data().to_vec()) }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn load_trainer(&mut self, data: &[u8]) {
+        self.prg_ram.load_trainer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vrc7aでも音源のレジスタはa4とa5で選ぶ() {
+        let prg = (0..0x10).flat_map(|i| vec![i as u8; 0x2000]).collect::<Vec<_>>();
+        let mut m = Vrc7::new(prg, vec![], 0x2000, 1);
+        m.write_prg(0x8008, 0x01);
+        m.write_prg(0x9000, 0x02);
+        assert_eq!(m.read_prg(0xa000), 0x01);
+        assert_eq!(m.read_prg(0xc000), 0x02);
+        // $9010と$9030は音源なので、PRGのバンクは変わらない
+        m.write_prg(0x9010, 0x05);
+        m.write_prg(0x9030, 0x06);
+        assert_eq!(m.read_prg(0xc000), 0x02);
+    }
+}
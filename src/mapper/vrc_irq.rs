@@ -0,0 +1,127 @@
+// VRC4/VRC6/VRC7共通のIRQカウンタ
+// https://www.nesdev.org/wiki/VRC_IRQ
+#[derive(Debug)]
+pub struct VrcIrq {
+    latch : u8,
+    counter : u8,
+    prescaler : i16,
+    is_enable : bool,
+    is_enable_after_ack : bool,
+    // true: CPUサイクルごと, false: スキャンライン相当(341/3サイクル)ごと
+    is_cycle_mode : bool,
+    is_pending : bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            is_enable: false,
+            is_enable_after_ack: false,
+            is_cycle_mode: false,
+            is_pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, v: u8) {
+        self.latch = v;
+    }
+
+    // VRC4はラッチを4bitずつ書き込む
+    pub fn write_latch_low(&mut self, v: u8) {
+        self.latch = self.latch & 0xf0 | v & 0x0f;
+    }
+
+    pub fn write_latch_high(&mut self, v: u8) {
+        self.latch = self.latch & 0x0f | (v & 0x0f) << 4;
+    }
+
+    // ---- -MEA  Mode (M), Enable (E), enable after Acknowledgement (A)
+    pub fn write_control(&mut self, v: u8) {
+        self.is_enable_after_ack = v & 1 != 0;
+        self.is_enable = v & 2 != 0;
+        self.is_cycle_mode = v & 4 != 0;
+        self.is_pending = false;
+        if self.is_enable {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.is_pending = false;
+        self.is_enable = self.is_enable_after_ack;
+    }
+
+    pub fn step_cycle(&mut self) {
+        if !self.is_enable {
+            return;
+        }
+        if self.is_cycle_mode {
+            self.clock_counter();
+        } else {
+            // 3ずつ減らして341ごとにカウントする (PPUの1ライン = 113.667 CPUサイクル)
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.is_pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.is_pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VrcIrq;
+
+    #[test]
+    fn サイクルモードは0xffを超えたサイクルでirqになる() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch_low(0x0c);
+        irq.write_latch_high(0x0f);
+        irq.write_control(0x06);
+        for _ in 0..(0x100 - 0xfc) {
+            assert!(!irq.irq());
+            irq.step_cycle();
+        }
+        assert!(irq.irq());
+
+        irq.acknowledge();
+        assert!(!irq.irq());
+        // A=0で書き込んでいたので、確認後は止まる
+        for _ in 0..0x200 {
+            irq.step_cycle();
+        }
+        assert!(!irq.irq());
+    }
+
+    #[test]
+    fn スキャンラインモードは341_3サイクルごとにカウントする() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch_low(0x0f);
+        irq.write_latch_high(0x0f);
+        irq.write_control(0x02);
+        // 1ライン(113.667サイクル)でカウンタが1回進む
+        for _ in 0..113 {
+            irq.step_cycle();
+        }
+        assert!(!irq.irq());
+        irq.step_cycle();
+        assert!(irq.irq());
+    }
+}
@@ -0,0 +1,216 @@
+use std::f32::consts::PI;
+use std::path::Path;
+
+// PPUの色番号からRGBへの変換表
+// 色番号(6bit)と色の強調(PPUMASK bit5-7の3bit)を合わせた512色を持つ
+// https://www.nesdev.org/wiki/PPU_palettes
+
+pub const PALETTE_SIZE : usize = 64 * 8;
+
+// 組み込みのパレット
+pub const PRESET_NAMES : [&str; 3] = ["famiko", "ntsc", "ntsc-vivid"];
+
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors : Vec<[u8; 3]>,
+}
+
+// NTSCの信号からパレットを作る時のパラメータ
+// https://www.nesdev.org/wiki/NTSC_video
+#[derive(Debug, Clone, Copy)]
+pub struct NtscParams {
+    // 色相のずれ (度)
+    pub hue : f32,
+    pub saturation : f32,
+    pub contrast : f32,
+    pub brightness : f32,
+    // 表示側のガンマ
+    pub gamma : f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 1.0,
+            gamma: 1.8,
+        }
+    }
+}
+
+impl Palette {
+    // .palファイル (RGBの3バイトが64色または512色並んだもの)
+    // 64色の場合は色の強調を計算で作る
+    pub fn from_pal(data : &[u8]) -> Result<Self, String> {
+        let colors = data.chunks(3).map(|c| [c[0], c[1], c[2]]);
+        match data.len() {
+            0xc0 => Ok(Self::with_emphasis(&colors.collect::<Vec<_>>())),
+            0x600 => Ok(Palette { colors: colors.collect() }),
+            n => Err(format!("invalid palette size {}", n)),
+        }
+    }
+
+    pub fn load(path : &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_pal(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // 組み込みのパレットか.palファイルを名前で選ぶ
+    pub fn from_name(name : &str) -> Result<Self, String> {
+        Self::preset(name).map_or_else(|| Self::load(Path::new(name)), Ok)
+    }
+
+    pub fn preset(name : &str) -> Option<Self> {
+        match name {
+            "famiko" => Some(Self::with_emphasis(&COLORS)),
+            "ntsc" => Some(Self::generate(&NtscParams::default())),
+            "ntsc-vivid" => Some(Self::generate(&NtscParams { saturation: 1.4, contrast: 1.1, ..Default::default() })),
+            _ => None,
+        }
+    }
+
+    // 強調したい色以外の成分を暗くして、512色にする
+    // bit5: 赤、bit6: 緑、bit7: 青 (NTSC)
+    // 3つとも立っている場合は全体が暗くなる
+    // https://www.nesdev.org/wiki/NTSC_video#Color_Tint_Bits
+    fn with_emphasis(colors : &[[u8; 3]]) -> Self {
+        const ATTENUATION : f32 = 0.816;
+        let colors = (0..8).flat_map(|emphasis| colors.iter().map(move |c| {
+            let mut c = *c;
+            if emphasis != 0 {
+                for (i, v) in c.iter_mut().enumerate() {
+                    if emphasis == 7 || emphasis & (1 << i) == 0 {
+                        *v = (*v as f32 * ATTENUATION) as u8;
+                    }
+                }
+            }
+            c
+        })).collect();
+        Palette { colors }
+    }
+
+    // PPUが出力する信号をYIQとして復調してRGBにする
+    // 1色あたり12の位相で、色番号の色相の位相の半分が高いレベル、残りが低いレベルの矩形波になる
+    // https://www.nesdev.org/wiki/NTSC_video#Brightness_Levels
+    pub fn generate(params : &NtscParams) -> Self {
+        // 信号のレベル (低、高)。輝度0-3
+        const LEVELS : [[f32; 4]; 2] = [[0.350, 0.518, 0.962, 1.550], [1.094, 1.506, 1.962, 1.962]];
+        const BLACK : f32 = 0.518;
+        const WHITE : f32 = 1.962;
+        const ATTENUATION : f32 = 0.746;
+
+        let in_phase = |color : usize, p : usize| (color + p + 8) % 12 < 6;
+        let gamma = |v : f32| if v <= 0.0 { 0.0 } else { v.powf(2.2 / params.gamma) };
+        let to_u8 = |v : f32| (gamma(v) * 255.95).clamp(0.0, 255.0) as u8;
+
+        let colors = (0..PALETTE_SIZE).map(|index| {
+            let color = index & 0x0f;
+            // $xE, $xFは黒
+            let level = if color > 0x0d { 1 } else { (index >> 4) & 3 };
+            let emphasis = index >> 6;
+            // $x0は高いレベルだけ、$xD以降は低いレベルだけ
+            let low = LEVELS[if color == 0 { 1 } else { 0 }][level];
+            let high = LEVELS[if color <= 0x0c { 1 } else { 0 }][level];
+
+            let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+            for p in 0..12 {
+                let mut spot = if in_phase(color, p) { high } else { low };
+                // 強調は赤、緑、青それぞれの位相の間だけ信号を弱める
+                if (emphasis & 1 != 0 && in_phase(12, p))
+                    || (emphasis & 2 != 0 && in_phase(4, p))
+                    || (emphasis & 4 != 0 && in_phase(8, p)) {
+                    spot *= ATTENUATION;
+                }
+                let v = (spot - BLACK) / (WHITE - BLACK);
+                let v = ((v - 0.5) * params.contrast + 0.5) * params.brightness / 12.0;
+                let phase = PI / 6.0 * p as f32 + params.hue.to_radians();
+                y += v;
+                i += v * phase.cos();
+                q += v * phase.sin();
+            }
+            i *= params.saturation;
+            q *= params.saturation;
+
+            // https://en.wikipedia.org/wiki/YIQ
+            [
+                to_u8(y + 0.946882 * i + 0.623557 * q),
+                to_u8(y - 0.274788 * i - 0.635691 * q),
+                to_u8(y - 1.108545 * i + 1.709007 * q),
+            ]
+        }).collect();
+        Palette { colors }
+    }
+
+    // 色番号(下位6bit)と強調(上位3bit)の9bitの値からRGB
+    pub fn rgb(&self, index : usize) -> &[u8; 3] {
+        &self.colors[index & (PALETTE_SIZE - 1)]
+    }
+
+    // PPUが出力した9bitのピクセルをRGBAに変換する
+    pub fn to_rgba(&self, pixels : &[u16], out : &mut [u8]) {
+        for (p, out) in pixels.iter().zip(out.chunks_exact_mut(4)) {
+            out[0..3].copy_from_slice(self.rgb(*p as usize));
+            out[3] = 0xff;
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::with_emphasis(&COLORS)
+    }
+}
+
+static COLORS : [[u8;3];64]= [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+  ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palファイルは64色と512色を読める() {
+        let pal = (0..64 * 3).map(|i| i as u8).collect::<Vec<_>>();
+        let palette = Palette::from_pal(&pal).unwrap();
+        assert_eq!(palette.rgb(1), &[3, 4, 5]);
+        // 赤の強調では緑と青が暗くなる
+        assert_eq!(palette.rgb(0x40 | 1), &[3, 3, 4]);
+        // 3つとも強調するとすべて暗くなる
+        assert_eq!(palette.rgb(0x1c0 | 1), &[2, 3, 4]);
+
+        let pal = (0..PALETTE_SIZE * 3).map(|i| (i / 3) as u8).collect::<Vec<_>>();
+        let palette = Palette::from_pal(&pal).unwrap();
+        assert_eq!(palette.rgb(0x141), &[0x41, 0x41, 0x41]);
+
+        assert!(Palette::from_pal(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn ntscのパレットを作る() {
+        let palette = Palette::generate(&NtscParams::default());
+        // $0Fは黒、$20は白
+        assert_eq!(palette.rgb(0x0f), &[0, 0, 0]);
+        assert!(palette.rgb(0x20).iter().all(|v| *v > 0xf0));
+        // $16は赤っぽい
+        let c = palette.rgb(0x16);
+        assert!(c[0] > c[1] && c[0] > c[2]);
+    }
+}
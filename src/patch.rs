@@ -0,0 +1,304 @@
+use crate::rom_db::crc32;
+
+// ROMのパッチ (IPS, UPS, BPS)
+// ヘッダを含むファイル全体に当てる
+
+// https://zerosoft.zophar.net/ips.php
+// 3バイトのオフセット、2バイトのサイズ、データ。サイズ0はRLE。EOFの後に3バイトあれば切り詰め
+pub fn apply_ips(data : &[u8], ips : &[u8]) -> Result<Vec::<u8>, String> {
+    if !ips.starts_with(b"PATCH") {
+        return Err("not an IPS patch".into());
+    }
+    let mut out = data.to_vec();
+    let mut p = 5;
+    let read = |p : usize, n : usize| -> Result<usize, String> {
+        let bytes = ips.get(p..p + n).ok_or("IPS patch is truncated")?;
+        Ok(bytes.iter().fold(0, |acc, v| acc << 8 | *v as usize))
+    };
+    loop {
+        if ips.get(p..p + 3) == Some(b"EOF") {
+            p += 3;
+            break;
+        }
+        let offset = read(p, 3)?;
+        let size = read(p + 3, 2)?;
+        p += 5;
+        let (bytes, len) = if size == 0 {
+            let count = read(p, 2)?;
+            let value = read(p + 2, 1)? as u8;
+            p += 3;
+            (vec![value; count], count)
+        } else {
+            let bytes = ips.get(p..p + size).ok_or("IPS patch is truncated")?.to_vec();
+            p += size;
+            (bytes, size)
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        out[offset..offset + len].copy_from_slice(&bytes);
+    }
+    if let Ok(size) = read(p, 3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// 元のデータとの差分をIPSにする (ディスクシステムの書き込みの保存用)
+// データの長さは変わらないものとする
+pub fn make_ips(original : &[u8], modified : &[u8]) -> Vec::<u8> {
+    let mut ips = b"PATCH".to_vec();
+    let mut i = 0;
+    while i < modified.len() {
+        if modified[i] == original[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < modified.len() && i - start < 0xffff && modified[i] != original[i] {
+            i += 1;
+        }
+        ips.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        ips.extend_from_slice(&((i - start) as u16).to_be_bytes());
+        ips.extend_from_slice(&modified[start..i]);
+    }
+    ips.extend_from_slice(b"EOF");
+    ips
+}
+
+// UPS, BPSの可変長整数
+// https://www.romhacking.net/documents/746/
+fn read_number(patch : &[u8], p : &mut usize) -> Result<usize, String> {
+    let mut data = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let x = *patch.get(*p).ok_or("patch is truncated")?;
+        *p += 1;
+        data = data.checked_add((x & 0x7f) as usize * shift).ok_or("patch number overflow")?;
+        if x & 0x80 != 0 {
+            return Ok(data);
+        }
+        shift = shift.checked_shl(7).ok_or("patch number overflow")?;
+        data = data.checked_add(shift).ok_or("patch number overflow")?;
+    }
+}
+
+// 末尾の12バイトは 元ファイル、パッチ後のファイル、パッチ自身のCRC32
+fn read_footer(patch : &[u8]) -> Result<[u32; 3], String> {
+    if patch.len() < 16 {
+        return Err("patch is truncated".into());
+    }
+    let footer = &patch[patch.len() - 12..];
+    let crc = |i : usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+    let v = [crc(0), crc(4), crc(8)];
+    if crc32(&patch[..patch.len() - 4]) != v[2] {
+        return Err("patch checksum mismatch".into());
+    }
+    Ok(v)
+}
+
+// https://www.romhacking.net/documents/392/
+// 差分のXORなので、パッチ後のファイルに当てると元に戻る
+pub fn apply_ups(data : &[u8], ups : &[u8]) -> Result<Vec::<u8>, String> {
+    if !ups.starts_with(b"UPS1") {
+        return Err("not a UPS patch".into());
+    }
+    let [source_crc, target_crc, _] = read_footer(ups)?;
+    let mut p = 4;
+    let source_size = read_number(ups, &mut p)?;
+    let target_size = read_number(ups, &mut p)?;
+    let data_crc = crc32(data);
+    let (out_size, expect_crc) = if data.len() == source_size && data_crc == source_crc {
+        (target_size, target_crc)
+    } else if data.len() == target_size && data_crc == target_crc {
+        (source_size, source_crc)
+    } else {
+        return Err("UPS patch does not match the rom".into());
+    };
+
+    let mut out = data.to_vec();
+    out.resize(out_size, 0);
+    let end = ups.len() - 12;
+    let mut offset = 0;
+    while p < end {
+        offset += read_number(ups, &mut p)?;
+        loop {
+            let x = *ups.get(p).ok_or("UPS patch is truncated")?;
+            p += 1;
+            if offset < out.len() {
+                out[offset] ^= x;
+            }
+            offset += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    if crc32(&out) != expect_crc {
+        return Err("UPS result checksum mismatch".into());
+    }
+    Ok(out)
+}
+
+// https://www.romhacking.net/documents/746/
+pub fn apply_bps(data : &[u8], bps : &[u8]) -> Result<Vec::<u8>, String> {
+    if !bps.starts_with(b"BPS1") {
+        return Err("not a BPS patch".into());
+    }
+    let [source_crc, target_crc, _] = read_footer(bps)?;
+    if crc32(data) != source_crc {
+        return Err("BPS patch does not match the rom".into());
+    }
+    let mut p = 4;
+    let source_size = read_number(bps, &mut p)?;
+    let target_size = read_number(bps, &mut p)?;
+    let metadata_size = read_number(bps, &mut p)?;
+    p += metadata_size;
+    if data.len() != source_size {
+        return Err("BPS source size mismatch".into());
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let end = bps.len() - 12;
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    let relative = |v : usize| if v & 1 != 0 { -((v >> 1) as isize) } else { (v >> 1) as isize };
+    while p < end {
+        let v = read_number(bps, &mut p)?;
+        let len = (v >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err("BPS target overflow".into());
+        }
+        match v & 3 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(data.get(start..start + len).ok_or("BPS source read out of range")?);
+            }
+            // TargetRead
+            1 => {
+                out.extend_from_slice(bps.get(p..p + len).ok_or("BPS patch is truncated")?);
+                p += len;
+            }
+            // SourceCopy
+            2 => {
+                source_offset += relative(read_number(bps, &mut p)?);
+                let start = usize::try_from(source_offset).map_err(|_| "BPS source copy out of range")?;
+                out.extend_from_slice(data.get(start..start + len).ok_or("BPS source copy out of range")?);
+                source_offset += len as isize;
+            }
+            // TargetCopy 書いたばかりのデータと重なることがあるので1バイトずつ
+            _ => {
+                target_offset += relative(read_number(bps, &mut p)?);
+                for _ in 0..len {
+                    let v = usize::try_from(target_offset).ok().and_then(|i| out.get(i).copied())
+                        .ok_or("BPS target copy out of range")?;
+                    out.push(v);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size || crc32(&out) != target_crc {
+        return Err("BPS result checksum mismatch".into());
+    }
+    Ok(out)
+}
+
+// 先頭のマジックで形式を判別して当てる
+pub fn apply_patch(data : &[u8], patch : &[u8]) -> Result<Vec::<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(data, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(data, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(data, patch)
+    } else {
+        Err("unknown patch format".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_number(out : &mut Vec<u8>, mut v : usize) {
+        loop {
+            let x = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            v -= 1;
+        }
+    }
+
+    fn finish(mut patch : Vec<u8>, source : &[u8], target : &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ipsのrleと切り詰め() {
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        ips.extend_from_slice(&[0, 0, 4, 0, 0, 0, 3, 0xcc]);
+        ips.extend_from_slice(b"EOF");
+        ips.extend_from_slice(&[0, 0, 6]);
+        let out = apply_patch(&[0; 4], &ips).unwrap();
+        assert_eq!(out, [0, 0xaa, 0xbb, 0, 0xcc, 0xcc]);
+    }
+
+    #[test]
+    fn 作ったipsを当てると変更後の内容になる() {
+        let original = (0..0x100).map(|i| i as u8).collect::<Vec<_>>();
+        let mut modified = original.clone();
+        modified[0x10] = 0x11;
+        modified[0x12] = 0x22;
+        modified[0xff] = 0x33;
+        let ips = make_ips(&original, &modified);
+        assert_eq!(apply_ips(&original, &ips).unwrap(), modified);
+        // 差分がなければレコードもない
+        assert_eq!(make_ips(&original, &original), b"PATCHEOF");
+    }
+
+    #[test]
+    fn upsとbpsはチェックサムを確認する() {
+        let source = b"HELLO WORLD".to_vec();
+        let target = b"HELLO NES WORLD".to_vec();
+
+        // UPS: 6バイト目からのXOR差分
+        let mut ups = b"UPS1".to_vec();
+        write_number(&mut ups, source.len());
+        write_number(&mut ups, target.len());
+        write_number(&mut ups, 6);
+        let mut padded = source.clone();
+        padded.resize(target.len(), 0);
+        ups.extend(padded[6..].iter().zip(&target[6..]).map(|(a, b)| a ^ b));
+        ups.push(0);
+        let ups = finish(ups, &source, &target);
+        assert_eq!(apply_patch(&source, &ups).unwrap(), target);
+        // 逆向きにも当てられる
+        assert_eq!(apply_patch(&target, &ups).unwrap(), source);
+        assert!(apply_patch(b"HELLO", &ups).is_err());
+
+        // BPS: "HELLO " をSourceRead、"NES " をTargetRead、"WORLD" をSourceCopy
+        let mut bps = b"BPS1".to_vec();
+        write_number(&mut bps, source.len());
+        write_number(&mut bps, target.len());
+        write_number(&mut bps, 0);
+        write_number(&mut bps, (6 - 1) << 2);
+        write_number(&mut bps, (4 - 1) << 2 | 1);
+        bps.extend_from_slice(b"NES ");
+        write_number(&mut bps, (5 - 1) << 2 | 2);
+        write_number(&mut bps, 6 << 1);
+        let bps = finish(bps, &source, &target);
+        assert_eq!(apply_patch(&source, &bps).unwrap(), target);
+        assert!(apply_patch(&target, &bps).is_err());
+    }
+}
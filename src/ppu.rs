@@ -1,21 +1,35 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::mapper::Mapper;
+use crate::mapper::{Mapper, Mirroring, PpuFetch};
+use crate::palette::Palette;
 
 pub const WIDTH: usize = 256;
 pub const HEIGHT: usize = 240;
-pub const FRAME_SIZE : usize = WIDTH * HEIGHT * 4;
+// 1画面のピクセル数。RGBAに変換する場合はこの4倍のバイト数になる
+pub const FRAME_SIZE : usize = WIDTH * HEIGHT;
 
 pub const CHR_DEBUG_WIDTH : usize = 16 * 8 * 2;
 pub const CHR_DEBUG_HEIGT : usize = 16 * 8;
 pub const CHR_DEBUG_FRAME_SIZE : usize = CHR_DEBUG_HEIGT * CHR_DEBUG_WIDTH;
 
+// 8x16のスプライトも表示できるように、1つ分の高さを16にする
 pub const SPRITE_DEBUG_WIDTH : usize = 8 * 8;
-pub const SPRITE_DEBUG_HEIGT : usize = 8 * 8;
+pub const SPRITE_DEBUG_HEIGT : usize = 8 * 16;
 pub const SPRITE_DEBUG_FRAME_SIZE : usize = SPRITE_DEBUG_HEIGT * SPRITE_DEBUG_WIDTH;
 
 const CLEAR_COLOR : u8 = 0x40;
 
+// I/Oラッチの値が消えるまでのフレーム数 (約600ms)
+const IO_LATCH_DECAY_FRAMES : usize = 36;
+
+// 1ラインに表示できるスプライトの数
+const SPRITES_PER_LINE : usize = 8;
+
+// line_spriteの各ドットの値
+// 下位5bitがパレットのアドレス ($10-$1F、0は透明)
+const SPRITE_BEHIND_BG : u8 = 0x20;
+const SPRITE_ZERO : u8 = 0x40;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct PPU {
@@ -32,34 +46,67 @@ pub struct PPU {
 
     togle : bool,
     
-    is_mirror_horizontal: bool,
+    mirroring: Mirroring,
     vram_addr: u16,
     temp_vram_addr: u16,
     x_value : u8,
     sprite_addr : u8,
-    scroll_x : u8,
-    scroll_y : u8,
     palette_ram : [u8; 0x20],
     name_table : [u8; 0x400 * 4],
     mapper : Rc<RefCell<Box<dyn Mapper>>>,
     sprite_ram : [u8; 0x100],
 
     read_buffer : u8,
+    io_latch : u8,
+    // I/Oラッチの各bitに最後に値が乗ったフレーム
+    io_latch_frame : [usize; 8],
+    frame_count : usize,
     
     x : usize,
     y : usize,
 
     pub nmi : bool,
+    // NMIの出力 (VBlankフラグ && PPUCTRL bit7)。立ち上がりでCPUにNMIが入る
+    nmi_output : bool,
+    // VBlankフラグが立つ直前に$2002を読まれた場合、そのフレームはフラグが立たない
+    suppress_vblank : bool,
+    is_odd_frame : bool,
+    // catch_upで先に進めたドット数と、その間にフレームが完成したか
+    ahead_dots : usize,
+    is_ahead_frame_ready : bool,
+
+    // 各ピクセルは色番号(下位6bit)と色の強調(PPUMASK bit5-7、上位3bit)の9bit
+    // RGBへの変換はPaletteで行う
+    // 描画中のフレームと、最後に完成したフレーム
+    frame : Vec<u16>,
+    completed_frame : Vec<u16>,
 
-    frame: Vec<u8>,
+    // 背景のシフトレジスタ
+    // https://www.nesdev.org/wiki/PPU_rendering
+    // 上位8bitが描画中のタイル、下位8bitが次のタイル
+    bg_pattern_lo : u16,
+    bg_pattern_hi : u16,
+    bg_attr_lo : u16,
+    bg_attr_hi : u16,
+    // 次のタイルとして読み出した値 (8ドットごとにシフトレジスタに入る)
+    next_tile : u8,
+    next_attr : u8,
+    next_pattern_lo : u8,
+    next_pattern_hi : u8,
 
-    frame_sprite_fg: Vec<u8>,
-    frame_sprite_bg: Vec<u8>,
-    frame_bg: RefCell<Vec<u8>>,
+    // セカンダリOAM。次のラインに表示するスプライトのOAMの内容
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    secondary_oam : Vec<u8>,
+    is_sprite_0_in_line : bool,
+    // 次のラインのスプライトをドットごとに展開したもの
+    line_sprite : [u8; WIDTH],
+
+    // 1ライン8個の制限をなくす (ちらつき軽減)。オーバーフローフラグは実機通りに立てる
+    pub remove_sprite_limit : bool,
 }
 
 impl PPU {
-    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>, is_mirror_horizontal: bool) -> Self {
+    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>, mirroring: Mirroring) -> Self {
         PPU { 
             ppuctrl: 0,
             ppumask: 0,
@@ -71,25 +118,41 @@ impl PPU {
             ppudata: 0,
             oamdma: 0,
             togle: false,
-            is_mirror_horizontal,
+            mirroring,
             vram_addr: 0,
             temp_vram_addr: 0,
             x_value : 0,
             sprite_addr: 0,
-            scroll_x: 0,
-            scroll_y: 0,
             palette_ram: [0; 0x20],
             name_table: [0; 0x400 * 4],
             mapper: mapper,
             sprite_ram: [0; 0x100],
             read_buffer : 0,
+            io_latch: 0,
+            io_latch_frame: [0; 8],
+            frame_count: 0,
             nmi : false,
+            nmi_output: false,
+            suppress_vblank: false,
+            is_odd_frame: false,
+            ahead_dots: 0,
+            is_ahead_frame_ready: false,
             x: 0,
             y: 0,
-            frame: [0].repeat(FRAME_SIZE),
-            frame_sprite_bg: [0].repeat(WIDTH*HEIGHT),
-            frame_sprite_fg: [0].repeat(WIDTH*HEIGHT),
-            frame_bg: RefCell::new([0].repeat(WIDTH*HEIGHT*4)),
+            frame: vec![0; FRAME_SIZE],
+            completed_frame: vec![0; FRAME_SIZE],
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attr_lo: 0,
+            bg_attr_hi: 0,
+            next_tile: 0,
+            next_attr: 0,
+            next_pattern_lo: 0,
+            next_pattern_hi: 0,
+            secondary_oam: Vec::with_capacity(64 * 4),
+            is_sprite_0_in_line: false,
+            line_sprite: [0; WIDTH],
+            remove_sprite_limit: false,
          }
     }
 
@@ -108,6 +171,13 @@ impl PPU {
             self.ppustatus & !(1u8 << 7)
         }
     }
+    fn update_sprite_overflow(&mut self, b: bool) {
+        self.ppustatus = if b {
+            self.ppustatus | (1u8 << 5)
+        } else {
+            self.ppustatus & !(1u8 << 5)
+        }
+    }
     fn update_sprite_0_hit(&mut self, b: bool) {
         self.ppustatus = if b {
             self.ppustatus | (1u8 << 6)
@@ -116,15 +186,37 @@ impl PPU {
         }
     }
 
+    // VBlankフラグとPPUCTRL bit7からNMIの出力を更新する
+    // VBlank中にbit7を0から1にした場合もNMIが入る
+    fn update_nmi(&mut self) {
+        let output = self.ppustatus & (1 << 7) != 0 && self.ppuctrl & (1 << 7) != 0;
+        if output && !self.nmi_output {
+            self.nmi = true;
+        }
+        self.nmi_output = output;
+    }
+
+    // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
     pub fn read_status(&mut self) -> u8 {
+        if self.y == 241 {
+            match self.x {
+                // フラグが立つ1ドット前に読むと、0が読めてそのフレームはフラグもNMIも起きない
+                1 => self.suppress_vblank = true,
+                // フラグが立ったのと同時か直後に読むと、1が読めるがNMIは起きない
+                2 | 3 => self.nmi = false,
+                _ => {}
+            }
+        }
         let status = self.ppustatus;
         self.update_vblank(false);
+        self.update_nmi();
         self.togle = false;
         status
     }
 
     pub fn write_ppuctrl(&mut self, v : u8) {
         self.ppuctrl = v;
+        self.update_nmi();
 
         // t: ...GH.. ........ <- d: ......GH
         //    <used elsewhere> <- d: ABCDEF..
@@ -134,7 +226,6 @@ impl PPU {
     pub fn write_ppuscroll(&mut self, v : u8) {
         match self.togle {
             false => { 
-                self.scroll_x = v;
                 // t: ....... ...ABCDE <- d: ABCDE...
                 // x:              FGH <- d: .....FGH
                 // w:                  <- 1
@@ -142,20 +233,15 @@ impl PPU {
                 self.x_value = v & 0x07;
             }
             true => { 
-                self.scroll_y = v;
                 // t: FGH..AB CDE..... <- d: ABCDEFGH
                 // w:                  <- 0
-                self.temp_vram_addr = self.temp_vram_addr & !0x0c1f | ((v as u16) & 0x07 << 12) | ((v as u16) & 0xf8 << 2);
+                self.temp_vram_addr = self.temp_vram_addr & !0x73e0 | ((v as u16) & 0x07) << 12 | ((v as u16) & 0xf8) << 2;
             }
         }
         self.togle = !self.togle;
     }
 
     pub fn write_ppuaddr(&mut self, v : u8) {
-        //self.vram_addr = self.vram_addr << 8 | v as u16;
-        if !self.togle {
-            self.ppuctrl = self.ppuctrl & !0x3u8 | v & 0x3u8;
-        }
         match self.togle {
             false => {
                 self.temp_vram_addr = self.temp_vram_addr & 0x00ff | (((v as u16) & 0x3f) << 8);
@@ -168,38 +254,112 @@ impl PPU {
         self.togle = !self.togle;
     }
 
-    // https://www.nesdev.org/wiki/PPU_memory_map
-    pub fn read_ppudata(&mut self, is_increment : bool) -> u8 {
-        let read_for_buffer = match self.vram_addr {
-            0x0000 ..= 0x1fff => {
-                self.mapper.borrow().read_chr(self.vram_addr as usize)
+    // https://www.nesdev.org/wiki/PPU_registers
+    // 書き込み専用のレジスタを読むと、最後にPPUのI/Oバスに乗った値が読める
+    pub fn read_register(&mut self, addr : u16, is_debug : bool) -> u8 {
+        if is_debug {
+            return self.peek_register(addr);
+        }
+        match addr & 7 {
+            2 => {
+                let v = self.read_status() & 0xe0 | self.io_latch() & 0x1f;
+                self.refresh_io_latch(v, 0xe0);
+                v
             }
-            0x2000 ..= 0x3fff => {
-                let a = (self.vram_addr as usize - 0x2000) % 0x1000;
-                let a = match self.is_mirror_horizontal {
-                    true => a & !0x400,
-                    false => a & !0x800,
-                };
-                self.name_table[a]
+            4 => {
+                let v = self.read_ppu_sprite_data();
+                self.refresh_io_latch(v, 0xff);
+                v
             }
-            _ => {
-                println!(" ppu cant read {:04X}", self.vram_addr);
-                panic!("not impl ppu read addr");
+            7 => {
+                // パレットは6bitなので上位2bitはラッチの値になる
+                if self.vram_addr & 0x3f00 == 0x3f00 {
+                    let v = self.read_ppudata(true) & 0x3f | self.io_latch() & 0xc0;
+                    self.refresh_io_latch(v, 0x3f);
+                    v
+                } else {
+                    let v = self.read_ppudata(true);
+                    self.refresh_io_latch(v, 0xff);
+                    v
+                }
+            }
+            _ => self.io_latch(),
+        }
+    }
+
+    // デバッグ用の読み出し。フラグや読み出しバッファ、I/Oラッチを変えず、マッパーにも通知しない
+    // $2007はCPUが読んだ場合に返る値 (パレット以外はバッファの値) を返す
+    fn peek_register(&self, addr : u16) -> u8 {
+        match addr & 7 {
+            2 => self.ppustatus & 0xe0 | self.peek_io_latch() & 0x1f,
+            4 => self.read_ppu_sprite_data(),
+            7 => {
+                let addr = self.vram_addr & 0x3fff;
+                if addr & 0x3f00 == 0x3f00 {
+                    self.palette_ram[palette_addr(addr)] & 0x3f | self.peek_io_latch() & 0xc0
+                } else {
+                    self.read_buffer
+                }
+            }
+            _ => self.peek_io_latch(),
+        }
+    }
+
+    pub fn write_register(&mut self, addr : u16, v : u8) {
+        self.refresh_io_latch(v, 0xff);
+        match addr & 7 {
+            0 => self.write_ppuctrl(v),
+            1 => self.ppumask = v,
+            2 => {}
+            3 => self.write_ppu_sprite_addr(v),
+            4 => self.write_ppu_sprite_data(v),
+            5 => self.write_ppuscroll(v),
+            6 => self.write_ppuaddr(v),
+            _ => self.write_ppudata(v),
+        }
+    }
+
+    // I/Oラッチはしばらく値が乗らないと0に戻る (1bitずつ、600ms程度)
+    // https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+    fn io_latch(&mut self) -> u8 {
+        self.io_latch = self.peek_io_latch();
+        self.io_latch
+    }
+
+    fn peek_io_latch(&self) -> u8 {
+        let mut v = self.io_latch;
+        for i in 0..8 {
+            if self.frame_count.wrapping_sub(self.io_latch_frame[i]) > IO_LATCH_DECAY_FRAMES {
+                v &= !(1 << i);
+            }
+        }
+        v
+    }
+
+    fn refresh_io_latch(&mut self, v : u8, mask : u8) {
+        self.io_latch = self.io_latch & !mask | v & mask;
+        for i in 0..8 {
+            if mask & (1 << i) != 0 {
+                self.io_latch_frame[i] = self.frame_count;
             }
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_memory_map
+    // アドレスは14bitで、$3000-$3EFFは$2000-$2EFFのミラー、$3F20-$3FFFはパレットのミラー
+    pub fn read_ppudata(&mut self, is_increment : bool) -> u8 {
+        let addr = self.vram_addr & 0x3fff;
+        let read_for_buffer = match addr {
+            0x0000 ..= 0x1fff => self.fetch_chr(addr as usize),
+            // パレットを読んだ場合も、バッファには下にあるネームテーブルの値が入る
+            _ => self.fetch_name_table((addr & 0x2fff) as usize),
         };
         if is_increment {
-            if self.ppuctrl & 4 != 0 {
-                self.vram_addr += 32;
-            } else {
-                self.vram_addr += 1;
-            }
+            self.increment_vram_addr();
         };
         // パレットのみ値がすぐに読める
-        let ret = match self.vram_addr {
-            0x3f00 ..= 0x3fff => {
-                let a = (self.vram_addr & 0x001f) as usize;
-                self.palette_ram[a]
-            }
+        let ret = match addr {
+            0x3f00 ..= 0x3fff => self.palette_ram[palette_addr(addr)],
             _ => self.read_buffer
         };
         self.read_buffer = read_for_buffer;
@@ -209,134 +369,438 @@ impl PPU {
     pub fn write_ppu_sprite_addr(&mut self, v: u8) {
         self.sprite_addr = v;
     }
+
+    // https://www.nesdev.org/wiki/PPU_OAM#Byte_2
+    // 属性のbit2-4は存在しないので0が読める
     pub fn write_ppu_sprite_data(&mut self, v: u8) {
+        let v = if self.sprite_addr & 3 == 2 { v & 0xe3 } else { v };
         self.sprite_ram[self.sprite_addr as usize] = v;
+        self.sprite_addr = self.sprite_addr.wrapping_add(1);
+    }
+
+    pub fn read_ppu_sprite_data(&self) -> u8 {
+        self.sprite_ram[self.sprite_addr as usize]
     }
 
     pub fn write_ppudata(&mut self, v : u8) {
-        match self.vram_addr {
+        let addr = self.vram_addr & 0x3fff;
+        match addr {
             0x0000 ..= 0x1fff => {
-                self.mapper.borrow_mut().write_chr(self.vram_addr, v)
+                self.mapper.borrow_mut().write_chr(addr, v)
             }
-            0x2000 ..= 0x3eff | 0x3f20 ..= 0x3fff => {
-                let a = (self.vram_addr as usize - 0x2000) % 0x1000;
-                let a = match self.is_mirror_horizontal {
-                    true => a & !0x400,
-                    false => a & !0x800,
-                };
-                self.name_table[a] = v;
-            }
-            0x3f00 ..= 0x3f1f => {
-                let a = (self.vram_addr & 0x001f) as usize;
-                if a % 4 == 0 {
-                    let a = a & 0x0f;
-                    self.palette_ram[a] = v;
-                    self.palette_ram[a | 0x10 ] = v;
-                } else {
-                    self.palette_ram[a] = v;
+            0x2000 ..= 0x3eff => {
+                let addr = addr & 0x2fff;
+                if !self.mapper.borrow_mut().write_name_table(addr, v) {
+                    let a = self.name_table_offset(addr as usize);
+                    self.name_table[a] = v;
                 }
             }
             _ => {
-                println!(" ppu cant write {:04x} {:02X}", self.vram_addr, v);
-                panic!("not impl ppu write addr");
+                // パレットは6bitだけ持つ。上位2bitを残すと透明(CLEAR_COLOR)と区別できなくなる
+                self.palette_ram[palette_addr(addr)] = v & 0x3f;
             }
         }
-        if self.ppuctrl & 4 != 0 {
-            self.vram_addr += 32;
+        self.increment_vram_addr();
+    }
+
+    // $2007の読み書きの後のvの更新
+    // 描画中は+1/+32ではなく、粗いXとYのインクリメントが同時に起きる
+    // https://www.nesdev.org/wiki/PPU_scrolling#$2007_reads_and_writes
+    fn increment_vram_addr(&mut self) {
+        if self.is_rendering() && (self.y < HEIGHT || self.y == 261) {
+            self.increment_x();
+            self.increment_y();
         } else {
-            self.vram_addr += 1;
+            let n = if self.ppuctrl & 4 != 0 { 32 } else { 1 };
+            self.vram_addr = (self.vram_addr + n) & 0x7fff;
         }
     }
     
+    // OAMADDRの位置から256バイト書き込む
     pub fn write_dma(&mut self, data : &[u8]) {
-        self.sprite_ram.clone_from_slice(data)
-    }
-
-    pub fn step(&mut self, cycle : usize) -> Option<Box<Vec<u8>>> {
-        let mut ret : Option<Box<Vec<u8>>> =  None;
-        for _ in 0..cycle {
-            if self.x == 340 {
-                // lineの最後で描画する
-                if self.y == 0 {
-                    // 最初にそれぞれのフレームを描く
-                    self.init_frame();
-                    self.write_sprite(&mut None::<Vec<u8>>);
-                    self.write_frame_bg();
-                }
+        for v in data {
+            self.write_ppu_sprite_data(*v);
+        }
+    }
 
-                if self.y < HEIGHT {
-                    // 1ラインずつコピーしていく
-                    let sprite_0_hit = self.write_line(self.y);
-                    if sprite_0_hit {
-                        self.update_sprite_0_hit(true);
-                    }
-                }
+    fn is_rendering(&self) -> bool {
+        self.ppumask & 0x18 != 0
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.ppuctrl & (1 << 5) != 0 { 16 } else { 8 }
+    }
+
+    // スプライトのrow行目のパターンのアドレス
+    // 8x16の場合はタイル番号のbit0でパターンテーブルを選び、上半分が偶数、下半分が奇数のタイルになる
+    // https://www.nesdev.org/wiki/PPU_OAM#Byte_1
+    fn sprite_pattern_addr(&self, tile : u8, row : usize) -> usize {
+        if self.sprite_height() == 16 {
+            let base = (tile as usize & 1) * 0x1000;
+            let tile = (tile as usize & 0xfe) + row / 8;
+            base + tile * 16 + row % 8
+        } else {
+            let base = if self.ppuctrl & 0x08 != 0 { 0x1000usize } else { 0x0000usize };
+            base + tile as usize * 16 + row
+        }
+    }
+
+    // フレームが完成した場合にtrueを返す。完成したフレームはframeで取り出す
+    pub fn step(&mut self, cycle : usize) -> bool {
+        // catch_upで進めた分は飛ばす
+        let skip = cycle.min(self.ahead_dots);
+        self.ahead_dots -= skip;
+        let mut is_ready = std::mem::take(&mut self.is_ahead_frame_ready);
+        for _ in skip..cycle {
+            is_ready |= self.step_one();
+        }
+        is_ready
+    } 
+
+    // CPUの命令の途中でレジスタにアクセスする場合に、命令の開始からdotsドットの位置までPPUを先に進める
+    // リードモディファイライト命令のように1命令で2回アクセスする場合は、まだ進めていない分だけ進める
+    // 進めた分は次のstepで差し引く
+    pub fn catch_up(&mut self, dots : usize) {
+        for _ in self.ahead_dots..dots {
+            self.is_ahead_frame_ready |= self.step_one();
+        }
+        self.ahead_dots = self.ahead_dots.max(dots);
+    }
+
+    // 最後に完成したフレーム (9bitの色番号)
+    pub fn completed_frame(&self) -> &[u16] {
+        &self.completed_frame
+    }
+
+    // 最後に完成したフレームをRGBAに変換して書き込む
+    pub fn render_frame(&self, palette : &Palette, out : &mut [u8]) {
+        palette.to_rgba(&self.completed_frame, out);
+    }
+
+    fn step_one(&mut self) -> bool {
+        self.step_dot();
+
+        // 奇数フレームで描画が有効な場合は、プリレンダーラインの最後の1ドットを飛ばす
+        // https://www.nesdev.org/wiki/PPU_frame_timing#Even/Odd_Frames
+        if self.y == 261 && self.x == 339 && self.is_odd_frame && self.is_rendering() {
+            self.x = 340;
+        }
+
+        self.x += 1;
+        if self.x >= 341 {
+            self.x = 0;
+            self.y += 1;
+            if self.y >= 262 {
+                self.y = 0;
+                self.is_odd_frame = !self.is_odd_frame;
+                self.frame_count += 1;
+                std::mem::swap(&mut self.frame, &mut self.completed_frame);
+                return true;
             }
+        }
+        false
+    }
+
+    // 1ドット分の処理
+    // https://www.nesdev.org/wiki/PPU_rendering
+    // https://www.nesdev.org/wiki/File:Ppu.svg
+    fn step_dot(&mut self) {
+        let dot = self.x;
+        let line = self.y;
+        let is_visible_line = line < HEIGHT;
+        let is_pre_render_line = line == 261;
+
+        // 321ドットから次のラインの先頭2タイルの先読みが始まるので、マッパーにはここで次のラインを通知する
+        if dot == 321 {
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.notify_ppu_scanline((line + 1) % 262);
+            mapper.notify_ppu_fetch(PpuFetch::Background);
+        }
+
+        // VBlankフラグは241ラインの1ドット目で立ち、プリレンダーラインの1ドット目で落ちる
+        if dot == 1 && line == 241 {
+            if !self.suppress_vblank {
+                self.update_vblank(true);
+                self.update_nmi();
+            }
+            self.suppress_vblank = false;
+        } else if dot == 1 && is_pre_render_line {
+            self.update_vblank(false);
+            self.update_nmi();
+            self.update_sprite_0_hit(false);
+            self.update_sprite_overflow(false);
+        }
+
+        if is_visible_line && (1..=256).contains(&dot) {
+            self.output_pixel(dot - 1, line);
+        }
 
-            self.x += 1;
-            if self.x >= 341 {
-                self.x = 0;
-                self.y += 1;
-                if self.y == 241 {
-                    self.update_vblank(true);
-                    if self.ppuctrl & (1 << 7) != 0 {
-                        self.nmi = true;
+        if !self.is_rendering() || !(is_visible_line || is_pre_render_line) {
+            return;
+        }
+
+        match dot {
+            1 ..= 256 | 321 ..= 336 => {
+                self.shift_bg();
+                // 8ドットでネームテーブル、属性、パターン下位、上位を2ドットずつかけて読む
+                match dot % 8 {
+                    1 => self.fetch_bg_tile(),
+                    3 => self.fetch_bg_attribute(),
+                    5 => self.next_pattern_lo = self.fetch_bg_pattern(0),
+                    7 => self.next_pattern_hi = self.fetch_bg_pattern(8),
+                    0 => {
+                        self.reload_bg();
+                        self.increment_x();
+                        if dot == 256 {
+                            self.increment_y();
+                        }
                     }
-                } else if self.y == 261 {
-                    self.update_vblank(false);
-                    self.update_sprite_0_hit(false);
+                    _ => {}
                 }
-                if self.y > 262 {
-                    self.y = 0;
-                    ret = Some(Box::new(self.frame.clone()));
-                    self.frame.iter_mut().for_each(|v| *v = 0);
+            }
+            257 => {
+                // t: ....A.. ...BCDEF -> v
+                self.vram_addr = self.vram_addr & !0x041f | self.temp_vram_addr & 0x041f;
+                // 257-320でOAMADDRは0になる
+                self.sprite_addr = 0;
+                // 実機では65-256で評価して257-320で読み出すが、ここでまとめて行う
+                // プリレンダーラインでは評価しないので、0ライン目にはスプライトが表示されない
+                if is_pre_render_line {
+                    self.secondary_oam.clear();
+                    self.is_sprite_0_in_line = false;
+                } else {
+                    self.evaluate_sprites(line);
                 }
+                self.fetch_sprites(line);
+            }
+            280 ..= 304 if is_pre_render_line => {
+                // t: GHIA.BC DEF..... -> v
+                self.vram_addr = self.vram_addr & !0x7be0 | self.temp_vram_addr & 0x7be0;
             }
+            // 337, 339のネームテーブルのダミー読み出しは、MMC5のライン検出に使われるだけなので
+            // notify_ppu_scanlineで代用して省略する
+            _ => {}
         }
-        ret
-    } 
+    }
 
-    pub fn init_frame(&mut self) {
-        _ = self.frame_bg.borrow_mut().iter_mut().map(|v|*v=CLEAR_COLOR).count();
-        _ = self.frame_sprite_bg.iter_mut().map(|v|*v=CLEAR_COLOR).count();
-        _ = self.frame_sprite_fg.iter_mut().map(|v|*v=CLEAR_COLOR).count();
+    // https://www.nesdev.org/wiki/PPU_scrolling#Coarse_X_increment
+    fn increment_x(&mut self) {
+        if self.vram_addr & 0x001f == 31 {
+            self.vram_addr &= !0x001f;
+            self.vram_addr ^= 0x0400;
+        } else {
+            self.vram_addr += 1;
+        }
     }
 
-    pub fn write_frame_bg(&mut self) {
-        let mut frame = self.frame_bg.borrow_mut();
-        self.draw_name_table_(|x, y, c|{
-            let i = x + y * WIDTH * 2;
-            frame[i] = c as u8;
-        });
+    // https://www.nesdev.org/wiki/PPU_scrolling#Y_increment
+    fn increment_y(&mut self) {
+        if self.vram_addr & 0x7000 != 0x7000 {
+            self.vram_addr += 0x1000;
+            return;
+        }
+        self.vram_addr &= !0x7000;
+        let mut y = (self.vram_addr & 0x03e0) >> 5;
+        if y == 29 {
+            y = 0;
+            self.vram_addr ^= 0x0800;
+        } else if y == 31 {
+            // 属性テーブルの位置までスクロールした場合はネームテーブルを切り替えない
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.vram_addr = self.vram_addr & !0x03e0 | y << 5;
     }
 
-    pub fn write_sprite(&mut self, frame: &mut Option<Vec<u8>>) {
+    fn name_table_offset(&self, addr: usize) -> usize {
+        let mirroring = self.mapper.borrow().mirroring().unwrap_or(self.mirroring);
+        mirroring.name_table_offset(addr)
+    }
+
+    // PPUバス経由の読み出し。読み出したアドレスはマッパーに通知する
+    fn fetch_name_table(&self, addr: usize) -> u8 {
+        let v = self.mapper.borrow_mut().read_name_table(addr as u16);
+        let v = v.unwrap_or_else(|| self.name_table[self.name_table_offset(addr)]);
+        self.mapper.borrow_mut().notify_ppu_read(addr as u16);
+        v
+    }
+
+    fn fetch_chr(&self, addr: usize) -> u8 {
+        let mut mapper = self.mapper.borrow_mut();
+        let v = mapper.read_chr(addr);
+        mapper.notify_ppu_read(addr as u16);
+        v
+    }
+
+    // https://www.nesdev.org/wiki/PPU_scrolling#Tile_and_attribute_fetching
+    fn fetch_bg_tile(&mut self) {
+        let addr = 0x2000 | (self.vram_addr & 0x0fff) as usize;
+        self.next_tile = self.fetch_name_table(addr);
+    }
+
+    // 属性テーブルは32x32px単位で、16x16pxごとに2bitずつ
+    // https://www.nesdev.org/wiki/PPU_attribute_tables
+    fn fetch_bg_attribute(&mut self) {
+        let v = self.vram_addr as usize;
+        let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        let shift_bit = ((v >> 4) & 4) | (v & 2);
+        self.next_attr = (self.fetch_name_table(addr) >> shift_bit) & 3;
+    }
+
+    fn fetch_bg_pattern(&mut self, plane : usize) -> u8 {
+        let chr_base = if self.ppuctrl & (1 << 4) != 0 { 0x1000 } else { 0x0000 };
+        let fine_y = (self.vram_addr >> 12) as usize & 7;
+        self.fetch_chr(chr_base + self.next_tile as usize * 16 + plane + fine_y)
+    }
+
+    fn shift_bg(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attr_lo <<= 1;
+        self.bg_attr_hi <<= 1;
+    }
+
+    // 読み出したタイルをシフトレジスタの下位8bitに入れる
+    // 属性は1タイルの間同じなので8bitに広げておく
+    fn reload_bg(&mut self) {
+        let expand = |b : u8| if b != 0 { 0xff } else { 0x00 };
+        self.bg_pattern_lo = self.bg_pattern_lo & 0xff00 | self.next_pattern_lo as u16;
+        self.bg_pattern_hi = self.bg_pattern_hi & 0xff00 | self.next_pattern_hi as u16;
+        self.bg_attr_lo = self.bg_attr_lo & 0xff00 | expand(self.next_attr & 1);
+        self.bg_attr_hi = self.bg_attr_hi & 0xff00 | expand(self.next_attr & 2);
+    }
+
+    // シフトレジスタの先頭から細かいXスクロール分ずらした位置の背景
+    fn bg_pixel(&self) -> u8 {
+        let bit = 15 - self.x_value as u16;
+        let palette_num = ((self.bg_pattern_lo >> bit) & 1 | ((self.bg_pattern_hi >> bit) & 1) << 1) as usize;
+        if palette_num == 0 {
+            return CLEAR_COLOR;
+        }
+        let palette_index = ((self.bg_attr_lo >> bit) & 1 | ((self.bg_attr_hi >> bit) & 1) << 1) as usize;
+        self.palette_ram[palette_index * 4 + palette_num]
+    }
+
+    // 次のラインに表示するスプライトを選ぶ
+    // OAMのYは表示位置-1なので、このラインの番号で比較すると次のラインに表示される
+    fn evaluate_sprites(&mut self, line: usize) {
+        self.secondary_oam.clear();
+        self.is_sprite_0_in_line = false;
+        let height = self.sprite_height();
+        let is_in_range = |y : u8| line >= y as usize && line < y as usize + height;
+
+        let mut n = 0;
+        while n < 64 && self.secondary_oam.len() < SPRITES_PER_LINE * 4 {
+            let sprite = &self.sprite_ram[n*4..n*4+4];
+            if is_in_range(sprite[0]) {
+                self.secondary_oam.extend_from_slice(sprite);
+                self.is_sprite_0_in_line |= n == 0;
+            }
+            n += 1;
+        }
+
+        // 制限をなくす場合は残りのスプライトも表示する
+        if self.remove_sprite_limit {
+            for i in n..64 {
+                let sprite = &self.sprite_ram[i*4..i*4+4];
+                if is_in_range(sprite[0]) {
+                    self.secondary_oam.extend_from_slice(sprite);
+                }
+            }
+        }
+
+        // 8個見つかった後、実機はスプライト番号と一緒にバイトの位置もずらしてしまうので
+        // Y以外の値をYとして比較する (オーバーフローの判定のバグ)
+        let mut m = 0;
+        while n < 64 {
+            if is_in_range(self.sprite_ram[n*4 + m]) {
+                self.update_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+    }
+
+    // セカンダリOAMのスプライトのパターンを読み出して次のライン分を展開する
+    fn fetch_sprites(&mut self, line: usize) {
+        self.mapper.borrow_mut().notify_ppu_fetch(PpuFetch::Sprite);
+        self.line_sprite.iter_mut().for_each(|v| *v = 0);
+
+        let height = self.sprite_height();
+
+        // 空きのスロットも実機はタイル$FFを読むので、マッパーから見えるアクセスを揃える
+        let count = (self.secondary_oam.len() / 4).max(SPRITES_PER_LINE);
+        for i in 0..count {
+            let sprite = self.secondary_oam.get(i*4..i*4+4).map(|s| [s[0], s[1], s[2], s[3]]);
+            let [sprite_y, tile, attr, sprite_x] = sprite.unwrap_or([0xff; 4]);
+            let is_h_reverse = attr & (1 << 6) != 0;
+            let is_v_reverse = attr & (1 << 7) != 0;
+
+            // 上下反転は8x16の場合は上下のタイルも入れ替わる
+            let y = line.wrapping_sub(sprite_y as usize) & (height - 1);
+            let y_ = if is_v_reverse { height - 1 - y } else { y };
+            let pattern_addr = self.sprite_pattern_addr(tile, y_);
+            let pattern0 = self.fetch_chr(pattern_addr);
+            let pattern1 = self.fetch_chr(pattern_addr + 8);
+            if sprite.is_none() {
+                continue;
+            }
+
+            // https://www.nesdev.org/wiki/PPU_OAM
+            let mut flags = (attr & 3) << 2 | 0x10;
+            if attr & (1 << 5) != 0 {
+                flags |= SPRITE_BEHIND_BG;
+            }
+            if i == 0 && self.is_sprite_0_in_line {
+                flags |= SPRITE_ZERO;
+            }
+
+            for x in 0..8usize {
+                let pattern_bit = if is_h_reverse { x } else { 7 - x };
+                let palette_num = (pattern0 >> pattern_bit) & 1 | ((pattern1 >> pattern_bit) & 1) << 1;
+                let x_ = sprite_x as usize + x;
+                // 重なった場合はOAMの番号が小さい方が優先される (背景の後ろに回るスプライトでも)
+                if x_ < WIDTH && palette_num != 0 && self.line_sprite[x_] == 0 {
+                    self.line_sprite[x_] = flags | palette_num;
+                }
+            }
+        }
+    }
+
+    // debug
+    pub fn write_sprite(&self, frame: &mut Option<Vec<u8>>, palette : &Palette) {
+        let frame_ = match frame {
+            Some(f) => f,
+            None => return,
+        };
         let mapper = self.mapper.borrow();
+        let height = self.sprite_height();
         for sprite_i in 0..64 {
             let sprite = &self.sprite_ram[sprite_i*4..sprite_i*4+4];
-            let tile = sprite[1] as usize;
+            let tile = sprite[1];
             let attr = sprite[2] as usize;
             let is_h_reverse = attr & (1 << 6) != 0;
             let is_v_reverse = attr & (1 << 7) != 0;
 
-            let is_debug = frame.is_some();
-            let sprite_x = if !is_debug {sprite[3] as usize} else { sprite_i % 8 * 8};
-            let sprite_y = if !is_debug {sprite[0] as usize} else { sprite_i / 8 * 8};
-            let width = if !is_debug { WIDTH } else { 64 };
+            let sprite_x = sprite_i % 8 * 8;
+            let sprite_y = sprite_i / 8 * 16;
+            let width = SPRITE_DEBUG_WIDTH;
 
-            // https://www.nesdev.org/wiki/PPU_OAM
-            let is_fg = attr & (1 << 5) == 0;
-            // size : 8x8
-            let pattern_table_base = if self.ppuctrl & 0x08 != 0 { 0x1000usize } else { 0x0000usize };
-            let pattern_base = pattern_table_base + tile * 16;
-            let pattern_table = mapper.read_chr_range(pattern_base..pattern_base+16);
             let palette_type = attr & 3;
             let palette_base = palette_type * 4 + 0x10;
 
-            for y in 0..8usize {
-                let y_ = if is_v_reverse { 7 - y } else { y };
-                let pattern0 = pattern_table[y_];
-                let pattern1 = pattern_table[y_ + 8];
+            for y in 0..16usize {
+                let i = ((sprite_y + y) * width + sprite_x) * 4;
+                if y >= height {
+                    frame_[i..i + 8 * 4].chunks_mut(4).for_each(|v| v.copy_from_slice(&[0, 0, 0, 0xff]));
+                    continue;
+                }
+                let y_ = if is_v_reverse { height - 1 - y } else { y };
+                let pattern_addr = self.sprite_pattern_addr(tile, y_);
+                let pattern0 = mapper.read_chr(pattern_addr);
+                let pattern1 = mapper.read_chr(pattern_addr + 8);
 
                 for x in 0..8usize {
 
@@ -345,69 +809,61 @@ impl PPU {
 
                     let color = self.palette_to_color(palette_base + palette_num);
                     
-                    let x_ = sprite_x + x;
-                    let y_ = sprite_y + y;
-                    if x_ < width && y_ < HEIGHT {
-                        let i = (y_ * width + x_) as usize;
-                        if let Some(frame_) = frame {
-                            let i = i*4;
-                            if color == CLEAR_COLOR {
-                                frame_[i+0] = 0;
-                                frame_[i+1] = 0;
-                                frame_[i+2] = 0;
-                            } else {
-                                let c = &COLORS[color as usize];
-                                frame_[i..i+3].clone_from_slice(c);
-                            }
-                            frame_[i+3] = 0xff;
-                        } else {
-                            // Sprite 0 Hit判定のためにフラグを一緒にセットする
-                            if color != CLEAR_COLOR {
-                                let color_ = color | if sprite_i == 0 && palette_num % 4 != 0 { 0x80 } else { 0x00 };
-                                if is_fg {
-                                    self.frame_sprite_fg[i] = self.frame_sprite_fg[i] & 0x80 | color_;
-                                } else {
-                                    self.frame_sprite_bg[i] = self.frame_sprite_bg[i] & 0x80 | color_;
-                                }
-                            }
-                        }
+                    let i = i + x * 4;
+                    if color == CLEAR_COLOR {
+                        frame_[i+0] = 0;
+                        frame_[i+1] = 0;
+                        frame_[i+2] = 0;
+                    } else {
+                        let c = palette.rgb(color as usize);
+                        frame_[i..i+3].clone_from_slice(c);
                     }
+                    frame_[i+3] = 0xff;
                 }
             }
         }
     }
 
-    // 戻り値はsprite 0 hitが起きた時にonとなる
-    pub fn write_line(&mut self, line: usize) -> bool {
-        let mut sprite_0_hit = false;
-        for x in 0..WIDTH {
-            let p = x + line * WIDTH;
-            let scroll_x = self.scroll_x as usize + ((self.ppuctrl & 1) as usize) * 256;
-            let scroll_y = self.scroll_y as usize + (((self.ppuctrl & 2) >> 1) as usize) * 240;
-            let p2 = ((x + scroll_x) % (WIDTH*2)) + ((line + scroll_y) % (HEIGHT * 2)) * WIDTH * 2;
-            let mut c = self.frame_sprite_fg[p];
-            if c == CLEAR_COLOR {
-                c = self.frame_bg.borrow()[p2];
-            }
-            if c == CLEAR_COLOR {
-                c = self.frame_sprite_bg[p];
-            }
-            let out_pixel = &mut self.frame[p*4..p*4+4];
-            if c == CLEAR_COLOR {
-                out_pixel[0..3].clone_from_slice(&COLORS[self.palette_ram[0] as usize]);
-                out_pixel[3] = 0xff;
-            } else {
-                out_pixel[0..3].clone_from_slice(&COLORS[(c & 0x3f) as usize]);
-                out_pixel[3] = 0xff;
-            }
+    fn output_pixel(&mut self, x : usize, line : usize) {
+        let color = if self.is_rendering() {
+            self.composite_pixel(x)
+        } else if self.vram_addr & 0x3f00 == 0x3f00 {
+            // 描画が無効の間は背景色だけになるが、vがパレットを指している場合はその色が出る
+            self.palette_ram[palette_addr(self.vram_addr)]
+        } else {
+            self.palette_ram[0]
+        };
 
-            if (self.frame_sprite_fg[p] & 0x80 != 0) || ( self.frame_sprite_bg[p] & 0x80 != 0) {
-                if self.frame_bg.borrow()[p2] != CLEAR_COLOR {
-                    sprite_0_hit |= true;
-                }
-            }
+        // https://www.nesdev.org/wiki/PPU_registers#PPUMASK
+        // bit0: グレースケール (色相を落とす)、bit5-7: 色の強調
+        let color = if self.ppumask & 1 != 0 { color & 0x30 } else { color & 0x3f };
+        let emphasis = (self.ppumask >> 5) as u16;
+        self.frame[x + line * WIDTH] = emphasis << 6 | color as u16;
+    }
+
+    // 背景とスプライトを重ねた色
+    fn composite_pixel(&mut self, x : usize) -> u8 {
+        // bit1, 2: 左端8ドットの背景、スプライトの表示
+        // bit3, 4: 背景、スプライトの表示
+        let is_left = x < 8;
+        let show_bg = self.ppumask & 0x08 != 0 && (!is_left || self.ppumask & 0x02 != 0);
+        let show_sprite = self.ppumask & 0x10 != 0 && (!is_left || self.ppumask & 0x04 != 0);
+
+        let bg = if show_bg { self.bg_pixel() } else { CLEAR_COLOR };
+        let sprite = if show_sprite { self.line_sprite[x] } else { 0 };
+
+        // 右端の255ドット目では起きない
+        if sprite & SPRITE_ZERO != 0 && bg != CLEAR_COLOR && x != 255 {
+            self.update_sprite_0_hit(true);
+        }
+
+        if sprite != 0 && (sprite & SPRITE_BEHIND_BG == 0 || bg == CLEAR_COLOR) {
+            self.palette_ram[(sprite & 0x1f) as usize]
+        } else if bg != CLEAR_COLOR {
+            bg
+        } else {
+            self.palette_ram[0]
         }
-        sprite_0_hit
     }
 
     fn palette_to_color(&self, i: usize) -> u8 {
@@ -418,7 +874,7 @@ impl PPU {
     }
 
     // debug
-    pub fn draw_chr(&self, frame: &mut [u8]) {
+    pub fn draw_chr(&self, frame: &mut [u8], palette : &Palette) {
         let mapper = self.mapper.borrow();
 
         for j in 0..2usize {
@@ -441,10 +897,10 @@ impl PPU {
                         
                         let base = ((y_base * 8 + y_pattern) * CHR_DEBUG_WIDTH + x_base * 8 + x_pattern) * 4;
                         let c = match palette_num {
-                            1 => &COLORS[1],
-                            2 => &COLORS[3],
-                            3 => &COLORS[6],
-                            _ => &COLORS[0],
+                            1 => palette.rgb(1),
+                            2 => palette.rgb(3),
+                            3 => palette.rgb(6),
+                            _ => palette.rgb(0),
                         };
                         frame[base..base+3].clone_from_slice(c);
                         frame[base+3] = 0xff;
@@ -455,14 +911,14 @@ impl PPU {
 
     }
 
-    pub fn draw_name_table(&self, frame_: &RefCell<Vec<u8>>) {
+    pub fn draw_name_table(&self, frame_: &RefCell<Vec<u8>>, palette : &Palette) {
         let mut frame = frame_.borrow_mut();
         self.draw_name_table_(|x,y,c| {
             let i = (x + y * WIDTH * 2) * 4;
             let color = if c == CLEAR_COLOR as usize {
-                &COLORS[self.palette_ram[0] as usize]
+                palette.rgb(self.palette_ram[0] as usize)
             } else {
-                &COLORS[c]
+                palette.rgb(c)
     
             };
             frame[i..i+3].clone_from_slice(color);
@@ -477,11 +933,7 @@ impl PPU {
 
         for i in 0..4 {
 
-            let base_addr = i * 0x400;
-            let base_addr = match self.is_mirror_horizontal {
-                true => base_addr & !0x400,
-                false => base_addr & !0x800,
-            };
+            let base_addr = self.name_table_offset(0x2000 + i * 0x400);
             let attribute_table = &self.name_table[base_addr + 0x3c0..base_addr + 0x3c0 + 64];
 
             // 属性テーブルは32x32px単位で
@@ -542,27 +994,181 @@ impl PPU {
     }
 }
 
-static COLORS : [[u8;3];64]= [
-    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
-    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
-    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
-    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
-    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
-    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
-    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
-    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
-    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
-    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
-    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
-    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
-    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
-    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
-    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
-    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
-  ];
+// $3F10/$3F14/$3F18/$3F1Cは$3F00/$3F04/$3F08/$3F0Cのミラー
+fn palette_addr(addr : u16) -> usize {
+    let a = addr as usize & 0x1f;
+    if a & 0x13 == 0x10 { a & 0x0f } else { a }
+}
 
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::PPU;
+    use crate::mapper::{new_mapper, Mirroring};
+    use crate::palette::Palette;
+    use crate::rom_header::RomHeader;
+
+    fn new_ppu() -> PPU {
+        let mapper = new_mapper(&RomHeader::default(), vec![0; 0x8000], vec![0; 0x2000]).unwrap();
+        PPU::new(Rc::new(RefCell::new(mapper)), Mirroring::Vertical)
+    }
+
+    #[test]
+    fn スクロールの書き込みとvのインクリメント() {
+        let mut ppu = new_ppu();
+        ppu.write_ppuctrl(0x01);
+        ppu.write_ppuscroll(0xff);
+        ppu.write_ppuscroll(0xef);
+        // t: 111 01 11101 11111
+        assert_eq!(ppu.temp_vram_addr, 0x77bf);
+        assert_eq!(ppu.x_value, 7);
+
+        // 右端のタイルから隣のネームテーブルの左端へ
+        ppu.vram_addr = ppu.temp_vram_addr;
+        ppu.increment_x();
+        assert_eq!(ppu.vram_addr, 0x73a0);
+        // 29行目の次は縦に隣のネームテーブルの0行目
+        ppu.increment_y();
+        assert_eq!(ppu.vram_addr, 0x0800);
+    }
+
+    fn step_to(ppu : &mut PPU, y : usize, x : usize) {
+        while ppu.y != y || ppu.x != x {
+            ppu.step(1);
+        }
+    }
+
+    #[test]
+    fn vblankの直前に2002を読むとnmiが起きない() {
+        let mut ppu = new_ppu();
+        ppu.read_status();
+        ppu.write_ppuctrl(0x80);
+        assert!(!ppu.nmi);
+
+        // フラグが立つ1ドット前
+        step_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.step(10);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        assert!(!ppu.nmi);
+
+        // フラグが立った直後
+        step_to(&mut ppu, 241, 2);
+        assert_ne!(ppu.read_status() & 0x80, 0);
+        assert!(!ppu.nmi);
+
+        // フラグを読んで落とした後は、NMIを有効にしても入らない
+        step_to(&mut ppu, 241, 10);
+        ppu.write_ppuctrl(0x00);
+        ppu.step(341 * 2);
+        ppu.write_ppuctrl(0x80);
+        assert!(!ppu.nmi);
+        step_to(&mut ppu, 241, 20);
+        assert!(ppu.nmi);
+        // VBlank中にNMIを有効にし直すとNMIが入る
+        ppu.nmi = false;
+        ppu.write_ppuctrl(0x00);
+        ppu.write_ppuctrl(0x80);
+        assert!(ppu.nmi);
+    }
+
+    #[test]
+    fn ppuのメモリマップとi_oラッチ() {
+        let mut ppu = new_ppu();
+        let write_addr = |ppu : &mut PPU, addr : u16| {
+            ppu.write_register(0x2006, (addr >> 8) as u8);
+            ppu.write_register(0x2006, addr as u8);
+        };
+
+        // $3F10は$3F00のミラー。パレットの上位2bitはラッチの値
+        write_addr(&mut ppu, 0x3f10);
+        ppu.write_register(0x2007, 0x2c);
+        write_addr(&mut ppu, 0x3f00);
+        ppu.write_register(0x2002, 0xc0);
+        assert_eq!(ppu.read_register(0x2007, false), 0xc0 | 0x2c);
+        // パレットには下位6bitだけが入る
+        write_addr(&mut ppu, 0x3f01);
+        ppu.write_register(0x2007, 0x40);
+        assert_eq!(ppu.palette_ram[1], 0x00);
+
+        // $3000-$3EFFは$2000-$2EFFのミラー。14bitを超えると0に戻る
+        write_addr(&mut ppu, 0x2005);
+        ppu.write_register(0x2007, 0x55);
+        write_addr(&mut ppu, 0x3005);
+        ppu.read_register(0x2007, false);
+        assert_eq!(ppu.read_register(0x2007, false), 0x55);
+        ppu.vram_addr = 0x3fff;
+        ppu.read_register(0x2007, false);
+        assert_eq!(ppu.vram_addr & 0x3fff, 0x0000);
+
+        // 属性の存在しないbitは0になる
+        ppu.write_register(0x2003, 0x02);
+        ppu.write_register(0x2004, 0xff);
+        ppu.write_register(0x2003, 0x02);
+        assert_eq!(ppu.read_register(0x2004, false), 0xe3);
+
+        // 書き込み専用のレジスタはラッチの値が読め、しばらくすると消える
+        ppu.write_register(0x2000, 0x5a);
+        assert_eq!(ppu.read_register(0x2005, false), 0x5a);
+        ppu.frame_count += super::IO_LATCH_DECAY_FRAMES + 1;
+        assert_eq!(ppu.read_register(0x2005, false), 0x00);
+    }
+
+    #[test]
+    fn フレームは9bitの色番号で出力される() {
+        let mut ppu = new_ppu();
+        ppu.palette_ram[0] = 0x21;
+        // 描画なし、グレースケール、全色強調
+        ppu.write_register(0x2001, 0xe1);
+        while !ppu.step(1) {}
+        while !ppu.step(1) {}
+        assert!(ppu.completed_frame().iter().all(|v| *v == 7 << 6 | 0x20));
+
+        let mut rgba = vec![0u8; super::FRAME_SIZE * 4];
+        let palette = Palette::default();
+        ppu.render_frame(&palette, &mut rgba);
+        assert_eq!(&rgba[0..3], palette.rgb(0x1e0));
+        assert_eq!(rgba[3], 0xff);
+    }
+
+    #[test]
+    fn 縦長スプライトのパターンのアドレス() {
+        let mut ppu = new_ppu();
+        ppu.write_ppuctrl(0x08);
+        assert_eq!(ppu.sprite_pattern_addr(0x25, 3), 0x1253);
+
+        // タイル番号のbit0でパターンテーブルを選び、下半分は次のタイル
+        ppu.write_ppuctrl(0x20);
+        assert_eq!(ppu.sprite_pattern_addr(0x25, 3), 0x1243);
+        assert_eq!(ppu.sprite_pattern_addr(0x25, 12), 0x1254);
+        assert_eq!(ppu.sprite_pattern_addr(0x24, 15), 0x0257);
+    }
+
+    #[test]
+    fn スプライトは1ライン8個まででオーバーフローが立つ() {
+        let mut ppu = new_ppu();
+        ppu.sprite_ram.iter_mut().for_each(|v| *v = 0xf0);
+        for i in 0..9 {
+            ppu.sprite_ram[i * 4] = 10;
+        }
+        ppu.evaluate_sprites(12);
+        assert_eq!(ppu.secondary_oam.len(), 8 * 4);
+        assert!(ppu.is_sprite_0_in_line);
+        assert_ne!(ppu.ppustatus & 0x20, 0);
+
+        ppu.ppustatus = 0;
+        ppu.remove_sprite_limit = true;
+        ppu.evaluate_sprites(12);
+        assert_eq!(ppu.secondary_oam.len(), 9 * 4);
+        assert_ne!(ppu.ppustatus & 0x20, 0);
+
+        // 範囲外
+        ppu.ppustatus = 0;
+        ppu.evaluate_sprites(18);
+        assert!(ppu.secondary_oam.is_empty());
+        assert_eq!(ppu.ppustatus & 0x20, 0);
+    }
 
     fn a(a: u16) -> u16 {
         a & !0x400        
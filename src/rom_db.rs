@@ -0,0 +1,160 @@
+use crate::mapper::Mirroring;
+use crate::rom_header::{ConsoleType, RomHeader, Timing};
+
+// ゲームデータベース
+// ヘッダが間違っているダンプが多いので、PRG+CHRのハッシュで引いてヘッダを補正する
+// テーブルはビルド時にdb/以下のXML (nes20db, NesCartDB) から生成する (build.rs)
+
+#[derive(Debug)]
+pub struct DbEntry {
+    pub crc32 : Option<u32>,
+    pub sha1 : Option<[u8; 20]>,
+    pub title : &'static str,
+    pub board : &'static str,
+    pub mapper : Option<u16>,
+    pub submapper : Option<u8>,
+    pub mirroring : Option<Mirroring>,
+    pub battery : Option<bool>,
+    pub prg_ram_size : Option<usize>,
+    pub prg_nvram_size : Option<usize>,
+    pub chr_ram_size : Option<usize>,
+    // NES 2.0のbyte 12と同じ値
+    pub timing : Option<u8>,
+    // NES 2.0のbyte 7の下位2bitと同じ値
+    pub console_type : Option<u8>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/rom_db.rs"));
+
+impl DbEntry {
+    // データベースにある項目だけヘッダを上書きする
+    pub fn apply(&self, header : &mut RomHeader) {
+        if let Some(v) = self.mapper {
+            header.mapper = v;
+            header.submapper = self.submapper.unwrap_or(0);
+        }
+        if let Some(v) = self.mirroring {
+            header.is_four_screen = v == Mirroring::FourScreen;
+            if v != Mirroring::FourScreen {
+                header.mirroring = v;
+            }
+        }
+        if let Some(v) = self.battery {
+            header.has_battery = v;
+        }
+        if self.prg_ram_size.is_some() || self.prg_nvram_size.is_some() {
+            header.prg_ram_size = self.prg_ram_size.unwrap_or(0);
+            header.prg_nvram_size = self.prg_nvram_size.unwrap_or(0);
+        }
+        if let Some(v) = self.chr_ram_size {
+            header.chr_ram_size = v;
+        }
+        if let Some(v) = self.timing {
+            header.timing = match v {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            };
+        }
+        match self.console_type {
+            Some(0) => header.console_type = ConsoleType::Nes,
+            Some(1) if !matches!(header.console_type, ConsoleType::VsSystem { .. }) => {
+                header.console_type = ConsoleType::VsSystem { ppu: 0, hardware: 0 };
+            }
+            Some(2) => header.console_type = ConsoleType::PlayChoice10,
+            _ => {}
+        }
+    }
+}
+
+// ビルド時にデータベースが見つかったか
+pub fn is_available() -> bool {
+    !ROM_DB.is_empty()
+}
+
+// PRG+CHRのハッシュで検索する。SHA-1が一致するものを優先する
+pub fn find(prg : &[u8], chr : &[u8]) -> Option<&'static DbEntry> {
+    if ROM_DB.is_empty() {
+        return None;
+    }
+    let data = [prg, chr].concat();
+    let sha1 = sha1(&data);
+    if let Some(e) = ROM_DB.iter().find(|e| e.sha1 == Some(sha1)) {
+        return Some(e);
+    }
+    let crc = crc32(&data);
+    ROM_DB.iter().find(|e| e.sha1.is_none() && e.crc32 == Some(crc))
+}
+
+// CRC-32 (IEEE 802.3)
+pub fn crc32(data : &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// https://datatracker.ietf.org/doc/html/rfc3174
+pub fn sha1(data : &[u8]) -> [u8; 20] {
+    let mut h : [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0 ..= 19 => (b & c | !b & d, 0x5a827999),
+                20 ..= 39 => (b ^ c ^ d, 0x6ed9eba1),
+                40 ..= 59 => (b & c | b & d | c & d, 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (i, v) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ハッシュが計算できる() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            sha1(b"abc"),
+            [0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// db/以下のゲームデータベース(XML)の読み込み
+// nes20db (https://forums.nesdev.org/viewtopic.php?t=19940) と NesCartDB の形式に対応
+// XMLの中身は単純なので、必要な要素と属性だけを拾う
+// build.rsから#[path]で読み込むので、標準ライブラリ以外には依存しない
+
+#[derive(Default)]
+pub struct Entry {
+    pub crc32 : Option<u32>,
+    pub sha1 : Option<[u8; 20]>,
+    pub title : String,
+    pub board : String,
+    pub mapper : Option<u16>,
+    pub submapper : Option<u8>,
+    // 'H', 'V', '4'
+    pub mirroring : Option<char>,
+    pub battery : Option<bool>,
+    pub prg_ram_size : Option<usize>,
+    pub prg_nvram_size : Option<usize>,
+    pub chr_ram_size : Option<usize>,
+    pub timing : Option<u8>,
+    pub console_type : Option<u8>,
+}
+
+// <name attr="value" ...> の要素名と属性
+struct Tag<'a> {
+    name : &'a str,
+    attrs : Vec<(&'a str, String)>,
+    is_end : bool,
+}
+
+impl Tag<'_> {
+    fn attr(&self, name : &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_str())
+    }
+}
+
+fn unescape(s : &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn parse_tag(s : &str) -> Tag<'_> {
+    let is_end = s.starts_with('/');
+    let s = s.trim_start_matches('/').trim_end_matches('/');
+    let name_end = s.find(char::is_whitespace).unwrap_or(s.len());
+    let mut attrs = vec![];
+    let mut rest = &s[name_end..];
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let after = rest[eq + 1..].trim_start();
+        let quote = match after.chars().next() {
+            Some(q @ ('"' | '\'')) => q,
+            _ => break,
+        };
+        let value_end = match after[1..].find(quote) {
+            Some(i) => i + 1,
+            None => break,
+        };
+        attrs.push((key, unescape(&after[1..value_end])));
+        rest = &after[value_end + 1..];
+    }
+    Tag { name: &s[..name_end], attrs, is_end }
+}
+
+fn parse_hex<const N : usize>(s : &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut v = [0u8; N];
+    for (i, b) in v.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(v)
+}
+
+// NesCartDBは "8k" のようにK単位
+fn parse_size(s : &str) -> Option<usize> {
+    match s.strip_suffix(|c| c == 'k' || c == 'K') {
+        Some(k) => k.parse::<usize>().ok().map(|v| v * 1024),
+        None => s.parse().ok(),
+    }
+}
+
+pub fn parse_db(xml : &str, entries : &mut Vec<Entry>) {
+    let mut entry : Option<Entry> = None;
+    let mut comment = String::new();
+    let mut game_name = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(body) = rest.strip_prefix("!--") {
+            let end = body.find("-->").unwrap_or(body.len());
+            comment = unescape(body[..end].trim());
+            rest = &body[end..];
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = parse_tag(rest[..end].trim());
+        rest = &rest[end + 1..];
+
+        match (tag.name, tag.is_end) {
+            // nes20db: <game> ごとに1エントリ。タイトルは直前のコメント
+            ("game", false) => {
+                game_name = tag.attr("name").map(str::to_string).unwrap_or_default();
+                if tag.attr("name").is_none() {
+                    entry = Some(Entry::default());
+                }
+            }
+            ("game", true) | ("cartridge", true) => {
+                if let Some(mut e) = entry.take() {
+                    if e.title.is_empty() {
+                        e.title = if game_name.is_empty() { comment.clone() } else { game_name.clone() };
+                    }
+                    if e.crc32.is_some() || e.sha1.is_some() {
+                        entries.push(e);
+                    }
+                }
+            }
+            // NesCartDB: <game name=...> の中に <cartridge crc=... sha1=...> が複数ある
+            ("cartridge", false) => {
+                entry = Some(Entry {
+                    crc32: tag.attr("crc").and_then(|v| u32::from_str_radix(v, 16).ok()),
+                    sha1: tag.attr("sha1").and_then(parse_hex::<20>),
+                    timing: tag.attr("system").map(|v| if v.contains("PAL") { 1 } else { 0 }),
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+
+        let e = match &mut entry {
+            Some(e) if !tag.is_end => e,
+            _ => continue,
+        };
+        match tag.name {
+            "rom" => {
+                e.crc32 = tag.attr("crc32").and_then(|v| u32::from_str_radix(v, 16).ok());
+                e.sha1 = tag.attr("sha1").and_then(parse_hex::<20>);
+            }
+            "prgram" => e.prg_ram_size = tag.attr("size").and_then(parse_size),
+            "prgnvram" => e.prg_nvram_size = tag.attr("size").and_then(parse_size),
+            "chrram" => e.chr_ram_size = tag.attr("size").and_then(parse_size),
+            "pcb" => {
+                e.mapper = tag.attr("mapper").and_then(|v| v.parse().ok());
+                e.submapper = tag.attr("submapper").and_then(|v| v.parse().ok());
+                e.mirroring = tag.attr("mirroring").and_then(|v| v.chars().next());
+                e.battery = tag.attr("battery").map(|v| v == "1");
+            }
+            "console" => {
+                e.console_type = tag.attr("type").and_then(|v| v.parse().ok());
+                e.timing = tag.attr("region").and_then(|v| v.parse().ok());
+            }
+            "board" => {
+                e.board = tag.attr("type").unwrap_or_default().to_string();
+                e.mapper = tag.attr("mapper").and_then(|v| v.parse().ok());
+            }
+            // 基板のはんだパッド。Hがつながっていると垂直ミラー
+            "pad" => {
+                e.mirroring = match (tag.attr("h"), tag.attr("v")) {
+                    (Some("1"), _) => Some('V'),
+                    (_, Some("1")) => Some('H'),
+                    _ => e.mirroring,
+                };
+            }
+            "wram" => {
+                let size = tag.attr("size").and_then(parse_size);
+                if tag.attr("battery") == Some("1") {
+                    e.battery = Some(true);
+                    e.prg_nvram_size = size;
+                } else {
+                    e.prg_ram_size = size;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nes20dbを読める() {
+        let mut entries = vec![];
+        parse_db(include_str!("../tests/fixtures/nes20db.xml"), &mut entries);
+        // ハッシュのないエントリは捨てる
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!(e.title, "Test Game (Japan)");
+        assert_eq!(e.crc32, Some(0x0123abcd));
+        assert_eq!(e.sha1.map(|v| v[1]), Some(0x11));
+        assert_eq!((e.mapper, e.submapper), (Some(1), Some(5)));
+        assert_eq!(e.mirroring, Some('4'));
+        assert_eq!(e.battery, Some(true));
+        assert_eq!((e.prg_ram_size, e.prg_nvram_size, e.chr_ram_size), (Some(0x2000), Some(0x2000), Some(0x2000)));
+        assert_eq!((e.console_type, e.timing), (Some(0), Some(1)));
+        assert_eq!(e.board, "");
+    }
+
+    #[test]
+    fn nescartdbを読める() {
+        let mut entries = vec![];
+        parse_db(include_str!("../tests/fixtures/NesCarts.xml"), &mut entries);
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!(e.title, "Cart & Board");
+        assert_eq!(e.board, "NES-SNROM");
+        assert_eq!(e.crc32, Some(0x89abcdef));
+        assert_eq!(e.sha1.map(|v| v[0]), Some(0xff));
+        assert_eq!(e.mapper, Some(1));
+        // Hのパッドがつながっていると垂直ミラー
+        assert_eq!(e.mirroring, Some('V'));
+        assert_eq!((e.battery, e.prg_nvram_size), (Some(true), Some(0x2000)));
+        assert_eq!(e.timing, Some(1));
+    }
+}
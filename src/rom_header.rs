@@ -0,0 +1,271 @@
+use crate::mapper::Mirroring;
+
+// iNES / NES 2.0 ヘッダ
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+
+pub const HEADER_SIZE : usize = 16;
+pub const TRAINER_SIZE : usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    // byte 7以降がゴミで埋まっている古いダンプ。マッパー番号の下位4bitだけを使う
+    ArchaicINes,
+    INes,
+    Nes20,
+}
+
+// https://www.nesdev.org/wiki/NES_2.0#CPU/PPU_Timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+// https://www.nesdev.org/wiki/NES_2.0#Vs._System_Type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    // PPUの種類、基板の種類
+    VsSystem { ppu : u8, hardware : u8 },
+    PlayChoice10,
+    // byte 13の下位4bit (Famicloneなど)
+    Extended(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct RomHeader {
+    pub format : HeaderFormat,
+    pub mapper : u16,
+    pub submapper : u8,
+    pub prg_rom_size : usize,
+    pub chr_rom_size : usize,
+    pub prg_ram_size : usize,
+    pub prg_nvram_size : usize,
+    pub chr_ram_size : usize,
+    pub chr_nvram_size : usize,
+    // byte 6 bit 0。0: 水平ミラー (垂直配置)、1: 垂直ミラー
+    pub mirroring : Mirroring,
+    // byte 6 bit 3。4画面 (マッパー30などでは1画面の意味になる)
+    pub is_four_screen : bool,
+    pub has_battery : bool,
+    pub has_trainer : bool,
+    pub timing : Timing,
+    pub console_type : ConsoleType,
+    pub misc_rom_count : u8,
+    // https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub expansion_device : u8,
+}
+
+impl Default for RomHeader {
+    fn default() -> Self {
+        Self {
+            format: HeaderFormat::INes,
+            mapper: 0,
+            submapper: 0,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring: Mirroring::Horizontal,
+            is_four_screen: false,
+            has_battery: false,
+            has_trainer: false,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_rom_count: 0,
+            expansion_device: 0,
+        }
+    }
+}
+
+// NES 2.0のRAMサイズは 64 << n (0の場合はなし)
+fn shift_size(n : u8) -> usize {
+    if n == 0 { 0 } else { 64 << n }
+}
+
+// NES 2.0のROMサイズ。上位4bitが$Fの場合は 2^E * (MM * 2 + 1)
+fn rom_size(lsb : u8, msb : u8, unit : usize) -> usize {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        2usize.checked_pow(exponent).map_or(usize::MAX, |v| v.saturating_mul(multiplier))
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+impl RomHeader {
+    pub fn parse(buf : &[u8]) -> Result<Self, String> {
+        if buf.len() < HEADER_SIZE {
+            return Err(format!("header size error {}", buf.len()));
+        }
+        if buf[0..4] != *b"NES\x1a" {
+            return Err("not an iNES file".into());
+        }
+
+        let flag6 = buf[6];
+        let flag7 = buf[7];
+        // https://www.nesdev.org/wiki/INES#Variant_comparison
+        let format = if flag7 & 0x0c == 0x08 {
+            HeaderFormat::Nes20
+        } else if flag7 & 0x0c == 0 && buf[12..16].iter().all(|v| *v == 0) {
+            HeaderFormat::INes
+        } else {
+            HeaderFormat::ArchaicINes
+        };
+
+        let mut h = Self {
+            format,
+            mirroring: if flag6 & 0x01 != 0 { Mirroring::Vertical } else { Mirroring::Horizontal },
+            is_four_screen: flag6 & 0x08 != 0,
+            has_battery: flag6 & 0x02 != 0,
+            has_trainer: flag6 & 0x04 != 0,
+            mapper: (flag6 >> 4) as u16,
+            ..Default::default()
+        };
+
+        match format {
+            HeaderFormat::ArchaicINes => {
+                h.prg_rom_size = buf[4] as usize * 0x4000;
+                h.chr_rom_size = buf[5] as usize * 0x2000;
+                h.prg_ram_size = 0x2000;
+            }
+            HeaderFormat::INes => {
+                h.mapper |= (flag7 & 0xf0) as u16;
+                h.prg_rom_size = buf[4] as usize * 0x4000;
+                h.chr_rom_size = buf[5] as usize * 0x2000;
+                // 0の場合は互換性のため8K
+                h.prg_ram_size = (buf[8].max(1) as usize) * 0x2000;
+                h.timing = if buf[9] & 0x01 != 0 { Timing::Pal } else { Timing::Ntsc };
+                h.console_type = match flag7 & 0x03 {
+                    1 => ConsoleType::VsSystem { ppu: 0, hardware: 0 },
+                    2 => ConsoleType::PlayChoice10,
+                    _ => ConsoleType::Nes,
+                };
+            }
+            HeaderFormat::Nes20 => {
+                h.mapper |= (flag7 & 0xf0) as u16 | ((buf[8] & 0x0f) as u16) << 8;
+                h.submapper = buf[8] >> 4;
+                h.prg_rom_size = rom_size(buf[4], buf[9] & 0x0f, 0x4000);
+                h.chr_rom_size = rom_size(buf[5], buf[9] >> 4, 0x2000);
+                h.prg_ram_size = shift_size(buf[10] & 0x0f);
+                h.prg_nvram_size = shift_size(buf[10] >> 4);
+                h.chr_ram_size = shift_size(buf[11] & 0x0f);
+                h.chr_nvram_size = shift_size(buf[11] >> 4);
+                h.timing = match buf[12] & 0x03 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                h.console_type = match flag7 & 0x03 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem { ppu: buf[13] & 0x0f, hardware: buf[13] >> 4 },
+                    2 => ConsoleType::PlayChoice10,
+                    _ => ConsoleType::Extended(buf[13] & 0x0f),
+                };
+                h.misc_rom_count = buf[14] & 0x03;
+                h.expansion_device = buf[15] & 0x3f;
+            }
+        }
+
+        // iNESではCHR-ROMがない場合はCHR-RAM 8K
+        if format != HeaderFormat::Nes20 && h.chr_rom_size == 0 {
+            h.chr_ram_size = 0x2000;
+        }
+        Ok(h)
+    }
+
+    // 4画面のビットが立っていればカートリッジ側のVRAMを使う4画面
+    pub fn name_table_mirroring(&self) -> Mirroring {
+        if self.is_four_screen { Mirroring::FourScreen } else { self.mirroring }
+    }
+
+    // $6000-$7FFFのRAMの合計 (バッテリーバックアップ分を含む)
+    pub fn total_prg_ram_size(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+}
+
+#[derive(Debug)]
+pub struct Rom {
+    pub header : RomHeader,
+    // $7000-$71FFに読み込む512バイト
+    pub trainer : Option<Vec::<u8>>,
+    pub prg : Vec::<u8>,
+    pub chr : Vec::<u8>,
+}
+
+impl Rom {
+    // ヘッダ、トレーナー、PRG-ROM、CHR-ROMの順に並んでいる
+    pub fn parse(buf : &[u8]) -> Result<Self, String> {
+        let header = RomHeader::parse(buf)?;
+        let mut p = HEADER_SIZE;
+        let mut take = |size : usize, name : &str| -> Result<Vec::<u8>, String> {
+            let end = p.checked_add(size).filter(|end| *end <= buf.len())
+                .ok_or_else(|| format!("{} size error: need {} bytes at {}, file is {} bytes", name, size, p, buf.len()))?;
+            let v = buf[p..end].to_vec();
+            p = end;
+            Ok(v)
+        };
+        let trainer = if header.has_trainer { Some(take(TRAINER_SIZE, "trainer")?) } else { None };
+        let prg = take(header.prg_rom_size, "prg rom")?;
+        let chr = take(header.chr_rom_size, "chr rom")?;
+        Ok(Self {
+            header,
+            trainer,
+            prg,
+            chr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nes20のヘッダを読める() {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(b"NES\x1a");
+        // PRGは指数表記で 2^14 * 3、CHRは8K * 2
+        buf[4] = 14 << 2 | 1;
+        buf[5] = 2;
+        buf[6] = 0x13;
+        buf[7] = 0x48;
+        buf[8] = 0x51;
+        buf[9] = 0x0f;
+        buf[10] = 0x70;
+        buf[12] = 0x01;
+        let h = RomHeader::parse(&buf).unwrap();
+        assert_eq!(h.format, HeaderFormat::Nes20);
+        assert_eq!(h.mapper, 0x141);
+        assert_eq!(h.submapper, 5);
+        assert_eq!(h.prg_rom_size, 0x4000 * 3);
+        assert_eq!(h.chr_rom_size, 0x4000);
+        assert_eq!(h.prg_nvram_size, 0x2000);
+        assert!(h.has_battery);
+        assert_eq!(h.mirroring, Mirroring::Vertical);
+        assert_eq!(h.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn データが足りない場合はエラー() {
+        let mut buf = vec![0u8; HEADER_SIZE + TRAINER_SIZE + 0x4000];
+        buf[0..4].copy_from_slice(b"NES\x1a");
+        buf[4] = 1;
+        buf[5] = 1;
+        buf[6] = 0x04;
+        let rom = Rom::parse(&buf[..HEADER_SIZE + TRAINER_SIZE + 0x4000]);
+        assert!(rom.is_err());
+        buf[5] = 0;
+        let rom = Rom::parse(&buf).unwrap();
+        assert_eq!(rom.trainer.map(|v| v.len()), Some(TRAINER_SIZE));
+        assert_eq!(rom.header.chr_ram_size, 0x2000);
+    }
+}
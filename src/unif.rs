@@ -0,0 +1,197 @@
+use crate::mapper::Mirroring;
+use crate::rom_header::{HeaderFormat, Rom, RomHeader};
+
+// UNIF
+// https://www.nesdev.org/wiki/UNIF
+// 32バイトのヘッダの後に、4文字のID + 長さ(32bit LE) + データ のチャンクが並ぶ
+
+const UNIF_HEADER_SIZE : usize = 32;
+
+#[derive(Debug)]
+pub struct Unif {
+    // MAPR (NES-UNROM など)
+    pub board : String,
+    pub name : Option<String>,
+    // CTRL 使用するコントローラーのビットフィールド
+    pub controllers : u8,
+    pub rom : Rom,
+}
+
+// ボード名からマッパー番号とサブマッパーを求める
+// https://www.nesdev.org/wiki/UNIF_to_NES_2.0_Mapping
+fn board_to_mapper(board : &str) -> Option<(u16, u8)> {
+    // NES-、UNL-などの接頭辞は無視する
+    let name = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-", "IREM-", "KONAMI-", "NAMCOT-", "BANDAI-", "SUNSOFT-"].iter()
+        .find_map(|p| board.strip_prefix(p))
+        .unwrap_or(board);
+    let m = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0),
+        "UNROM" | "UOROM" | "UN1ROM" => (2, 0),
+        "CNROM" => (3, 0),
+        "ELROM" | "EKROM" | "ETROM" | "EWROM" => (5, 0),
+        "PNROM" | "PEEOROM" => (9, 0),
+        "FJROM" | "FKROM" => (10, 0),
+        "LZ93D50+24C01" => (159, 0),
+        "LZ93D50+24C02" => (16, 5),
+        "FCG-1" | "FCG-2" => (16, 4),
+        "163" | "129" => (19, 0),
+        "UNROM-512-8" | "UNROM-512-16" | "UNROM-512-32" => (30, 0),
+        "JLROM" | "JSROM" | "BTR" => (69, 0),
+        _ => return None,
+    };
+    Some(m)
+}
+
+fn c_string(data : &[u8]) -> String {
+    let end = data.iter().position(|v| *v == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+pub fn is_unif(buf : &[u8]) -> bool {
+    buf.starts_with(b"UNIF")
+}
+
+pub fn parse_unif(buf : &[u8]) -> Result<Unif, String> {
+    if buf.len() < UNIF_HEADER_SIZE || !is_unif(buf) {
+        return Err("not a UNIF file".into());
+    }
+
+    let mut board = None;
+    let mut name = None;
+    let mut controllers = 0;
+    let mut mirror = None;
+    let mut has_battery = false;
+    // PRG0..PRGF, CHR0..CHRF の順に連結する
+    let mut prg_chunks : [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks : [Option<&[u8]>; 16] = [None; 16];
+
+    let mut p = UNIF_HEADER_SIZE;
+    while p + 8 <= buf.len() {
+        let id = &buf[p..p + 4];
+        let len = u32::from_le_bytes([buf[p + 4], buf[p + 5], buf[p + 6], buf[p + 7]]) as usize;
+        let start = p + 8;
+        let end = start.checked_add(len).filter(|end| *end <= buf.len())
+            .ok_or_else(|| format!("chunk {} size error", String::from_utf8_lossy(id)))?;
+        let data = &buf[start..end];
+        match id {
+            b"MAPR" => board = Some(c_string(data)),
+            b"NAME" => name = Some(c_string(data)),
+            b"CTRL" => controllers = data.first().copied().unwrap_or(0),
+            b"MIRR" => mirror = data.first().copied(),
+            b"BATR" => has_battery = true,
+            _ => {
+                let index = (id[3] as char).to_digit(16).map(|v| v as usize);
+                match (&id[0..3], index) {
+                    (b"PRG", Some(i)) => prg_chunks[i] = Some(data),
+                    (b"CHR", Some(i)) => chr_chunks[i] = Some(data),
+                    _ => {}
+                }
+            }
+        }
+        p = end;
+    }
+
+    let board = board.ok_or("UNIF has no MAPR chunk")?;
+    let (mapper, submapper) = board_to_mapper(&board)
+        .ok_or_else(|| format!("unsupported UNIF board {}", board))?;
+    let prg = prg_chunks.iter().flatten().flat_map(|v| v.iter().copied()).collect::<Vec<u8>>();
+    let chr = chr_chunks.iter().flatten().flat_map(|v| v.iter().copied()).collect::<Vec<u8>>();
+    if prg.is_empty() {
+        return Err("UNIF has no PRG chunk".into());
+    }
+
+    // MIRR 0: 水平、1: 垂直、2,3: 1画面、4: 4画面、5: マッパー制御
+    // https://www.nesdev.org/wiki/UNIF#MIRR
+    let mirroring = match mirror {
+        None | Some(0) | Some(4) => Mirroring::Horizontal,
+        Some(1) => Mirroring::Vertical,
+        Some(2) => Mirroring::SingleScreenLower,
+        Some(3) => Mirroring::SingleScreenUpper,
+        // マッパーが切り替えるので初期値は水平にしておく
+        Some(5) => Mirroring::Horizontal,
+        Some(v) => return Err(format!("unknown UNIF mirroring {}", v)),
+    };
+    let header = RomHeader {
+        format: HeaderFormat::INes,
+        mapper,
+        submapper,
+        prg_rom_size: prg.len(),
+        chr_rom_size: chr.len(),
+        // UNIFにはRAMのサイズがないので8Kとする
+        prg_ram_size: 0x2000,
+        chr_ram_size: if chr.is_empty() { 0x2000 } else { 0 },
+        mirroring,
+        is_four_screen: mirror == Some(4),
+        has_battery,
+        ..Default::default()
+    };
+
+    Ok(Unif {
+        board,
+        name,
+        controllers,
+        rom: Rom {
+            header,
+            trainer: None,
+            prg,
+            chr,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id : &[u8], data : &[u8]) -> Vec<u8> {
+        let mut v = id.to_vec();
+        v.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        v.extend_from_slice(data);
+        v
+    }
+
+    #[test]
+    fn チャンクを読んでマッパーを決める() {
+        let mut buf = b"UNIF".to_vec();
+        buf.resize(UNIF_HEADER_SIZE, 0);
+        buf[4] = 7;
+        buf.extend(chunk(b"MAPR", b"NES-UNROM\0"));
+        buf.extend(chunk(b"PRG1", &[2; 0x4000]));
+        buf.extend(chunk(b"PRG0", &[1; 0x4000]));
+        buf.extend(chunk(b"MIRR", &[1]));
+        buf.extend(chunk(b"BATR", &[0]));
+        let unif = parse_unif(&buf).unwrap();
+        assert_eq!(unif.board, "NES-UNROM");
+        assert_eq!(unif.rom.header.mapper, 2);
+        assert_eq!(unif.rom.header.mirroring, Mirroring::Vertical);
+        assert!(unif.rom.header.has_battery);
+        // PRG0, PRG1の順に並ぶ
+        assert_eq!(unif.rom.prg[0], 1);
+        assert_eq!(unif.rom.prg[0x4000], 2);
+        assert_eq!(unif.rom.header.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn mirrの1画面と不明な値() {
+        let unif_with_mirr = |v : u8| {
+            let mut buf = b"UNIF".to_vec();
+            buf.resize(UNIF_HEADER_SIZE, 0);
+            buf.extend(chunk(b"MAPR", b"NES-NROM-256\0"));
+            buf.extend(chunk(b"PRG0", &[0; 0x8000]));
+            buf.extend(chunk(b"MIRR", &[v]));
+            parse_unif(&buf)
+        };
+        assert_eq!(unif_with_mirr(2).unwrap().rom.header.mirroring, Mirroring::SingleScreenLower);
+        assert_eq!(unif_with_mirr(3).unwrap().rom.header.mirroring, Mirroring::SingleScreenUpper);
+        assert!(unif_with_mirr(4).unwrap().rom.header.is_four_screen);
+        assert!(unif_with_mirr(6).is_err());
+    }
+
+    #[test]
+    fn 接頭辞を外してボード名を探す() {
+        assert_eq!(board_to_mapper("BANDAI-FCG-1"), Some((16, 4)));
+        assert_eq!(board_to_mapper("BANDAI-LZ93D50+24C01"), Some((159, 0)));
+        assert_eq!(board_to_mapper("BANDAI-LZ93D50+24C02"), Some((16, 5)));
+        assert_eq!(board_to_mapper("NES-XXXROM"), None);
+    }
+}